@@ -0,0 +1,563 @@
+use crate::{
+    Clock, ConfigLayers, ConfigLoadError, Fan, FanFeedback, FanSpeed, KickConfig, TachError,
+    CONFIG_DROP_IN_DIR,
+};
+use log::{info, warn};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Drop-in file the calibrate subcommand writes its suggestions to
+pub const CALIBRATION_DROP_IN: &str = "calibration.toml";
+
+/// Percentage points added to the measured speeds, so the fan still starts when it
+/// has aged a bit or the supply voltage sags
+const MARGIN: u8 = 5;
+
+/// Percent of the top RPM from which the fan counts as no longer getting faster
+const SATURATION_PERCENT: u32 = 97;
+
+/// How long the RPM is measured over, at each step
+const MEASURE_TIME: Duration = Duration::from_secs(2);
+
+#[derive(Debug, err_derive::Error)]
+pub enum CalibrationError {
+    #[error(display = "Failed to set the fan speed, {}", _0)]
+    Fan(Box<dyn Error>),
+
+    #[error(display = "Failed to tell whether the fan turns, {}", _0)]
+    Probe(Box<dyn Error>),
+
+    #[error(display = "The fan didn't turn at any speed, up to 100%")]
+    NoSpin,
+
+    #[error(display = "Failed to write {:?}, {}", _0, _1)]
+    Io(PathBuf, io::Error),
+
+    #[error(display = "The calibrated configuration is invalid, {}", _0)]
+    Config(#[error(from)] ConfigLoadError),
+}
+
+/// Whether the fan turned at a fan speed, and how fast if known
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct CalibrationPoint {
+    pub fan_speed: FanSpeed,
+    pub spinning: bool,
+    /// None without a tachometer
+    pub rpm: Option<u32>,
+}
+
+impl fmt::Display for CalibrationPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>5}  ", self.fan_speed.to_string())?;
+        match (self.rpm, self.spinning) {
+            (Some(rpm), _) => write!(f, "{} RPM", rpm),
+            (None, true) => f.write_str("spinning"),
+            (None, false) => f.write_str("stopped"),
+        }
+    }
+}
+
+/// Tells whether the fan turns, once set to a fan speed
+pub trait SpinProbe {
+    type Error: Error + 'static;
+
+    fn observe(&mut self, fan_speed: FanSpeed) -> Result<CalibrationPoint, Self::Error>;
+}
+
+/// Measures the RPM with the tachometer, after letting the fan settle
+pub struct TachProbe<K> {
+    clock: K,
+    feedback: Box<dyn FanFeedback<Error = TachError>>,
+    settle: Duration,
+}
+
+impl<K: Clock> TachProbe<K> {
+    pub fn new(
+        clock: K,
+        feedback: Box<dyn FanFeedback<Error = TachError>>,
+        settle: Duration,
+    ) -> Self {
+        TachProbe {
+            clock,
+            feedback,
+            settle,
+        }
+    }
+}
+
+impl<K: Clock> SpinProbe for TachProbe<K> {
+    type Error = TachError;
+
+    fn observe(&mut self, fan_speed: FanSpeed) -> Result<CalibrationPoint, Self::Error> {
+        self.clock.sleep(self.settle);
+        // Pulse counters average since the previous read, start afresh
+        self.feedback.rpm()?;
+        self.clock.sleep(MEASURE_TIME);
+        let rpm = self.feedback.rpm()?;
+        Ok(CalibrationPoint {
+            fan_speed,
+            spinning: rpm > 0,
+            rpm: Some(rpm),
+        })
+    }
+}
+
+/// Asks whether the fan spins, for fans without a tachometer
+pub struct InteractiveProbe<R, W, K> {
+    clock: K,
+    input: R,
+    output: W,
+    settle: Duration,
+}
+
+impl<R: BufRead, W: Write, K: Clock> InteractiveProbe<R, W, K> {
+    pub fn new(clock: K, input: R, output: W, settle: Duration) -> Self {
+        InteractiveProbe {
+            clock,
+            input,
+            output,
+            settle,
+        }
+    }
+}
+
+impl<R: BufRead, W: Write, K: Clock> SpinProbe for InteractiveProbe<R, W, K> {
+    type Error = io::Error;
+
+    fn observe(&mut self, fan_speed: FanSpeed) -> Result<CalibrationPoint, Self::Error> {
+        self.clock.sleep(self.settle);
+        loop {
+            write!(
+                self.output,
+                "Fan speed {}, does the fan spin? [y/n] ",
+                fan_speed
+            )?;
+            self.output.flush()?;
+            let mut answer = String::new();
+            if self.input.read_line(&mut answer)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let spinning = match answer.trim().to_lowercase().as_str() {
+                "y" | "yes" => true,
+                "n" | "no" => false,
+                _ => continue,
+            };
+            return Ok(CalibrationPoint {
+                fan_speed,
+                spinning,
+                rpm: None,
+            });
+        }
+    }
+}
+
+/// What a sweep up from stopped and back down found
+#[derive(Clone, PartialEq, Debug)]
+pub struct Calibration {
+    pub up: Vec<CalibrationPoint>,
+    pub down: Vec<CalibrationPoint>,
+    /// Lowest fan speed starting the fan from stopped
+    pub start: FanSpeed,
+    /// Lowest fan speed keeping the fan turning, once started
+    pub keep_running: FanSpeed,
+    /// Fan speed from which the fan gets no faster, and its RPM, with a tachometer
+    pub saturation: Option<(FanSpeed, u32)>,
+}
+
+impl Calibration {
+    /// None if the fan never turned
+    pub fn new(up: Vec<CalibrationPoint>, down: Vec<CalibrationPoint>) -> Option<Self> {
+        let first = up.iter().find(|p| p.spinning)?;
+        let start = first.fan_speed;
+        let top = up.last().map_or(start, |p| p.fan_speed);
+        let keep_running = down
+            .iter()
+            .take_while(|p| p.spinning)
+            .last()
+            .map_or(top, |p| p.fan_speed);
+        let rpms: Option<Vec<u32>> = up.iter().map(|p| p.rpm).collect();
+        let saturation = rpms
+            .and_then(|r| r.into_iter().max())
+            .filter(|max| *max > 0)
+            .and_then(|max| {
+                up.iter().find_map(|p| {
+                    let rpm = p.rpm?;
+                    if rpm * 100 >= max * SATURATION_PERCENT {
+                        Some((p.fan_speed, rpm))
+                    } else {
+                        None
+                    }
+                })
+            });
+        Some(Calibration {
+            up,
+            down,
+            start,
+            keep_running,
+            saturation,
+        })
+    }
+
+    /// The lowest fan speed the fan keeps turning at, with a margin
+    pub fn fan_speed_min(&self) -> FanSpeed {
+        Self::with_margin(self.keep_running)
+    }
+
+    /// A kick when the fan can't start at fan_speed_min
+    pub fn kick(&self) -> Option<KickConfig> {
+        if self.start > self.fan_speed_min() {
+            Some(KickConfig {
+                fan_speed: Self::with_margin(self.start),
+                ..Default::default()
+            })
+        } else {
+            None
+        }
+    }
+
+    fn with_margin(s: FanSpeed) -> FanSpeed {
+        FanSpeed::new_unchecked(u8::from(s).saturating_add(MARGIN).min(FanSpeed::MAX.into()))
+    }
+
+    /// The drop-in configuration file with the suggested values
+    pub fn drop_in(&self) -> String {
+        let mut out = format!(
+            "# Written by argon-fan-ctl calibrate, the fan starts at {} and keeps turning \
+             down to {}\nfan_speed_min = {}\n",
+            self.start,
+            self.keep_running,
+            u8::from(self.fan_speed_min())
+        );
+        if let Some(k) = self.kick() {
+            out.push_str(&format!(
+                "\n[kick]\nfan_speed = {}\nmilliseconds = {}\n",
+                u8::from(k.fan_speed),
+                k.milliseconds
+            ));
+        }
+        out
+    }
+}
+
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Up from stopped")?;
+        for p in self.up.iter() {
+            writeln!(f, "  {}", p)?;
+        }
+        writeln!(f, "Down")?;
+        for p in self.down.iter() {
+            writeln!(f, "  {}", p)?;
+        }
+        writeln!(
+            f,
+            "Starts at {}, keeps turning down to {}",
+            self.start, self.keep_running
+        )?;
+        if let Some((s, rpm)) = self.saturation {
+            writeln!(f, "No faster above {}, {} RPM", s, rpm)?;
+        }
+        write!(f, "Suggested fan_speed_min {}", self.fan_speed_min())?;
+        match self.kick() {
+            Some(k) => write!(f, ", kick at {}", k.fan_speed),
+            None => f.write_str(", no kick needed"),
+        }
+    }
+}
+
+/// Steps the fan up from stopped every `step` percent, then back down until it stops.
+/// Without an RPM the sweep up stops at the first speed the fan turns at. The fan is
+/// left at 100%, until the daemon takes over again.
+pub fn calibrate<F: Fan, P: SpinProbe>(
+    fan: &mut F,
+    probe: &mut P,
+    step: u8,
+) -> Result<Calibration, CalibrationError> {
+    let result = sweep(fan, probe, step.max(1));
+    if let Err(e) = fan.set_speed(FanSpeed::MAX) {
+        warn!("Failed to set the fan speed back to 100%, {}", e);
+    }
+    result
+}
+
+fn sweep<F: Fan, P: SpinProbe>(
+    fan: &mut F,
+    probe: &mut P,
+    step: u8,
+) -> Result<Calibration, CalibrationError> {
+    let mut observe = |s: FanSpeed| {
+        fan.set_speed(s)
+            .map_err(|e| CalibrationError::Fan(Box::new(e)))?;
+        let p = probe
+            .observe(s)
+            .map_err(|e| CalibrationError::Probe(Box::new(e)))?;
+        info!("{}", p);
+        Ok::<_, CalibrationError>(p)
+    };
+
+    let mut up = Vec::new();
+    for s in speeds_up(step) {
+        let p = observe(s)?;
+        up.push(p);
+        if p.spinning && p.rpm.is_none() {
+            break;
+        }
+    }
+    let mut down = Vec::new();
+    if let Some(top) = up.last().filter(|_| up.iter().any(|p| p.spinning)) {
+        for s in speeds_down(top.fan_speed, step) {
+            let p = observe(s)?;
+            down.push(p);
+            if !p.spinning {
+                break;
+            }
+        }
+    }
+    Calibration::new(up, down).ok_or(CalibrationError::NoSpin)
+}
+
+/// 0% to 100% every `step`, 100% included
+fn speeds_up(step: u8) -> Vec<FanSpeed> {
+    let mut speeds: Vec<FanSpeed> = (0..=FanSpeed::MAX.into())
+        .step_by(step.into())
+        .map(FanSpeed::new_unchecked)
+        .collect();
+    if speeds.last() != Some(&FanSpeed::MAX) {
+        speeds.push(FanSpeed::MAX);
+    }
+    speeds
+}
+
+/// Below `top` every `step`, down to 0% included
+fn speeds_down(top: FanSpeed, step: u8) -> Vec<FanSpeed> {
+    let mut speeds: Vec<FanSpeed> = (1..u8::from(top))
+        .rev()
+        .skip(usize::from(step) - 1)
+        .step_by(step.into())
+        .map(FanSpeed::new_unchecked)
+        .collect();
+    speeds.push(FanSpeed::MIN);
+    speeds
+}
+
+/// Writes the suggestions to the drop-in directory next to `config_path`, replacing
+/// the previous calibration. Left as it was if the resulting configuration is invalid.
+pub fn write_calibration(
+    config_path: &Path,
+    calibration: &Calibration,
+) -> Result<PathBuf, CalibrationError> {
+    let dir = config_path.parent().map_or_else(
+        || PathBuf::from(CONFIG_DROP_IN_DIR),
+        |p| p.join(CONFIG_DROP_IN_DIR),
+    );
+    let path = dir.join(CALIBRATION_DROP_IN);
+    let err = |p: &Path| {
+        let p = p.to_path_buf();
+        move |e| CalibrationError::Io(p, e)
+    };
+    fs::create_dir_all(&dir).map_err(err(&dir))?;
+    let previous = fs::read_to_string(&path).ok();
+    fs::write(&path, calibration.drop_in()).map_err(err(&path))?;
+    if let Err(e) = ConfigLayers::new(config_path).load() {
+        match previous {
+            Some(p) => fs::write(&path, p),
+            None => fs::remove_file(&path),
+        }
+        .map_err(err(&path))?;
+        return Err(e.into());
+    }
+    info!("Wrote the calibration to {}", path.display());
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::test::{FakeError, FakeFan};
+    use crate::tach::test::FakeTach;
+    use crate::{Config, ManualClock};
+
+    fn speed(s: u8) -> FanSpeed {
+        FanSpeed::new(s).unwrap()
+    }
+
+    /// Starts at 30% or more, keeps turning down to 20%, 50 RPM per percent up to 4000
+    #[derive(Default)]
+    struct FakeFanModel {
+        turning: bool,
+        tach: bool,
+    }
+
+    impl SpinProbe for FakeFanModel {
+        type Error = FakeError;
+
+        fn observe(&mut self, fan_speed: FanSpeed) -> Result<CalibrationPoint, Self::Error> {
+            let s = u8::from(fan_speed);
+            self.turning = s >= 30 || (self.turning && s >= 20);
+            let rpm = if self.turning {
+                (u32::from(s) * 50).min(4000)
+            } else {
+                0
+            };
+            Ok(CalibrationPoint {
+                fan_speed,
+                spinning: self.turning,
+                rpm: Some(rpm).filter(|_| self.tach),
+            })
+        }
+    }
+
+    #[test]
+    fn steps() {
+        let up: Vec<u8> = speeds_up(30).into_iter().map(u8::from).collect();
+        assert_eq!(up, vec![0, 30, 60, 90, 100]);
+        let down: Vec<u8> = speeds_down(speed(100), 30)
+            .into_iter()
+            .map(u8::from)
+            .collect();
+        assert_eq!(down, vec![70, 40, 10, 0]);
+        let down: Vec<u8> = speeds_down(speed(30), 10)
+            .into_iter()
+            .map(u8::from)
+            .collect();
+        assert_eq!(down, vec![20, 10, 0]);
+        let down: Vec<u8> = speeds_down(speed(1), 5).into_iter().map(u8::from).collect();
+        assert_eq!(down, vec![0]);
+    }
+
+    #[test]
+    fn with_tachometer() {
+        let mut fan = FakeFan::default();
+        let mut model = FakeFanModel {
+            tach: true,
+            ..Default::default()
+        };
+        let c = calibrate(&mut fan, &mut model, 5).unwrap();
+        assert_eq!(c.up.len(), 21);
+        assert_eq!(c.start, speed(30));
+        assert_eq!(c.keep_running, speed(20));
+        assert_eq!(c.saturation, Some((speed(80), 4000)));
+        assert_eq!(c.fan_speed_min(), speed(25));
+        assert_eq!(c.kick().unwrap().fan_speed, speed(35));
+        assert_eq!(c.down.last().unwrap().fan_speed, speed(15));
+        assert_eq!(fan.speeds().last(), Some(&FanSpeed::MAX));
+        assert!(c.to_string().ends_with(
+            "Starts at 30%, keeps turning down to 20%\n\
+             No faster above 80%, 4000 RPM\n\
+             Suggested fan_speed_min 25%, kick at 35%"
+        ));
+    }
+
+    #[test]
+    fn without_tachometer() {
+        let mut fan = FakeFan::default();
+        let c = calibrate(&mut fan, &mut FakeFanModel::default(), 10).unwrap();
+        // Up to the first speed it turns at only
+        let up: Vec<u8> = c.up.iter().map(|p| p.fan_speed.into()).collect();
+        assert_eq!(up, vec![0, 10, 20, 30]);
+        let down: Vec<u8> = c.down.iter().map(|p| p.fan_speed.into()).collect();
+        assert_eq!(down, vec![20, 10]);
+        assert_eq!(c.saturation, None);
+        assert_eq!(c.fan_speed_min(), speed(25));
+        assert_eq!(
+            c.drop_in(),
+            "# Written by argon-fan-ctl calibrate, the fan starts at 30% and keeps turning \
+             down to 20%\nfan_speed_min = 25\n\n[kick]\nfan_speed = 35\nmilliseconds = 1000\n"
+        );
+    }
+
+    #[test]
+    fn no_spin() {
+        let mut fan = FakeFan::default();
+        let never = |fan_speed| CalibrationPoint {
+            fan_speed,
+            spinning: false,
+            rpm: Some(0),
+        };
+        let up = speeds_up(25).into_iter().map(never).collect();
+        assert_eq!(Calibration::new(up, Vec::new()), None);
+        *fan.fail.lock().unwrap() = true;
+        assert!(matches!(
+            calibrate(&mut fan, &mut FakeFanModel::default(), 10),
+            Err(CalibrationError::Fan(_))
+        ));
+    }
+
+    #[test]
+    fn tach_probe_waits_on_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let settle = Duration::from_secs(5);
+        let tach = FakeTach(vec![0, 900].into_iter().collect());
+        let mut probe = TachProbe::new(clock.clone(), Box::new(tach), settle);
+        let p = probe.observe(speed(30)).unwrap();
+        assert_eq!(p.rpm, Some(900));
+        assert!(p.spinning);
+        assert_eq!(clock.now() - start, settle + MEASURE_TIME);
+    }
+
+    #[test]
+    fn interactive() {
+        let input = io::Cursor::new("n\nn\nmaybe\nY\nyes\nno\n");
+        let mut out = Vec::new();
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let settle = Duration::from_secs(3);
+        let mut probe = InteractiveProbe::new(clock.clone(), input, &mut out, settle);
+        let mut fan = FakeFan::default();
+        let c = calibrate(&mut fan, &mut probe, 10).unwrap();
+        // Settled before each of the five answers
+        assert_eq!(clock.now() - start, settle * 5);
+        assert_eq!(c.start, speed(20));
+        assert_eq!(c.keep_running, speed(10));
+        assert_eq!(c.kick().unwrap().fan_speed, speed(25));
+        // Out of answers
+        assert!(matches!(
+            calibrate(&mut fan, &mut probe, 10),
+            Err(CalibrationError::Probe(_))
+        ));
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with(
+            "Fan speed 0%, does the fan spin? [y/n] \
+             Fan speed 10%, does the fan spin? [y/n] \
+             Fan speed 20%, does the fan spin? [y/n] \
+             Fan speed 20%, does the fan spin? [y/n] \
+             Fan speed 10%, does the fan spin? [y/n] \
+             Fan speed 0%, does the fan spin? [y/n] "
+        ));
+    }
+
+    #[test]
+    fn write() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, Config::default().to_toml_string().unwrap()).unwrap();
+        let mut model = FakeFanModel {
+            tach: true,
+            ..Default::default()
+        };
+        let c = calibrate(&mut FakeFan::default(), &mut model, 5).unwrap();
+        let path = write_calibration(&config_path, &c).unwrap();
+        assert_eq!(path, dir.path().join("config.d").join("calibration.toml"));
+        let config = ConfigLayers::new(&config_path).load().unwrap().config;
+        assert_eq!(config.fan_speed_min, speed(25));
+        assert_eq!(config.kick.unwrap().fan_speed, speed(35));
+
+        // Above fan_speed_max, the previous calibration stays
+        let config = Config {
+            fan_speed_max: speed(20),
+            ..Default::default()
+        };
+        fs::write(&config_path, config.to_toml_string().unwrap()).unwrap();
+        fs::write(&path, "fan_speed_min = 10\n").unwrap();
+        assert!(matches!(
+            write_calibration(&config_path, &c),
+            Err(CalibrationError::Config(_))
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fan_speed_min = 10\n");
+    }
+}
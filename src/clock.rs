@@ -0,0 +1,176 @@
+use chrono::prelude::*;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Source of monotonic time for everything time dependent
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    /// Local wall clock time, for time of day schedules
+    fn local_time(&self) -> NaiveDateTime;
+
+    /// Wall clock time, for timestamps
+    fn utc_now(&self) -> DateTime<Utc>;
+
+    fn sleep(&self, duration: Duration);
+
+    /// Sleeps for up to `timeout`, returns true if woken early by `wakeup`
+    fn wait(&self, timeout: Duration, wakeup: &Wakeup) -> bool;
+}
+
+/// Wakes a waiting loop early, e.g. on shutdown or an incoming command.
+/// A notification made while nothing waits is kept for the next wait.
+#[derive(Clone, Debug, Default)]
+pub struct Wakeup(Arc<(Mutex<bool>, Condvar)>);
+
+impl Wakeup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn notify(&self) {
+        let (pending, cvar) = &*self.0;
+        *pending.lock().unwrap() = true;
+        cvar.notify_all();
+    }
+
+    /// Clears a pending notification, returns true if there was one
+    fn take(&self) -> bool {
+        std::mem::replace(&mut *(self.0).0.lock().unwrap(), false)
+    }
+}
+
+/// The system monotonic clock
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn local_time(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+
+    fn utc_now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+
+    fn wait(&self, timeout: Duration, wakeup: &Wakeup) -> bool {
+        let (pending, cvar) = &*wakeup.0;
+        let guard = pending.lock().unwrap();
+        let (mut guard, _) = cvar
+            .wait_timeout_while(guard, timeout, |pending| !*pending)
+            .unwrap();
+        std::mem::replace(&mut *guard, false)
+    }
+}
+
+/// A clock that only moves when advanced or slept on, clones share the same time
+#[derive(Clone, Debug)]
+pub struct ManualClock(Arc<Mutex<(Instant, NaiveDateTime)>>);
+
+impl ManualClock {
+    /// Starts at midnight, 2022-01-01 local time
+    pub fn new() -> Self {
+        Self::at(
+            NaiveDate::from_ymd_opt(2022, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+        )
+    }
+
+    pub fn at(local_time: NaiveDateTime) -> Self {
+        ManualClock(Arc::new(Mutex::new((Instant::now(), local_time))))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut t = self.0.lock().unwrap();
+        t.0 += duration;
+        t.1 += chrono::Duration::from_std(duration).unwrap();
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.0.lock().unwrap().0
+    }
+
+    fn local_time(&self) -> NaiveDateTime {
+        self.0.lock().unwrap().1
+    }
+
+    /// The local time taken as UTC, a manual clock has no time zone
+    fn utc_now(&self) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&self.local_time())
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+
+    /// Returns immediately if a notification is pending, otherwise advances by `timeout`
+    fn wait(&self, timeout: Duration, wakeup: &Wakeup) -> bool {
+        if wakeup.take() {
+            true
+        } else {
+            self.advance(timeout);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new();
+        let other = clock.clone();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_secs(5));
+        other.sleep(Duration::from_secs(3600));
+        assert_eq!(clock.now() - start, Duration::from_secs(3605));
+        assert_eq!(other.now(), clock.now());
+        assert_eq!(clock.local_time().to_string(), "2022-01-01 01:00:05");
+        assert_eq!(clock.utc_now().to_rfc3339(), "2022-01-01T01:00:05+00:00");
+
+        let wakeup = Wakeup::new();
+        let now = clock.now();
+        assert!(!clock.wait(Duration::from_secs(10), &wakeup));
+        assert_eq!(clock.now() - now, Duration::from_secs(10));
+        wakeup.notify();
+        assert!(clock.wait(Duration::from_secs(10), &wakeup));
+        assert_eq!(clock.now() - now, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn system_clock_wakeup() {
+        let wakeup = Wakeup::new();
+        let w = wakeup.clone();
+        let start = Instant::now();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            w.notify();
+        });
+        assert!(SystemClock.wait(Duration::from_secs(10), &wakeup));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        t.join().unwrap();
+        assert!(!SystemClock.wait(Duration::from_millis(1), &wakeup));
+    }
+}
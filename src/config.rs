@@ -1,9 +1,16 @@
-use crate::{DegreesC, FanSpeed, UpdateIntervalSeconds};
+use crate::layers::temperatures_in_unit;
+use crate::{
+    AdaptiveIntervalConfig, ConfigLayers, DegreesC, FanOutputConfig, FanSpeed, FanSpeedMapError,
+    FeedForwardConfig, KickConfig, MigrationError, MissedTicks, MqttConfig, Profile,
+    ProfileSchedule, ScheduleEntry, TachometerConfig, TelemetryConfig, TemperatureUnit,
+    UpdateIntervalSeconds, CONFIG_VERSION, DEFAULT_PROFILE, MAIN_FAN,
+};
 use log::info;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::num::NonZeroU32;
 use std::path::{Path, PathBuf};
-use std::{fs, io};
+use std::{fmt, fs, io};
 
 #[derive(Debug, err_derive::Error)]
 pub enum ConfigLoadError {
@@ -13,29 +20,147 @@ pub enum ConfigLoadError {
     #[error(display = "Configuration file {:?} is invalid, {}", _0, _1)]
     Invalid(PathBuf, toml::de::Error),
 
-    #[error(display = "{}", _0)]
-    Check(#[error(from)] ConfigCheckError),
+    #[error(display = "Configuration file {:?} is invalid\n{}", _0, _1)]
+    Check(PathBuf, ConfigReport),
+
+    #[error(display = "Configuration file {:?} can't be migrated, {}", _0, _1)]
+    Migration(PathBuf, MigrationError),
+
+    #[error(display = "Failed to serialize the configuration, {}", _0)]
+    Serialize(#[error(from)] toml::ser::Error),
+
+    #[error(display = "The merged configuration is invalid, {}", _0)]
+    Merged(toml::de::Error),
+
+    #[error(
+        display = "Invalid configuration override '{}', expected key=value",
+        _0
+    )]
+    InvalidOverride(String),
 }
 
-#[derive(Debug, PartialEq, err_derive::Error)]
+#[derive(Debug, Clone, PartialEq, err_derive::Error)]
 pub enum ConfigCheckError {
-    #[error(display = "The configuration file temperature range is invalid")]
-    InvalidTemperatureRange,
+    #[error(display = "{}", _0)]
+    FanSpeedMap(#[error(from)] FanSpeedMapError),
+
+    #[error(
+        display = "min_interval_seconds ({}) must not be above max_interval_seconds ({})",
+        _0,
+        _1
+    )]
+    InvalidAdaptiveIntervalRange(UpdateIntervalSeconds, UpdateIntervalSeconds),
+
+    #[error(
+        display = "utilisation_min ({}%) must be below utilisation_max ({}%), at most 100%",
+        _0,
+        _1
+    )]
+    InvalidFeedForwardRange(u8, u8),
+
+    #[error(display = "hysteresis ({}) must not be below 0", _0)]
+    NegativeHysteresis(DegreesC),
+
+    #[error(
+        display = "safety_temperature ({}) must not be below temperature_min ({})",
+        _0,
+        _1
+    )]
+    InvalidSafetyTemperature(DegreesC, DegreesC),
+
+    #[error(display = "exactly one of gpio_pin and register must be set")]
+    InvalidTachometerSource,
+
+    #[error(display = "pulses_per_revolution must be above 0")]
+    InvalidPulsesPerRevolution,
+
+    #[error(display = "profile name '{}' is reserved", _0)]
+    ReservedProfileName(String),
+
+    #[error(display = "unknown profile '{}'", _0)]
+    UnknownProfile(String),
+
+    #[error(display = "fan name '{}' is reserved", _0)]
+    ReservedFanName(String),
+
+    #[error(
+        display = "fan name '{}' must only have the characters a-z, A-Z, 0-9, _ and -",
+        _0
+    )]
+    InvalidFanName(String),
+
+    #[error(display = "exactly one of i2c_address and gpio_pin must be set")]
+    InvalidFanDriver,
+}
+
+/// A problem found in the configuration, at a dotted key such as
+/// "profiles.quiet.fan_speed_max" or "schedule[0].profile"
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigIssue {
+    pub key: String,
+    pub error: ConfigCheckError,
+    /// Line and column, 1 based, when found in the source
+    pub location: Option<(usize, usize)>,
+    /// Where the value came from, when not the configuration file itself
+    pub origin: Option<String>,
+}
+
+impl ConfigIssue {
+    pub fn new<K: Into<String>, E: Into<ConfigCheckError>>(key: K, error: E) -> Self {
+        ConfigIssue {
+            key: key.into(),
+            error: error.into(),
+            location: None,
+            origin: None,
+        }
+    }
+}
 
-    #[error(display = "The configuration file fan speed range is invalid")]
-    InvalidFanSpeedRange,
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.error)?;
+        match (&self.origin, self.location) {
+            (Some(o), Some((line, col))) => write!(f, " ({}, line {}, column {})", o, line, col),
+            (Some(o), None) => write!(f, " ({})", o),
+            (None, Some((line, col))) => write!(f, " (line {}, column {})", line, col),
+            (None, None) => Ok(()),
+        }
+    }
+}
 
-    #[error(display = "The configuration file fan speed min is invalid")]
-    InvalidFanSpeedMin,
+/// Every problem found in the configuration
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConfigReport {
+    pub issues: Vec<ConfigIssue>,
+}
 
-    #[error(display = "The configuration file fan speed max is invalid")]
-    InvalidFanSpeedMax,
+impl fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, issue) in self.issues.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "  {}", issue)?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+impl std::error::Error for ConfigReport {}
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// Layout version, files without one are the v0.2 layout, the same but for this field
+    #[serde(default = "Config::default_version")]
+    pub version: u32,
     /// Time interval to check temperature and update fan speed
     pub update_interval_seconds: UpdateIntervalSeconds,
+    /// Whether to skip or catch up on updates missed while the loop was held up
+    #[serde(default)]
+    pub missed_ticks: MissedTicks,
+    /// Unit temperatures are shown in, and read in when written without a C or F suffix
+    #[serde(default)]
+    pub units: TemperatureUnit,
     /// Min temp, degrees C
     pub temperature_min: DegreesC,
     /// Max temp, degrees C
@@ -44,61 +169,414 @@ pub struct Config {
     pub fan_speed_min: FanSpeed,
     /// Max fan speed percentage
     pub fan_speed_max: FanSpeed,
+    /// Degrees C the temperature must drop by before the fan slows down
+    #[serde(default)]
+    pub hysteresis: DegreesC,
+    /// At or above this temperature the fan runs at 100%
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub safety_temperature: Option<DegreesC>,
+    /// Adapt the update interval to how fast the temperature changes, disabled when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adaptive_interval: Option<AdaptiveIntervalConfig>,
+    /// Boost the fan speed with the CPU load, ahead of the temperature, disabled when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feed_forward: Option<FeedForwardConfig>,
+    /// Read the fan speed back and raise an alarm when it stalls, disabled when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tachometer: Option<TachometerConfig>,
+    /// Run the fan faster for a moment when starting it, disabled when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kick: Option<KickConfig>,
+    /// Named profiles besides the default one (the top level fields)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+    /// Local time of day windows using the named profiles, the first match wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<ScheduleEntry>,
+    /// MQTT publishing and Home Assistant discovery, disabled when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttConfig>,
+    /// Per tick telemetry logging, disabled when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub telemetry: Option<TelemetryConfig>,
+    /// Fans besides the main one, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fans: BTreeMap<String, FanOutputConfig>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
+            version: CONFIG_VERSION,
             update_interval_seconds: UpdateIntervalSeconds(NonZeroU32::new(30).unwrap()),
+            missed_ticks: MissedTicks::Skip,
+            units: TemperatureUnit::Celsius,
+            adaptive_interval: None,
+            feed_forward: None,
+            tachometer: None,
+            kick: None,
             temperature_min: 33.into(),
             temperature_max: 65.into(),
             fan_speed_min: FanSpeed(0),
             fan_speed_max: FanSpeed::MAX,
+            hysteresis: DegreesC::new(0),
+            safety_temperature: None,
+            profiles: BTreeMap::new(),
+            schedule: Vec::new(),
+            mqtt: None,
+            telemetry: None,
+            fans: BTreeMap::new(),
         }
     }
 }
 
 impl Config {
+    fn default_version() -> u32 {
+        CONFIG_VERSION
+    }
+
+    /// Loads the configuration file layered over the defaults, with its drop-in files
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigLoadError> {
-        let content = fs::read_to_string(&path)
-            .map_err(|e| ConfigLoadError::Io(path.as_ref().to_path_buf(), e))?;
-        let config: Self = toml::from_str(&content)
-            .map_err(|e| ConfigLoadError::Invalid(path.as_ref().to_path_buf(), e))?;
-        config.check()?;
+        Self::load_versioned(path).map(|(config, _)| config)
+    }
+
+    /// Like `load`, along with the version of the file before migration
+    pub fn load_versioned<P: AsRef<Path>>(path: P) -> Result<(Self, u32), ConfigLoadError> {
+        let e = ConfigLayers::new(&path).load()?;
         info!("Loaded configuration file {}", path.as_ref().display());
-        info!("Update interval {}", config.update_interval_seconds);
+        e.config.log_summary();
+        Ok((e.config, e.version))
+    }
+
+    pub fn log_summary(&self) {
+        info!(
+            "Update interval {}, {:?} missed updates",
+            self.update_interval_seconds, self.missed_ticks
+        );
         info!(
             "Temperature range {}..={} C",
-            u8::from(config.temperature_min),
-            u8::from(config.temperature_max)
+            self.temperature_min.as_f32(),
+            self.temperature_max.as_f32()
         );
         info!(
             "Fan speed range {}..={} %",
-            u8::from(config.fan_speed_min),
-            u8::from(config.fan_speed_max)
+            u8::from(self.fan_speed_min),
+            u8::from(self.fan_speed_max)
+        );
+        for (name, p) in self.profiles.iter() {
+            info!(
+                "Profile {}, temperature range {}..={} C, fan speed range {}..={} %",
+                name,
+                p.temperature_min.as_f32(),
+                p.temperature_max.as_f32(),
+                u8::from(p.fan_speed_min),
+                u8::from(p.fan_speed_max)
+            );
+        }
+        for e in self.schedule.iter() {
+            info!("Profile {} from {} to {}", e.profile, e.start, e.end);
+        }
+        if let Some(a) = &self.adaptive_interval {
+            info!(
+                "Adaptive update interval {}..={}",
+                a.min_interval_seconds, a.max_interval_seconds
+            );
+        }
+        if let Some(f) = &self.feed_forward {
+            info!(
+                "Feed forward boost up to {} from {}..={} % CPU utilisation",
+                f.boost, f.utilisation_min, f.utilisation_max
+            );
+        }
+        if let Some(t) = &self.tachometer {
+            info!(
+                "Tachometer stall alarm at {} and 0 RPM for {} s",
+                t.stall_fan_speed, t.stall_seconds
+            );
+        }
+        if let Some(k) = &self.kick {
+            info!(
+                "Fan start kick at {} for {} ms",
+                k.fan_speed, k.milliseconds
+            );
+        }
+        if let Some(mqtt) = &self.mqtt {
+            info!("MQTT broker {}:{}", mqtt.host, mqtt.port);
+        }
+        if let Some(telemetry) = &self.telemetry {
+            info!("Telemetry file {}", telemetry.path.display());
+        }
+        for (name, f) in self.fans.iter() {
+            info!(
+                "Fan {} on {}, {} profile",
+                name,
+                match (f.i2c_address, f.gpio_pin) {
+                    (Some(addr), _) => format!("I2C address 0x{:X}", addr),
+                    (None, Some(pin)) => format!("GPIO pin {}", pin),
+                    (None, None) => "no driver".to_string(),
+                },
+                f.profile.as_deref().unwrap_or("the main fan's")
+            );
+        }
+    }
+
+    /// Rewrites a configuration file of an older version in the current one, the
+    /// original is kept as `<path>.v<version>.bak`. Comments are not carried over.
+    /// Returns the backup path, or None if the file was already current.
+    pub fn migrate_file<P: AsRef<Path>>(path: P) -> Result<Option<PathBuf>, ConfigLoadError> {
+        let path = path.as_ref();
+        let e = ConfigLayers::new(path).with_drop_in_dir(None).load()?;
+        let (config, version) = (e.config, e.version);
+        if version == CONFIG_VERSION {
+            return Ok(None);
+        }
+        let err = |e| ConfigLoadError::Io(path.to_path_buf(), e);
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(".v{}.bak", version));
+        let backup = PathBuf::from(backup);
+        fs::copy(path, &backup).map_err(err)?;
+        fs::write(path, config.to_toml_string()?).map_err(err)?;
+        info!(
+            "Migrated configuration file {} from version {} to {}, the original is {}",
+            path.display(),
+            version,
+            CONFIG_VERSION,
+            backup.display()
         );
-        Ok(config)
+        Ok(Some(backup))
     }
 
-    pub fn check(&self) -> Result<(), ConfigCheckError> {
-        if self.temperature_min.0 >= self.temperature_max.0 {
-            Err(ConfigCheckError::InvalidTemperatureRange)
-        } else if self.fan_speed_min.0 >= self.fan_speed_max.0 {
-            Err(ConfigCheckError::InvalidFanSpeedRange)
-        } else if self.fan_speed_min.0 > FanSpeed::MAX.0 {
-            Err(ConfigCheckError::InvalidFanSpeedMin)
-        } else if self.fan_speed_max.0 > FanSpeed::MAX.0 {
-            Err(ConfigCheckError::InvalidFanSpeedMax)
-        } else {
+    /// The configuration file contents, with the temperatures in `units`
+    pub fn to_toml_string(&self) -> Result<String, ConfigLoadError> {
+        if self.units == TemperatureUnit::Celsius {
+            return Ok(toml::to_string_pretty(self)?);
+        }
+        let mut value = toml::Value::try_from(self)?;
+        temperatures_in_unit(&mut value, self.units);
+        Ok(toml::to_string_pretty(&value)?)
+    }
+
+    /// Collects every problem, rather than stopping at the first one
+    pub fn check(&self) -> Result<(), ConfigReport> {
+        let mut issues = Vec::new();
+        Self::check_profile("", &self.default_profile(), &mut issues);
+        for (name, p) in self.profiles.iter() {
+            if name == DEFAULT_PROFILE {
+                issues.push(ConfigIssue::new(
+                    format!("profiles.{}", name),
+                    ConfigCheckError::ReservedProfileName(name.clone()),
+                ));
+            }
+            Self::check_profile(&format!("profiles.{}.", name), p, &mut issues);
+        }
+        for (i, e) in self.schedule.iter().enumerate() {
+            if e.profile != DEFAULT_PROFILE && !self.profiles.contains_key(&e.profile) {
+                issues.push(ConfigIssue::new(
+                    format!("schedule[{}].profile", i),
+                    ConfigCheckError::UnknownProfile(e.profile.clone()),
+                ));
+            }
+        }
+        if let Some(a) = &self.adaptive_interval {
+            if a.min_interval_seconds > a.max_interval_seconds {
+                issues.push(ConfigIssue::new(
+                    "adaptive_interval.max_interval_seconds",
+                    ConfigCheckError::InvalidAdaptiveIntervalRange(
+                        a.min_interval_seconds,
+                        a.max_interval_seconds,
+                    ),
+                ));
+            }
+        }
+        if let Some(f) = &self.feed_forward {
+            if f.utilisation_min >= f.utilisation_max || f.utilisation_max > 100 {
+                issues.push(ConfigIssue::new(
+                    "feed_forward.utilisation_max",
+                    ConfigCheckError::InvalidFeedForwardRange(f.utilisation_min, f.utilisation_max),
+                ));
+            }
+        }
+        if let Some(t) = &self.tachometer {
+            if t.gpio_pin.is_some() == t.register.is_some() {
+                issues.push(ConfigIssue::new(
+                    "tachometer",
+                    ConfigCheckError::InvalidTachometerSource,
+                ));
+            }
+            if t.pulses_per_revolution == 0 {
+                issues.push(ConfigIssue::new(
+                    "tachometer.pulses_per_revolution",
+                    ConfigCheckError::InvalidPulsesPerRevolution,
+                ));
+            }
+        }
+        if let Some(k) = &self.kick {
+            if k.fan_speed > FanSpeed::MAX {
+                issues.push(ConfigIssue::new(
+                    "kick.fan_speed",
+                    FanSpeedMapError::InvalidFanSpeed(k.fan_speed),
+                ));
+            }
+        }
+        for (name, f) in self.fans.iter() {
+            Self::check_fan(name, f, &mut issues);
+            if let Some(p) = &f.profile {
+                if p != DEFAULT_PROFILE && !self.profiles.contains_key(p) {
+                    issues.push(ConfigIssue::new(
+                        format!("fans.{}.profile", name),
+                        ConfigCheckError::UnknownProfile(p.clone()),
+                    ));
+                }
+            }
+        }
+        if issues.is_empty() {
             Ok(())
+        } else {
+            Err(ConfigReport { issues })
+        }
+    }
+
+    fn check_fan(name: &str, f: &FanOutputConfig, issues: &mut Vec<ConfigIssue>) {
+        let key = format!("fans.{}", name);
+        if name == MAIN_FAN {
+            issues.push(ConfigIssue::new(
+                &key,
+                ConfigCheckError::ReservedFanName(name.to_string()),
+            ));
+        } else if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            issues.push(ConfigIssue::new(
+                &key,
+                ConfigCheckError::InvalidFanName(name.to_string()),
+            ));
+        }
+        if f.i2c_address.is_some() == f.gpio_pin.is_some() {
+            issues.push(ConfigIssue::new(&key, ConfigCheckError::InvalidFanDriver));
+        }
+        if let Some(k) = &f.kick {
+            if k.fan_speed > FanSpeed::MAX {
+                issues.push(ConfigIssue::new(
+                    format!("{}.kick.fan_speed", key),
+                    FanSpeedMapError::InvalidFanSpeed(k.fan_speed),
+                ));
+            }
+        }
+    }
+
+    fn check_profile(prefix: &str, p: &Profile, issues: &mut Vec<ConfigIssue>) {
+        let mut speeds_valid = true;
+        for (key, speed) in [
+            ("fan_speed_min", p.fan_speed_min),
+            ("fan_speed_max", p.fan_speed_max),
+        ]
+        .iter()
+        {
+            if *speed > FanSpeed::MAX {
+                speeds_valid = false;
+                issues.push(ConfigIssue::new(
+                    format!("{}{}", prefix, key),
+                    FanSpeedMapError::InvalidFanSpeed(*speed),
+                ));
+            }
+        }
+        if p.temperature_min >= p.temperature_max {
+            issues.push(ConfigIssue::new(
+                format!("{}temperature_max", prefix),
+                FanSpeedMapError::InvalidTemperatureRange(p.temperature_min, p.temperature_max),
+            ));
+        }
+        if speeds_valid && p.fan_speed_min >= p.fan_speed_max {
+            issues.push(ConfigIssue::new(
+                format!("{}fan_speed_max", prefix),
+                FanSpeedMapError::InvalidFanSpeedRange(p.fan_speed_min, p.fan_speed_max),
+            ));
+        }
+        if p.hysteresis < DegreesC::new(0) {
+            issues.push(ConfigIssue::new(
+                format!("{}hysteresis", prefix),
+                ConfigCheckError::NegativeHysteresis(p.hysteresis),
+            ));
+        }
+        if let Some(t) = p.safety_temperature.filter(|t| *t < p.temperature_min) {
+            issues.push(ConfigIssue::new(
+                format!("{}safety_temperature", prefix),
+                ConfigCheckError::InvalidSafetyTemperature(t, p.temperature_min),
+            ));
+        }
+    }
+
+    /// The profile made of the top level fields
+    pub fn default_profile(&self) -> Profile {
+        Profile {
+            temperature_min: self.temperature_min,
+            temperature_max: self.temperature_max,
+            fan_speed_min: self.fan_speed_min,
+            fan_speed_max: self.fan_speed_max,
+            hysteresis: self.hysteresis,
+            safety_temperature: self.safety_temperature,
+        }
+    }
+
+    pub fn profile(&self, name: &str) -> Option<Profile> {
+        if name == DEFAULT_PROFILE {
+            Some(self.default_profile())
+        } else {
+            self.profiles.get(name).cloned()
+        }
+    }
+
+    pub fn profile_schedule(&self) -> ProfileSchedule {
+        let mut profiles = self.profiles.clone();
+        profiles.insert(DEFAULT_PROFILE.to_string(), self.default_profile());
+        ProfileSchedule::new(profiles, self.schedule.clone())
+    }
+}
+
+/// Finds a dotted key in TOML source written with plain `key = value` lines under
+/// `[table]` and `[[array]]` headers, falling back to a table header
+pub(crate) fn locate_key(source: &str, key: &str) -> Option<(usize, usize)> {
+    let (table, leaf) = match key.rfind('.') {
+        Some(i) => (&key[..i], &key[i + 1..]),
+        None => ("", key),
+    };
+    let mut current = String::new();
+    let mut arrays: BTreeMap<String, usize> = BTreeMap::new();
+    let mut header = None;
+    for (n, line) in source.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let col = line.len() - trimmed.len() + 1;
+        if let Some(name) = trimmed.strip_prefix("[[") {
+            let name: String = name.split("]]").next()?.split_whitespace().collect();
+            let count = arrays.entry(name.clone()).or_insert(0);
+            current = format!("{}[{}]", name, count);
+            *count += 1;
+        } else if let Some(name) = trimmed.strip_prefix('[') {
+            current = name.split(']').next()?.split_whitespace().collect();
+        } else if current == table {
+            if let Some(rest) = trimmed.strip_prefix(leaf) {
+                if rest.trim_start().starts_with('=') {
+                    return Some((n + 1, col));
+                }
+            }
+            continue;
+        } else {
+            continue;
+        }
+        if current == key && header.is_none() {
+            header = Some((n + 1, col));
         }
     }
+    header
 }
 
 #[cfg(test)]
 pub(crate) mod test {
     use super::*;
     use crate::test::*;
+    use crate::{HA_DISCOVERY_PREFIX, MQTT_PORT};
     use proptest::prelude::*;
     use std::cmp::Ordering;
 
@@ -109,11 +587,22 @@ pub(crate) mod test {
             t_b in gen_degrees_c(),
             fs_a in gen_fan_speed(),
             fs_b in gen_fan_speed(),
+            adaptive in proptest::option::of(
+                (gen_update_interval_seconds(), gen_update_interval_seconds())
+            ),
+            feed_forward in proptest::option::of((0..50u8, 50..=100u8, gen_fan_speed(), any::<bool>())),
+            tachometer in proptest::option::of((any::<u8>(), any::<bool>(), 1..=4u8, gen_fan_speed(), any::<u32>())),
+            kick in proptest::option::of((gen_fan_speed(), any::<u32>())),
+            fans in proptest::collection::btree_map(
+                "[a-z0-9_-]{1,8}".prop_filter("reserved", |n| n != MAIN_FAN),
+                (any::<u8>(), any::<bool>(), any::<u32>(), proptest::option::of("/[a-z/]{1,20}")),
+                0..3,
+            ),
         ) -> Config {
             let (t_min, t_max) = match t_a.cmp(&t_b) {
                 Ordering::Less => (t_a, t_b),
                 Ordering::Greater => (t_b, t_a),
-                Ordering::Equal => (t_a.0.saturating_sub(1).into(), t_b.0.saturating_add(1).into()),
+                Ordering::Equal => (t_a.saturating_sub(DegreesC::from_tenths(1)), t_b.saturating_add(DegreesC::from_tenths(1))),
             };
             let (fs_min, fs_max) = match fs_a.cmp(&fs_b) {
                 Ordering::Less => (fs_a, fs_b),
@@ -127,12 +616,58 @@ pub(crate) mod test {
             };
             assert!(t_max > t_min);
             assert!(fs_max > fs_min);
+            let adaptive_interval = adaptive.map(|(a, b)| AdaptiveIntervalConfig {
+                min_interval_seconds: a.min(b),
+                max_interval_seconds: a.max(b),
+                ..Default::default()
+            });
+            let feed_forward = feed_forward.map(|(min, max, boost, load_average)| FeedForwardConfig {
+                utilisation_min: min,
+                utilisation_max: max,
+                boost,
+                load_average,
+            });
+            let tachometer = tachometer.map(|(source, gpio, pulses, speed, seconds)| TachometerConfig {
+                gpio_pin: Some(source).filter(|_| gpio),
+                register: Some(source).filter(|_| !gpio),
+                pulses_per_revolution: pulses,
+                stall_fan_speed: speed,
+                stall_seconds: seconds,
+            });
+            let kick = kick.map(|(fan_speed, milliseconds)| KickConfig { fan_speed, milliseconds });
+            let fans = fans
+                .into_iter()
+                .map(|(name, (target, gpio, frequency, sensor))| {
+                    let fan = FanOutputConfig {
+                        i2c_address: Some(u16::from(target)).filter(|_| !gpio),
+                        gpio_pin: Some(target).filter(|_| gpio),
+                        pwm_frequency_hz: frequency,
+                        sensor: sensor.map(PathBuf::from),
+                        ..Default::default()
+                    };
+                    (name, fan)
+                })
+                .collect();
             let config = Config {
+                version: CONFIG_VERSION,
                 update_interval_seconds: i,
+                missed_ticks: MissedTicks::Skip,
+                units: TemperatureUnit::Celsius,
+                adaptive_interval,
+                feed_forward,
+                tachometer,
+                kick,
                 temperature_min: t_min,
                 temperature_max: t_max,
                 fan_speed_min: fs_min,
                 fan_speed_max: fs_max,
+                hysteresis: DegreesC::new(0),
+                safety_temperature: None,
+                profiles: BTreeMap::new(),
+                schedule: Vec::new(),
+                mqtt: None,
+                telemetry: None,
+                fans,
             };
             assert!(config.check().is_ok());
             config
@@ -160,32 +695,399 @@ pub(crate) mod test {
         assert_eq!(
             Config::default(),
             Config {
+                version: CONFIG_VERSION,
                 update_interval_seconds: UpdateIntervalSeconds(NonZeroU32::new(30).unwrap()),
+                missed_ticks: MissedTicks::Skip,
+                units: TemperatureUnit::Celsius,
+                adaptive_interval: None,
+                feed_forward: None,
+                tachometer: None,
+                kick: None,
                 temperature_min: 33.into(),
                 temperature_max: 65.into(),
                 fan_speed_min: FanSpeed::new(0).unwrap(),
                 fan_speed_max: FanSpeed::MAX,
+                hysteresis: DegreesC::new(0),
+                safety_temperature: None,
+                profiles: BTreeMap::new(),
+                schedule: Vec::new(),
+                mqtt: None,
+                telemetry: None,
+                fans: BTreeMap::new(),
             }
         );
     }
 
+    fn issues(c: &Config) -> Vec<(String, ConfigCheckError)> {
+        c.check()
+            .unwrap_err()
+            .issues
+            .into_iter()
+            .map(|i| (i.key, i.error))
+            .collect()
+    }
+
     #[test]
     fn config_check_errors() {
         let c = Config {
-            update_interval_seconds: UpdateIntervalSeconds(NonZeroU32::new(30).unwrap()),
             temperature_min: 1.into(),
             temperature_max: 0.into(),
             fan_speed_min: FanSpeed::new(10).unwrap(),
-            fan_speed_max: FanSpeed::MAX,
+            fan_speed_max: FanSpeed::new(1).unwrap(),
+            adaptive_interval: Some(AdaptiveIntervalConfig {
+                min_interval_seconds: UpdateIntervalSeconds(NonZeroU32::new(60).unwrap()),
+                max_interval_seconds: UpdateIntervalSeconds(NonZeroU32::new(10).unwrap()),
+                ..Default::default()
+            }),
+            feed_forward: Some(FeedForwardConfig {
+                utilisation_min: 90,
+                utilisation_max: 50,
+                ..Default::default()
+            }),
+            tachometer: Some(TachometerConfig {
+                gpio_pin: Some(18),
+                register: Some(0x90),
+                pulses_per_revolution: 0,
+                ..Default::default()
+            }),
+            kick: Some(KickConfig {
+                fan_speed: FanSpeed(101),
+                ..Default::default()
+            }),
+            ..Default::default()
         };
-        assert_eq!(c.check(), Err(ConfigCheckError::InvalidTemperatureRange));
+        // All of them at once
+        assert_eq!(
+            issues(&c),
+            vec![
+                (
+                    "temperature_max".to_string(),
+                    FanSpeedMapError::InvalidTemperatureRange(1.into(), 0.into()).into()
+                ),
+                (
+                    "fan_speed_max".to_string(),
+                    FanSpeedMapError::InvalidFanSpeedRange(FanSpeed(10), FanSpeed(1)).into()
+                ),
+                (
+                    "adaptive_interval.max_interval_seconds".to_string(),
+                    ConfigCheckError::InvalidAdaptiveIntervalRange(
+                        UpdateIntervalSeconds(NonZeroU32::new(60).unwrap()),
+                        UpdateIntervalSeconds(NonZeroU32::new(10).unwrap())
+                    )
+                ),
+                (
+                    "feed_forward.utilisation_max".to_string(),
+                    ConfigCheckError::InvalidFeedForwardRange(90, 50)
+                ),
+                (
+                    "tachometer".to_string(),
+                    ConfigCheckError::InvalidTachometerSource
+                ),
+                (
+                    "tachometer.pulses_per_revolution".to_string(),
+                    ConfigCheckError::InvalidPulsesPerRevolution
+                ),
+                (
+                    "kick.fan_speed".to_string(),
+                    FanSpeedMapError::InvalidFanSpeed(FanSpeed(101)).into()
+                ),
+            ]
+        );
+
         let c = Config {
-            update_interval_seconds: UpdateIntervalSeconds(NonZeroU32::new(30).unwrap()),
-            temperature_min: 0.into(),
-            temperature_max: 1.into(),
-            fan_speed_min: FanSpeed::new(10).unwrap(),
-            fan_speed_max: FanSpeed::new(1).unwrap(),
+            fan_speed_max: FanSpeed(150),
+            ..Default::default()
+        };
+        assert_eq!(
+            issues(&c),
+            vec![(
+                "fan_speed_max".to_string(),
+                FanSpeedMapError::InvalidFanSpeed(FanSpeed(150)).into()
+            )]
+        );
+
+        let c = Config {
+            hysteresis: DegreesC::from_tenths(-15),
+            safety_temperature: Some(DegreesC::new(20)),
+            temperature_min: DegreesC::new(30),
+            ..Default::default()
+        };
+        assert_eq!(
+            issues(&c),
+            vec![
+                (
+                    "hysteresis".to_string(),
+                    ConfigCheckError::NegativeHysteresis(DegreesC::from_tenths(-15))
+                ),
+                (
+                    "safety_temperature".to_string(),
+                    ConfigCheckError::InvalidSafetyTemperature(
+                        DegreesC::new(20),
+                        DegreesC::new(30)
+                    )
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn report_locations() {
+        let source = r#"update_interval_seconds = 30
+temperature_min = 50
+temperature_max = 40
+fan_speed_min = 0
+fan_speed_max = 100
+
+[profiles.quiet]
+temperature_min = 40
+temperature_max = 70
+fan_speed_min = 50
+  fan_speed_max = 40
+
+[profiles.default]
+temperature_min = 40
+temperature_max = 70
+fan_speed_min = 0
+fan_speed_max = 40
+
+[[schedule]]
+profile = "quiet"
+start = "22:00"
+end = "07:00"
+
+[[schedule]]
+profile = "silent"
+start = "12:00"
+end = "13:00"
+"#;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, source).unwrap();
+        let report = match Config::load(&path) {
+            Err(ConfigLoadError::Check(_, report)) => report,
+            r => panic!("Unexpected {:?}", r),
         };
-        assert_eq!(c.check(), Err(ConfigCheckError::InvalidFanSpeedRange));
+        assert_eq!(
+            report.to_string(),
+            "  temperature_max: temperature_min (50 C) must be below temperature_max (40 C) (line 3, column 1)
+  profiles.default: profile name 'default' is reserved (line 13, column 1)
+  profiles.quiet.fan_speed_max: fan_speed_min (50%) must be below fan_speed_max (40%) (line 11, column 3)
+  schedule[1].profile: unknown profile 'silent' (line 25, column 1)"
+        );
+    }
+
+    #[test]
+    fn profiles_and_schedule() {
+        let c: Config = toml::from_str(
+            r#"
+            update_interval_seconds = 30
+            temperature_min = 33
+            temperature_max = 65
+            fan_speed_min = 0
+            fan_speed_max = 100
+            hysteresis = 2
+
+            [profiles.quiet]
+            temperature_min = 40
+            temperature_max = 70
+            fan_speed_min = 0
+            fan_speed_max = 40
+            safety_temperature = 75
+
+            [[schedule]]
+            profile = "quiet"
+            start = "22:00"
+            end = "07:00"
+            days = ["mon", "tue", "wed", "thu", "fri"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(c.check(), Ok(()));
+        assert_eq!(c.default_profile().hysteresis, DegreesC::new(2));
+        let quiet = c.profile("quiet").unwrap();
+        assert_eq!(quiet.hysteresis, DegreesC::new(0));
+        assert_eq!(quiet.safety_temperature, Some(DegreesC::new(75)));
+        assert_eq!(c.schedule[0].days.len(), 5);
+        assert_eq!(c.profile_schedule().get("quiet"), Some(&quiet));
+
+        let mut bad = c.clone();
+        bad.schedule[0].profile = "silent".to_string();
+        assert_eq!(
+            issues(&bad),
+            vec![(
+                "schedule[0].profile".to_string(),
+                ConfigCheckError::UnknownProfile("silent".to_string())
+            )]
+        );
+        let mut bad = c;
+        bad.profiles.get_mut("quiet").unwrap().fan_speed_max = FanSpeed::MIN;
+        bad.profiles.insert(DEFAULT_PROFILE.to_string(), quiet);
+        assert_eq!(
+            issues(&bad),
+            vec![
+                (
+                    "profiles.default".to_string(),
+                    ConfigCheckError::ReservedProfileName(DEFAULT_PROFILE.to_string())
+                ),
+                (
+                    "profiles.quiet.fan_speed_max".to_string(),
+                    FanSpeedMapError::InvalidFanSpeedRange(FanSpeed::MIN, FanSpeed::MIN).into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn fan_outputs() {
+        let c: Config = toml::from_str(
+            r#"
+            update_interval_seconds = 30
+            temperature_min = 33
+            temperature_max = 65
+            fan_speed_min = 0
+            fan_speed_max = 100
+
+            [profiles.nvme]
+            temperature_min = 40
+            temperature_max = 60
+            fan_speed_min = 20
+            fan_speed_max = 100
+
+            [fans.hat]
+            i2c_bus = 3
+            i2c_address = 0x1b
+
+            [fans.nvme]
+            gpio_pin = 18
+            sensor = "/sys/class/hwmon/hwmon1/temp1_input"
+            profile = "nvme"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(c.check(), Ok(()));
+        assert_eq!(
+            c.fans["hat"],
+            FanOutputConfig {
+                i2c_bus: Some(3),
+                i2c_address: Some(0x1b),
+                ..Default::default()
+            }
+        );
+        let nvme = &c.fans["nvme"];
+        assert_eq!(nvme.pwm_frequency_hz, 1000);
+        assert_eq!(nvme.profile.as_deref(), Some("nvme"));
+
+        let mut bad = c;
+        bad.fans
+            .insert(MAIN_FAN.to_string(), bad.fans["hat"].clone());
+        bad.fans.get_mut("hat").unwrap().gpio_pin = Some(17);
+        bad.fans.insert(
+            "hat 2".to_string(),
+            FanOutputConfig {
+                gpio_pin: Some(12),
+                profile: Some("turbo".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            issues(&bad),
+            vec![
+                (
+                    "fans.case".to_string(),
+                    ConfigCheckError::ReservedFanName(MAIN_FAN.to_string())
+                ),
+                ("fans.hat".to_string(), ConfigCheckError::InvalidFanDriver),
+                (
+                    "fans.hat 2".to_string(),
+                    ConfigCheckError::InvalidFanName("hat 2".to_string())
+                ),
+                (
+                    "fans.hat 2.profile".to_string(),
+                    ConfigCheckError::UnknownProfile("turbo".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn migrate_golden_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let v0 = include_str!("../testdata/config/v0.toml");
+        fs::write(&path, v0).unwrap();
+        let (config, version) = Config::load_versioned(&path).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(config, Config::default());
+
+        let backup = Config::migrate_file(&path).unwrap().unwrap();
+        assert_eq!(backup, dir.path().join("config.toml.v0.bak"));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), v0);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            include_str!("../testdata/config/v0-migrated.toml")
+        );
+        assert_eq!(Config::migrate_file(&path).unwrap(), None);
+        assert_eq!(
+            Config::load_versioned(&path).unwrap(),
+            (config, CONFIG_VERSION)
+        );
+
+        fs::write(&path, "version = 2\nupdate_interval_seconds = 30\n").unwrap();
+        assert!(matches!(
+            Config::load(&path),
+            Err(ConfigLoadError::Migration(
+                _,
+                MigrationError::UnsupportedVersion(2)
+            ))
+        ));
+    }
+
+    #[test]
+    fn mqtt_defaults() {
+        let c: Config = toml::from_str(
+            r#"
+            update_interval_seconds = 30
+            temperature_min = 33
+            temperature_max = 65
+            fan_speed_min = 0
+            fan_speed_max = 100
+
+            [mqtt]
+            host = "broker.local"
+            client_id = "pi-1"
+            "#,
+        )
+        .unwrap();
+        let mqtt = c.mqtt.unwrap();
+        assert_eq!(mqtt.port, MQTT_PORT);
+        assert_eq!(mqtt.discovery_prefix, HA_DISCOVERY_PREFIX);
+        assert_eq!(mqtt.base_topic(), "argonone/pi-1");
+        assert_eq!(mqtt.username, None);
+        assert_eq!(mqtt.override_timeout(), None);
+        assert_eq!(c.missed_ticks, MissedTicks::Skip);
+    }
+
+    #[test]
+    fn decimal_thresholds() {
+        let c: Config = toml::from_str(
+            r#"
+            update_interval_seconds = 30
+            temperature_min = 42.5
+            temperature_max = 65
+            fan_speed_min = 0
+            fan_speed_max = 100
+            hysteresis = 1.5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(c.temperature_min, DegreesC::from_tenths(425));
+        assert_eq!(c.temperature_max, DegreesC::new(65));
+        assert_eq!(c.hysteresis, DegreesC::from_tenths(15));
+
+        // Whole degrees are written back as integers
+        let s = toml::to_string(&c).unwrap();
+        assert!(s.contains("temperature_min = 42.5\n"));
+        assert!(s.contains("temperature_max = 65\n"));
+        assert_eq!(toml::from_str::<Config>(&s).unwrap(), c);
     }
 }
@@ -0,0 +1,411 @@
+use crate::{DegreesC, FanSpeed, TachReading, Wakeup};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+use std::{fs, mem, ptr, thread};
+
+pub const CONTROL_SOCKET_PATH: &str = "/run/argon-fan-ctl/control.sock";
+
+/// Switches to the next profile
+pub const SIGNAL_NEXT_PROFILE: libc::c_int = libc::SIGUSR1;
+/// Returns to the scheduled profile
+pub const SIGNAL_AUTO_PROFILE: libc::c_int = libc::SIGUSR2;
+
+#[derive(Debug, err_derive::Error)]
+pub enum ControlSocketError {
+    #[error(display = "Control socket {:?} error, {}", _0, _1)]
+    Io(PathBuf, io::Error),
+
+    #[error(
+        display = "Control socket {:?} is in use, is the daemon already running?",
+        _0
+    )]
+    InUse(PathBuf),
+
+    #[error(display = "Failed to set up the profile signals, {}", _0)]
+    Signal(io::Error),
+
+    #[error(display = "Invalid control command '{}'", _0)]
+    InvalidCommand(String),
+}
+
+/// A named profile, or "auto" for the scheduled one
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum ProfileSelection {
+    Auto,
+    Named(String),
+}
+
+impl FromStr for ProfileSelection {
+    type Err = ControlSocketError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err(ControlSocketError::InvalidCommand(s.to_string())),
+            "auto" => Ok(ProfileSelection::Auto),
+            name => Ok(ProfileSelection::Named(name.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for ProfileSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileSelection::Auto => f.write_str("auto"),
+            ProfileSelection::Named(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Control socket commands, one per line: "status", "report", "profile <name|auto>"
+/// or "next-profile"
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum ControlCommand {
+    Status,
+    /// Replies with a `DaemonReport` as JSON
+    Report,
+    SetProfile(ProfileSelection),
+    NextProfile,
+}
+
+/// What the daemon is doing, the reply to the "report" command
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct DaemonReport {
+    /// Profile in use, if there are any
+    pub profile: Option<String>,
+    /// True if the profile was selected at runtime, rather than by the schedule
+    pub selected: bool,
+    /// Temperature at the last update
+    pub temperature: Option<DegreesC>,
+    /// Fan speed commanded at the last update
+    pub fan_speed: Option<FanSpeed>,
+    pub overridden: bool,
+    /// Fan speed read back at the last update, when there's a tachometer
+    #[serde(default)]
+    pub tach: Option<TachReading>,
+    /// Fans besides the main one, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fans: BTreeMap<String, FanReport>,
+}
+
+/// The last update of a fan besides the main one
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct FanReport {
+    pub temperature: Option<DegreesC>,
+    pub fan_speed: Option<FanSpeed>,
+    pub overridden: bool,
+}
+
+impl FromStr for ControlCommand {
+    type Err = ControlSocketError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let mut words = s.splitn(2, ' ');
+        match (words.next(), words.next()) {
+            (Some("status"), None) => Ok(ControlCommand::Status),
+            (Some("report"), None) => Ok(ControlCommand::Report),
+            (Some("next-profile"), None) => Ok(ControlCommand::NextProfile),
+            (Some("profile"), Some(p)) => Ok(ControlCommand::SetProfile(p.parse()?)),
+            _ => Err(ControlSocketError::InvalidCommand(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlCommand::Status => f.write_str("status"),
+            ControlCommand::Report => f.write_str("report"),
+            ControlCommand::SetProfile(p) => write!(f, "profile {}", p),
+            ControlCommand::NextProfile => f.write_str("next-profile"),
+        }
+    }
+}
+
+/// A command waiting for the daemon, along with where to send the reply
+#[derive(Debug)]
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    reply: Option<Sender<String>>,
+}
+
+impl ControlRequest {
+    pub fn respond(self, reply: String) {
+        if let Some(tx) = self.reply {
+            let _ = tx.send(reply);
+        }
+    }
+}
+
+/// Receives commands from the control socket and profile signals, each one
+/// notifies the wakeup
+#[derive(Debug)]
+pub struct ControlServer {
+    tx: Sender<ControlRequest>,
+    rx: Receiver<ControlRequest>,
+    wakeup: Wakeup,
+}
+
+impl ControlServer {
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub fn new(wakeup: Wakeup) -> Self {
+        let (tx, rx) = mpsc::channel();
+        ControlServer { tx, rx, wakeup }
+    }
+
+    /// Listens on a Unix socket, replacing a stale socket file. Fails if something
+    /// answers on it.
+    pub fn listen<P: AsRef<Path>>(&self, path: P) -> Result<(), ControlSocketError> {
+        let path = path.as_ref().to_path_buf();
+        let err = |e| ControlSocketError::Io(path.clone(), e);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(err)?;
+        }
+        match UnixStream::connect(&path) {
+            Ok(_) => return Err(ControlSocketError::InUse(path)),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                debug!("Replacing stale control socket {:?}", path);
+                fs::remove_file(&path).map_err(err)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(err(e)),
+        }
+        let listener = UnixListener::bind(&path).map_err(err)?;
+        let tx = self.tx.clone();
+        let wakeup = self.wakeup.clone();
+        thread::Builder::new()
+            .name("control".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(s) => {
+                            if let Err(e) = Self::serve(s, &tx, &wakeup) {
+                                debug!("Control connection error, {}", e);
+                            }
+                        }
+                        Err(e) => warn!("Control socket error, {}", e),
+                    }
+                }
+            })
+            .map_err(err)?;
+        Ok(())
+    }
+
+    /// Handles SIGUSR1 (next profile) and SIGUSR2 (scheduled profile), the signals
+    /// must have been blocked with `block_profile_signals`
+    pub fn handle_signals(&self, signals: ProfileSignals) -> Result<(), ControlSocketError> {
+        let tx = self.tx.clone();
+        let wakeup = self.wakeup.clone();
+        thread::Builder::new()
+            .name("signals".to_string())
+            .spawn(move || loop {
+                let command = match signals.wait() {
+                    Ok(SIGNAL_NEXT_PROFILE) => ControlCommand::NextProfile,
+                    Ok(_) => ControlCommand::SetProfile(ProfileSelection::Auto),
+                    Err(e) => {
+                        warn!("Failed to wait for signals, {}", e);
+                        break;
+                    }
+                };
+                if tx
+                    .send(ControlRequest {
+                        command,
+                        reply: None,
+                    })
+                    .is_err()
+                {
+                    break;
+                }
+                wakeup.notify();
+            })
+            .map_err(ControlSocketError::Signal)?;
+        Ok(())
+    }
+
+    /// Queues a command as if it came from the socket, returns the reply receiver
+    pub fn submit(&self, command: ControlCommand) -> Receiver<String> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        let _ = self.tx.send(ControlRequest {
+            command,
+            reply: Some(reply_tx),
+        });
+        self.wakeup.notify();
+        reply_rx
+    }
+
+    pub fn try_recv(&self) -> Option<ControlRequest> {
+        self.rx.try_recv().ok()
+    }
+
+    fn serve(stream: UnixStream, tx: &Sender<ControlRequest>, wakeup: &Wakeup) -> io::Result<()> {
+        stream.set_read_timeout(Some(Self::REPLY_TIMEOUT))?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        let reply = match line.parse::<ControlCommand>() {
+            Err(e) => format!("error {}", e),
+            Ok(command) => {
+                let (reply_tx, reply_rx) = mpsc::channel();
+                let _ = tx.send(ControlRequest {
+                    command,
+                    reply: Some(reply_tx),
+                });
+                wakeup.notify();
+                reply_rx
+                    .recv_timeout(Self::REPLY_TIMEOUT)
+                    .unwrap_or_else(|_| "error no reply".to_string())
+            }
+        };
+        writeln!(&stream, "{}", reply)
+    }
+}
+
+/// Sends a command to the daemon's control socket, returns the reply
+pub fn send_command<P: AsRef<Path>>(
+    path: P,
+    command: &ControlCommand,
+) -> Result<String, ControlSocketError> {
+    let path = path.as_ref();
+    let err = |e| ControlSocketError::Io(path.to_path_buf(), e);
+    let stream = UnixStream::connect(path).map_err(err)?;
+    stream
+        .set_read_timeout(Some(ControlServer::REPLY_TIMEOUT * 2))
+        .map_err(err)?;
+    writeln!(&stream, "{}", command).map_err(err)?;
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply).map_err(err)?;
+    Ok(reply.trim_end().to_string())
+}
+
+/// The blocked profile signal set, waited on by a dedicated thread
+#[derive(Copy, Clone)]
+pub struct ProfileSignals(libc::sigset_t);
+
+impl fmt::Debug for ProfileSignals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProfileSignals")
+    }
+}
+
+impl ProfileSignals {
+    fn wait(&self) -> io::Result<libc::c_int> {
+        let mut sig = 0;
+        // Safety: the set was initialized by block_profile_signals
+        match unsafe { libc::sigwait(&self.0, &mut sig) } {
+            0 => Ok(sig),
+            e => Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+/// Blocks the profile signals in the calling thread and the threads it spawns
+/// afterwards, call before any threads are spawned
+pub fn block_profile_signals() -> Result<ProfileSignals, ControlSocketError> {
+    // Safety: plain libc signal set manipulation on a zeroed, then emptied set
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, SIGNAL_NEXT_PROFILE);
+        libc::sigaddset(&mut set, SIGNAL_AUTO_PROFILE);
+        match libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) {
+            0 => Ok(ProfileSignals(set)),
+            e => Err(ControlSocketError::Signal(io::Error::from_raw_os_error(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        for (s, c) in [
+            ("status\n", ControlCommand::Status),
+            ("next-profile", ControlCommand::NextProfile),
+            ("report", ControlCommand::Report),
+            (
+                "profile auto",
+                ControlCommand::SetProfile(ProfileSelection::Auto),
+            ),
+            (
+                "profile silent\n",
+                ControlCommand::SetProfile(ProfileSelection::Named("silent".to_string())),
+            ),
+        ]
+        .iter()
+        {
+            assert_eq!(&s.parse::<ControlCommand>().unwrap(), c);
+            assert_eq!(c.to_string().parse::<ControlCommand>().unwrap(), *c);
+        }
+        assert!("profile".parse::<ControlCommand>().is_err());
+        assert!("reboot".parse::<ControlCommand>().is_err());
+    }
+
+    #[test]
+    fn socket_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run/control.sock");
+        let wakeup = Wakeup::new();
+        let server = ControlServer::new(wakeup.clone());
+        server.listen(&path).unwrap();
+
+        let client = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let cmd = ControlCommand::SetProfile(ProfileSelection::Named("silent".into()));
+                send_command(&path, &cmd).unwrap()
+            })
+        };
+        let req = loop {
+            if let Some(r) = server.try_recv() {
+                break r;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(
+            req.command,
+            ControlCommand::SetProfile(ProfileSelection::Named("silent".into()))
+        );
+        req.respond("ok profile silent".to_string());
+        assert_eq!(client.join().unwrap(), "ok profile silent");
+
+        let stream = UnixStream::connect(&path).unwrap();
+        writeln!(&stream, "reboot").unwrap();
+        let mut reply = String::new();
+        BufReader::new(&stream).read_line(&mut reply).unwrap();
+        assert_eq!(reply, "error Invalid control command 'reboot'\n");
+        assert!(server.try_recv().is_none());
+
+        // A second server doesn't take over the socket
+        assert!(matches!(
+            ControlServer::new(wakeup.clone()).listen(&path),
+            Err(ControlSocketError::InUse(_))
+        ));
+        let cmd = ControlCommand::Report;
+        let client = thread::spawn(move || send_command(&path, &cmd).unwrap());
+        let req = loop {
+            if let Some(r) = server.try_recv() {
+                break r;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        req.respond("ok {}".to_string());
+        assert_eq!(client.join().unwrap(), "ok {}");
+
+        // A stale socket file is replaced
+        let stale = dir.path().join("stale.sock");
+        drop(UnixListener::bind(&stale).unwrap());
+        assert!(stale.exists());
+        ControlServer::new(wakeup).listen(&stale).unwrap();
+        UnixStream::connect(&stale).unwrap();
+    }
+}
@@ -0,0 +1,413 @@
+use crate::{
+    DegreesC, FanOverride, FanSpeed, FanSpeedMap, FanSpeedMapError, Mailbox, MailboxError, Profile,
+    TachReading,
+};
+use chrono::prelude::*;
+use log::{debug, info};
+use std::error::Error;
+use std::time::Instant;
+
+pub trait TemperatureSource {
+    type Error: Error + 'static;
+
+    /// Returns the temperature in degrees C
+    fn temperature(&mut self) -> Result<f32, Self::Error>;
+}
+
+pub trait Fan {
+    type Error: Error + 'static;
+
+    fn set_speed(&mut self, speed: FanSpeed) -> Result<(), Self::Error>;
+}
+
+impl TemperatureSource for Mailbox {
+    type Error = MailboxError;
+
+    fn temperature(&mut self) -> Result<f32, Self::Error> {
+        Mailbox::temperature(self)
+    }
+}
+
+#[derive(Debug, err_derive::Error)]
+pub enum ControlError {
+    #[error(display = "Failed to read the temperature, {}", _0)]
+    Temperature(Box<dyn Error>),
+
+    #[error(display = "Failed to set the fan speed, {}", _0)]
+    Fan(Box<dyn Error>),
+}
+
+/// Outcome of a single control loop iteration
+#[derive(Debug)]
+pub struct Tick {
+    pub timestamp: DateTime<Utc>,
+    /// Temperature as read from the sensor
+    pub raw_temperature: Option<f32>,
+    /// Temperature used to compute the fan speed
+    pub temperature: Option<DegreesC>,
+    /// Computed (or overridden) fan speed
+    pub fan_speed: Option<FanSpeed>,
+    /// True if the fan speed was overridden
+    pub overridden: bool,
+    /// Feed-forward boost included in the fan speed
+    pub boost: FanSpeed,
+    /// True if the fan speed was written to the fan controller
+    pub written: bool,
+    /// Fan speed read back, when there's a tachometer
+    pub tach: Option<TachReading>,
+    pub error: Option<ControlError>,
+}
+
+pub struct Controller<T, F> {
+    sensor: T,
+    fan: F,
+    map: FanSpeedMap,
+    hysteresis: DegreesC,
+    safety_temperature: Option<DegreesC>,
+    fan_override: Option<FanOverride>,
+    /// Last fan speed computed from the map, for hysteresis
+    auto_speed: Option<FanSpeed>,
+    /// Added to the fan speed computed from the map
+    boost: FanSpeed,
+}
+
+impl<T: TemperatureSource, F: Fan> Controller<T, F> {
+    pub fn new(sensor: T, fan: F, map: FanSpeedMap) -> Self {
+        Controller {
+            sensor,
+            fan,
+            map,
+            hysteresis: DegreesC::new(0),
+            safety_temperature: None,
+            fan_override: None,
+            auto_speed: None,
+            boost: FanSpeed::MIN,
+        }
+    }
+
+    /// Switches to the profile's fan speed map, hysteresis and safety temperature
+    pub fn set_profile(&mut self, profile: &Profile) -> Result<(), FanSpeedMapError> {
+        self.map = profile.fan_speed_map()?;
+        self.hysteresis = profile.hysteresis;
+        self.safety_temperature = profile.safety_temperature;
+        Ok(())
+    }
+
+    pub fn fan_override(&self) -> Option<FanOverride> {
+        self.fan_override
+    }
+
+    pub fn set_override(&mut self, fan_override: Option<FanOverride>) {
+        self.fan_override = fan_override;
+    }
+
+    /// Clears the override if it expired, returns true if it did
+    pub fn expire_override(&mut self, now: Instant) -> bool {
+        if self
+            .fan_override
+            .map(|o| o.is_expired(now))
+            .unwrap_or(false)
+        {
+            info!("Fan speed override expired");
+            self.fan_override = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Sets the feed-forward boost, applied from the next tick unless overridden
+    pub fn set_boost(&mut self, boost: FanSpeed) {
+        self.boost = boost;
+    }
+
+    /// Writes a fan speed directly, bypassing the fan speed map
+    pub fn set_speed(&mut self, speed: FanSpeed) -> Result<(), ControlError> {
+        self.fan
+            .set_speed(speed)
+            .map_err(|e| ControlError::Fan(Box::new(e)))
+    }
+
+    /// Reads the temperature, then computes and writes the fan speed
+    pub fn tick(&mut self, timestamp: DateTime<Utc>) -> Tick {
+        let mut tick = Tick {
+            timestamp,
+            raw_temperature: None,
+            temperature: None,
+            fan_speed: None,
+            overridden: self.fan_override.is_some(),
+            boost: FanSpeed::MIN,
+            written: false,
+            tach: None,
+            error: None,
+        };
+
+        let raw_temp = match self.sensor.temperature() {
+            Ok(t) => t,
+            Err(e) => {
+                tick.error = Some(ControlError::Temperature(Box::new(e)));
+                return tick;
+            }
+        };
+        let temp_c = DegreesC::from_f32(raw_temp);
+        let unsafe_temp = self
+            .safety_temperature
+            .map(|t| temp_c >= t)
+            .unwrap_or(false);
+        let fan_speed = match self.fan_override {
+            // Full speed at the safety temperature, overridden or not
+            _ if unsafe_temp => FanSpeed::MAX,
+            Some(o) => o.speed(),
+            None => {
+                let speed = self.auto_speed(temp_c);
+                let boosted = FanSpeed::new_unchecked(
+                    speed.0.saturating_add(self.boost.0).min(FanSpeed::MAX.0),
+                );
+                tick.boost = FanSpeed::new_unchecked(boosted.0 - speed.0);
+                boosted
+            }
+        };
+        tick.raw_temperature = Some(raw_temp);
+        tick.temperature = Some(temp_c);
+        tick.fan_speed = Some(fan_speed);
+
+        match self.set_speed(fan_speed) {
+            Ok(()) => tick.written = true,
+            Err(e) => tick.error = Some(e),
+        }
+        debug!("Temp {}, fan speed {}", temp_c, fan_speed);
+        tick
+    }
+
+    /// Only slows down once the temperature dropped by the hysteresis
+    fn auto_speed(&mut self, temp_c: DegreesC) -> FanSpeed {
+        let mut speed = self.map.get(temp_c);
+        if let Some(prev) = self.auto_speed {
+            if speed < prev {
+                let lagged = self.map.get(temp_c.saturating_add(self.hysteresis));
+                speed = prev.min(lagged);
+            }
+        }
+        self.auto_speed = Some(speed);
+        speed
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub(crate) struct FakeError(pub &'static str);
+
+    impl fmt::Display for FakeError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    impl Error for FakeError {}
+
+    /// Returns the queued readings, then repeats the last one
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct FakeSensor {
+        pub readings: VecDeque<Result<f32, FakeError>>,
+        pub last: Option<Result<f32, FakeError>>,
+    }
+
+    impl FakeSensor {
+        pub(crate) fn new(readings: &[Result<f32, FakeError>]) -> Self {
+            FakeSensor {
+                readings: readings.iter().cloned().collect(),
+                last: None,
+            }
+        }
+    }
+
+    impl TemperatureSource for FakeSensor {
+        type Error = FakeError;
+
+        fn temperature(&mut self) -> Result<f32, Self::Error> {
+            if let Some(r) = self.readings.pop_front() {
+                self.last = Some(r);
+            }
+            self.last.clone().expect("No readings")
+        }
+    }
+
+    /// Records the written speeds, shared so tests can inspect them
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct FakeFan {
+        pub speeds: Arc<Mutex<Vec<FanSpeed>>>,
+        pub fail: Arc<Mutex<bool>>,
+    }
+
+    impl FakeFan {
+        pub(crate) fn speeds(&self) -> Vec<FanSpeed> {
+            self.speeds.lock().unwrap().clone()
+        }
+    }
+
+    impl Fan for FakeFan {
+        type Error = FakeError;
+
+        fn set_speed(&mut self, speed: FanSpeed) -> Result<(), Self::Error> {
+            if *self.fail.lock().unwrap() {
+                Err(FakeError("I2C write failed"))
+            } else {
+                self.speeds.lock().unwrap().push(speed);
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn map() -> FanSpeedMap {
+        FanSpeedMap::new(
+            DegreesC::new(30),
+            DegreesC::new(70),
+            FanSpeed::MIN,
+            FanSpeed::MAX,
+        )
+        .unwrap()
+    }
+
+    /// The profile of `map`, without hysteresis or a safety temperature
+    pub(crate) fn profile() -> Profile {
+        Profile {
+            temperature_min: DegreesC::new(30),
+            temperature_max: DegreesC::new(70),
+            fan_speed_min: FanSpeed::MIN,
+            fan_speed_max: FanSpeed::MAX,
+            hysteresis: DegreesC::new(0),
+            safety_temperature: None,
+        }
+    }
+
+    #[test]
+    fn tick_maps_temperature() {
+        let fan = FakeFan::default();
+        let sensor = FakeSensor::new(&[Ok(20.5), Ok(50.9), Ok(80.0)]);
+        let mut c = Controller::new(sensor, fan.clone(), map());
+        let t = c.tick(Utc::now());
+        assert_eq!(t.raw_temperature, Some(20.5));
+        assert_eq!(t.temperature, Some(DegreesC::from_tenths(205)));
+        assert_eq!(t.fan_speed, Some(FanSpeed::MIN));
+        assert!(t.written);
+        assert!(!t.overridden);
+        assert!(t.error.is_none());
+        c.tick(Utc::now());
+        c.tick(Utc::now());
+        assert_eq!(
+            fan.speeds(),
+            vec![FanSpeed::MIN, FanSpeed::new(52).unwrap(), FanSpeed::MAX]
+        );
+    }
+
+    #[test]
+    fn tick_errors() {
+        let fan = FakeFan::default();
+        let sensor = FakeSensor::new(&[Err(FakeError("mailbox")), Ok(40.0)]);
+        let mut c = Controller::new(sensor, fan.clone(), map());
+        let t = c.tick(Utc::now());
+        assert_eq!(t.raw_temperature, None);
+        assert!(!t.written);
+        assert!(matches!(t.error, Some(ControlError::Temperature(_))));
+
+        *fan.fail.lock().unwrap() = true;
+        let t = c.tick(Utc::now());
+        assert_eq!(t.fan_speed, Some(FanSpeed::new(25).unwrap()));
+        assert!(!t.written);
+        assert_eq!(
+            t.error.unwrap().to_string(),
+            "Failed to set the fan speed, I2C write failed"
+        );
+    }
+
+    #[test]
+    fn profile_hysteresis_and_safety() {
+        let fan = FakeFan::default();
+        let temps = [50.0, 60.0, 57.0, 55.0, 50.0, 75.0, 80.0, 60.0];
+        let sensor = FakeSensor::new(&temps.iter().map(|t| Ok(*t)).collect::<Vec<_>>());
+        let mut c = Controller::new(sensor, fan.clone(), map());
+        c.set_profile(&Profile {
+            temperature_min: DegreesC::new(30),
+            temperature_max: DegreesC::new(70),
+            fan_speed_min: FanSpeed::MIN,
+            fan_speed_max: FanSpeed::new(60).unwrap(),
+            hysteresis: DegreesC::new(4),
+            safety_temperature: Some(DegreesC::new(80)),
+        })
+        .unwrap();
+        for _ in temps.iter() {
+            c.tick(Utc::now());
+        }
+        let speeds: Vec<u8> = fan.speeds().into_iter().map(u8::from).collect();
+        // 57 C holds the 60 C speed, 55 C gets the 59 C speed, 75 C is capped,
+        // 80 C is the safety temperature
+        assert_eq!(speeds, vec![30, 45, 45, 43, 36, 60, 100, 51]);
+    }
+
+    #[test]
+    fn feed_forward_boost() {
+        let fan = FakeFan::default();
+        let sensor = FakeSensor::new(&[Ok(50.0), Ok(50.0), Ok(65.0), Ok(65.0)]);
+        let mut c = Controller::new(sensor, fan.clone(), map());
+        c.set_boost(FanSpeed::new(30).unwrap());
+        let t = c.tick(Utc::now());
+        assert_eq!(t.fan_speed, Some(FanSpeed::new(80).unwrap()));
+        assert_eq!(t.boost, FanSpeed::new(30).unwrap());
+        c.set_boost(FanSpeed::MIN);
+        c.tick(Utc::now());
+        // Capped at 100%
+        c.set_boost(FanSpeed::new(30).unwrap());
+        let t = c.tick(Utc::now());
+        assert_eq!(t.boost, FanSpeed::new(13).unwrap());
+        c.set_override(Some(FanOverride::new(FanSpeed::MIN, Instant::now(), None)));
+        let t = c.tick(Utc::now());
+        assert_eq!(t.boost, FanSpeed::MIN);
+        let speeds: Vec<u8> = fan.speeds().into_iter().map(u8::from).collect();
+        assert_eq!(speeds, vec![80, 50, 100, 0]);
+    }
+
+    #[test]
+    fn override_takes_precedence() {
+        let fan = FakeFan::default();
+        let mut c = Controller::new(FakeSensor::new(&[Ok(80.0)]), fan.clone(), map());
+        let now = Instant::now();
+        let dur = Duration::from_secs(10);
+        c.set_override(Some(FanOverride::new(FanSpeed::MIN, now, Some(dur))));
+        let t = c.tick(Utc::now());
+        assert!(t.overridden);
+        assert_eq!(t.fan_speed, Some(FanSpeed::MIN));
+        assert!(!c.expire_override(now));
+        assert!(c.expire_override(now + dur));
+        assert_eq!(c.fan_override(), None);
+        let t = c.tick(Utc::now());
+        assert!(!t.overridden);
+        assert_eq!(t.fan_speed, Some(FanSpeed::MAX));
+    }
+
+    #[test]
+    fn safety_beats_override() {
+        let fan = FakeFan::default();
+        let sensor = FakeSensor::new(&[Ok(79.0), Ok(85.0), Ok(60.0)]);
+        let mut c = Controller::new(sensor, fan.clone(), map());
+        c.set_profile(&Profile {
+            safety_temperature: Some(DegreesC::new(80)),
+            ..profile()
+        })
+        .unwrap();
+        c.set_override(Some(FanOverride::new(FanSpeed::MIN, Instant::now(), None)));
+        for _ in 0..3 {
+            assert!(c.tick(Utc::now()).overridden);
+        }
+        assert_eq!(
+            fan.speeds(),
+            vec![FanSpeed::MIN, FanSpeed::MAX, FanSpeed::MIN]
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use crate::{DegreesC, FanSpeed, FanSpeedMap, TemperatureUnit, UnitTemperature};
+use serde::Serialize;
+use std::fmt::{self, Write};
+
+/// A temperature on the curve, in the report's unit, and its fan speed percentage
+#[derive(Copy, Clone, PartialEq, Debug, Serialize)]
+pub struct CurvePoint {
+    pub temperature: UnitTemperature,
+    pub fan_speed: FanSpeed,
+}
+
+/// The fan speed every `step` whole degrees in `unit`, shown as a table
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct CurveReport {
+    pub unit: TemperatureUnit,
+    pub points: Vec<CurvePoint>,
+}
+
+impl CurveReport {
+    pub fn new(
+        map: &FanSpeedMap,
+        from: DegreesC,
+        to: DegreesC,
+        step: u8,
+        unit: TemperatureUnit,
+    ) -> Self {
+        let (from, to) = ordered(from, to);
+        let points = temperatures(from, to, step, unit)
+            .map(|(temperature, t)| CurvePoint {
+                temperature,
+                fan_speed: map.get(t),
+            })
+            .collect();
+        CurveReport { unit, points }
+    }
+}
+
+impl fmt::Display for CurveReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:>8}  {:>9}", "Temp", "Fan speed")?;
+        for p in self.points.iter() {
+            writeln!(
+                f,
+                "{:>8}  {:>9}",
+                p.temperature.to_string(),
+                p.fan_speed.to_string()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Temperature to fan speed table, one row per step of whole degrees in `unit`
+pub fn curve_table(
+    map: &FanSpeedMap,
+    from: DegreesC,
+    to: DegreesC,
+    step: u8,
+    unit: TemperatureUnit,
+) -> String {
+    CurveReport::new(map, from, to, step, unit).to_string()
+}
+
+/// ASCII plot of the fan speed over a temperature range, one column per degree in
+/// `unit` (sampled down to at most `max_width` columns) and one row per 10%
+pub fn curve_plot(
+    map: &FanSpeedMap,
+    from: DegreesC,
+    to: DegreesC,
+    max_width: usize,
+    unit: TemperatureUnit,
+) -> String {
+    plot(map, from, to, max_width, unit, None)
+}
+
+/// Like `curve_plot`, with an operating point, e.g. the current temperature and fan
+/// speed, marked 'O' in the nearest column
+pub fn curve_plot_at(
+    map: &FanSpeedMap,
+    from: DegreesC,
+    to: DegreesC,
+    max_width: usize,
+    unit: TemperatureUnit,
+    at: (DegreesC, FanSpeed),
+) -> String {
+    plot(map, from, to, max_width, unit, Some(at))
+}
+
+fn plot(
+    map: &FanSpeedMap,
+    from: DegreesC,
+    to: DegreesC,
+    max_width: usize,
+    unit: TemperatureUnit,
+    at: Option<(DegreesC, FanSpeed)>,
+) -> String {
+    let (from, to) = ordered(from, to);
+    let span = (unit.from_celsius(to) - unit.from_celsius(from))
+        .max(0.0)
+        .round() as usize
+        + 1;
+    let step = ((span + max_width - 1) / max_width.max(1)).max(1);
+    let columns: Vec<(UnitTemperature, FanSpeed)> = temperatures(from, to, step as u8, unit)
+        .map(|(label, t)| (label, map.get(t)))
+        .collect();
+    let mark = at.map(|(t, s)| {
+        let offset = (unit.from_celsius(t) - unit.from_celsius(from)) / step as f64;
+        let column = (offset.round().max(0.0) as usize).min(columns.len().saturating_sub(1));
+        (column, row_of(s))
+    });
+
+    let mut out = String::new();
+    for row in (0..=10).rev() {
+        let pct = row * 10;
+        let line: String = columns
+            .iter()
+            .enumerate()
+            .map(|(i, (_, s))| {
+                if mark == Some((i, row)) {
+                    'O'
+                } else if row_of(*s) == row {
+                    '*'
+                } else {
+                    ' '
+                }
+            })
+            .collect();
+        writeln!(out, "{:>4}% |{}", pct, line.trim_end()).unwrap();
+    }
+    writeln!(out, "      +{}", "-".repeat(columns.len())).unwrap();
+    let from_label = from.in_unit(unit).to_string();
+    let to_label = columns
+        .last()
+        .map(|(label, _)| label.to_string())
+        .unwrap_or_default();
+    let pad = columns
+        .len()
+        .saturating_sub(from_label.len() + to_label.len())
+        .max(1);
+    writeln!(out, "       {}{}{}", from_label, " ".repeat(pad), to_label).unwrap();
+    out
+}
+
+/// The range low end first, so a reversed range shows the same curve
+fn ordered(from: DegreesC, to: DegreesC) -> (DegreesC, DegreesC) {
+    if from > to {
+        (to, from)
+    } else {
+        (from, to)
+    }
+}
+
+/// The nearest row, one per 10%
+fn row_of(s: FanSpeed) -> u8 {
+    (u8::from(s) + 5) / 10
+}
+
+/// Every `step` whole degrees in `unit` from `from`, along with their label
+fn temperatures(
+    from: DegreesC,
+    to: DegreesC,
+    step: u8,
+    unit: TemperatureUnit,
+) -> impl Iterator<Item = (UnitTemperature, DegreesC)> {
+    let from = unit.from_celsius(from);
+    let to = unit.from_celsius(to);
+    let step = f64::from(step.max(1));
+    (0..)
+        .map(move |i| from + f64::from(i) * step)
+        .take_while(move |t| *t <= to + 0.01)
+        .map(move |value| (UnitTemperature { value, unit }, unit.to_celsius(value)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use TemperatureUnit::*;
+
+    fn map() -> FanSpeedMap {
+        FanSpeedMap::new(
+            DegreesC::new(40),
+            DegreesC::new(60),
+            FanSpeed::MIN,
+            FanSpeed::MAX,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn table() {
+        assert_eq!(
+            curve_table(&map(), DegreesC::new(30), DegreesC::new(70), 10, Celsius),
+            "    Temp  Fan speed\n\
+             \x20   30 C         0%\n\
+             \x20   40 C         0%\n\
+             \x20   50 C        50%\n\
+             \x20   60 C       100%\n\
+             \x20   70 C       100%\n"
+        );
+    }
+
+    #[test]
+    fn plot() {
+        let plot = curve_plot(&map(), DegreesC::new(35), DegreesC::new(65), 80, Celsius);
+        let lines: Vec<&str> = plot.lines().collect();
+        assert_eq!(lines.len(), 13);
+        assert_eq!(lines[0], format!(" 100% |{}*******", " ".repeat(24)));
+        assert_eq!(lines[5], format!("  50% |{}**", " ".repeat(14)));
+        assert_eq!(lines[10], "   0% |******");
+        assert_eq!(lines[11], format!("      +{}", "-".repeat(31)));
+        assert!(lines[12].trim_start().starts_with("35 C"));
+        assert!(lines[12].ends_with("65 C"));
+    }
+
+    #[test]
+    fn reversed_range() {
+        let (low, high) = (DegreesC::new(30), DegreesC::new(70));
+        assert_eq!(
+            curve_table(&map(), high, low, 10, Celsius),
+            curve_table(&map(), low, high, 10, Celsius)
+        );
+        assert_eq!(
+            curve_plot(&map(), high, low, 80, Celsius),
+            curve_plot(&map(), low, high, 80, Celsius)
+        );
+    }
+
+    #[test]
+    fn plot_at() {
+        let at = (DegreesC::from_tenths(451), FanSpeed::new(80).unwrap());
+        let plot = curve_plot_at(
+            &map(),
+            DegreesC::new(35),
+            DegreesC::new(65),
+            80,
+            Celsius,
+            at,
+        );
+        let lines: Vec<&str> = plot.lines().collect();
+        assert_eq!(
+            lines[2],
+            format!("  80% |{}O{}**", " ".repeat(10), " ".repeat(9))
+        );
+        assert_eq!(lines[8], format!("  20% |{}**", " ".repeat(8)));
+
+        // Clamped to the plotted range
+        let at = (DegreesC::new(90), FanSpeed::MAX);
+        let plot = curve_plot_at(
+            &map(),
+            DegreesC::new(35),
+            DegreesC::new(65),
+            80,
+            Celsius,
+            at,
+        );
+        assert!(plot.lines().next().unwrap().ends_with("******O"));
+    }
+
+    #[test]
+    fn plot_is_sampled_to_width() {
+        let plot = curve_plot(&map(), DegreesC::new(0), DegreesC::new(255), 64, Celsius);
+        let axis = plot.lines().nth(11).unwrap();
+        assert_eq!(axis.trim_start().len(), 1 + 64);
+        assert!(plot.lines().all(|l| l.len() <= 7 + 64));
+    }
+
+    #[test]
+    fn report_json() {
+        let report = CurveReport::new(&map(), DegreesC::new(40), DegreesC::new(60), 10, Celsius);
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            "{\"unit\":\"celsius\",\"points\":[\
+             {\"temperature\":40,\"fan_speed\":0},\
+             {\"temperature\":50,\"fan_speed\":50},\
+             {\"temperature\":60,\"fan_speed\":100}]}"
+        );
+    }
+
+    #[test]
+    fn fahrenheit() {
+        assert_eq!(
+            curve_table(&map(), DegreesC::new(40), DegreesC::new(60), 9, Fahrenheit),
+            "    Temp  Fan speed\n\
+             \x20  104 F         0%\n\
+             \x20  113 F        25%\n\
+             \x20  122 F        50%\n\
+             \x20  131 F        75%\n\
+             \x20  140 F       100%\n"
+        );
+        let plot = curve_plot(&map(), DegreesC::new(35), DegreesC::new(65), 80, Fahrenheit);
+        let lines: Vec<&str> = plot.lines().collect();
+        assert_eq!(lines[11], format!("      +{}", "-".repeat(55)));
+        assert!(lines[12].trim_start().starts_with("95 F"));
+        assert!(lines[12].ends_with("149 F"));
+    }
+}
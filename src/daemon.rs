@@ -0,0 +1,1113 @@
+use crate::{
+    Clock, ControlCommand, ControlError, ControlServer, Controller, DaemonReport, DegreesC, Fan,
+    FanCommand, FanOverride, FanReport, FanSpeed, FeedForward, MqttBridge, MqttClient,
+    ProfileSchedule, ProfileSelection, RumqttClient, Scheduler, State, StateFile, SystemClock,
+    SystemdNotifier, TachReading, Tachometer, TelemetrySink, TemperatureSource, TemperatureUnit,
+    Tick, TraceRecorder, Wakeup, MAIN_FAN,
+};
+use log::{debug, info, warn};
+use std::iter;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// A fan besides the main one, along with its last reading
+struct FanChannel<T, F> {
+    name: String,
+    controller: Controller<T, F>,
+    /// True if it uses the main fan's profile, rather than one of its own
+    follow_profile: bool,
+    /// Temperature, fan speed and whether it was overridden, at the last update
+    reading: Option<(DegreesC, FanSpeed, bool)>,
+}
+
+/// The control loop, along with everything reporting on it
+pub struct Daemon<T, F, C = RumqttClient, K = SystemClock> {
+    clock: K,
+    wakeup: Wakeup,
+    controller: Controller<T, F>,
+    /// Fans besides the main one
+    fans: Vec<FanChannel<T, F>>,
+    scheduler: Scheduler,
+    notifier: SystemdNotifier,
+    mqtt: Option<MqttBridge<C>>,
+    override_timeout: Option<Duration>,
+    telemetry: Option<TelemetrySink>,
+    trace: Option<TraceRecorder>,
+    profiles: Option<ProfileSchedule>,
+    profile: Option<String>,
+    /// Profile selected at runtime, instead of the scheduled one
+    selected: Option<String>,
+    control: Option<ControlServer>,
+    state_file: Option<StateFile>,
+    feed_forward: Option<FeedForward>,
+    tachometer: Option<Tachometer>,
+    units: TemperatureUnit,
+    /// Temperature, fan speed and whether it was overridden, at the last update
+    reading: Option<(DegreesC, FanSpeed, bool)>,
+    /// Fan speed read back at the last update
+    tach: Option<TachReading>,
+}
+
+impl<T: TemperatureSource, F: Fan, C: MqttClient, K: Clock> Daemon<T, F, C, K> {
+    pub fn new(
+        clock: K,
+        controller: Controller<T, F>,
+        scheduler: Scheduler,
+        notifier: SystemdNotifier,
+    ) -> Self {
+        Daemon {
+            clock,
+            wakeup: Wakeup::new(),
+            controller,
+            fans: Vec::new(),
+            scheduler,
+            notifier,
+            mqtt: None,
+            override_timeout: None,
+            telemetry: None,
+            trace: None,
+            profiles: None,
+            profile: None,
+            selected: None,
+            control: None,
+            state_file: None,
+            feed_forward: None,
+            tachometer: None,
+            units: TemperatureUnit::Celsius,
+            reading: None,
+            tach: None,
+        }
+    }
+
+    /// Notifications on `wakeup` end the wait for the next deadline early
+    pub fn with_wakeup(mut self, wakeup: Wakeup) -> Self {
+        self.wakeup = wakeup;
+        self
+    }
+
+    /// Controls another fan alongside the main one, with the main fan's profile when
+    /// `follow_profile` is true. Its failures are logged rather than stopping the loop.
+    pub fn with_fan(
+        mut self,
+        name: &str,
+        controller: Controller<T, F>,
+        follow_profile: bool,
+    ) -> Self {
+        self.fans.push(FanChannel {
+            name: name.to_string(),
+            controller,
+            follow_profile,
+            reading: None,
+        });
+        self
+    }
+
+    pub fn with_mqtt(mut self, mqtt: MqttBridge<C>, override_timeout: Option<Duration>) -> Self {
+        self.mqtt = Some(mqtt);
+        self.override_timeout = override_timeout;
+        self
+    }
+
+    pub fn with_telemetry(mut self, telemetry: TelemetrySink) -> Self {
+        self.telemetry = Some(telemetry);
+        self
+    }
+
+    pub fn with_trace(mut self, trace: TraceRecorder) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Switches the controller profile according to the schedule
+    pub fn with_profiles(mut self, profiles: ProfileSchedule) -> Self {
+        self.profiles = Some(profiles);
+        self
+    }
+
+    /// Handles control socket commands and profile signals
+    pub fn with_control(mut self, control: ControlServer) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Restores the profile selected at runtime, and saves it on changes
+    pub fn with_state_file(mut self, state_file: StateFile) -> Self {
+        match state_file.load() {
+            Ok(state) => {
+                if let Some(p) = &state.profile {
+                    info!("Restoring selected profile {}", p);
+                }
+                self.selected = state.profile;
+            }
+            Err(e) => warn!("{}", e),
+        }
+        self.state_file = Some(state_file);
+        self
+    }
+
+    /// Boosts the fan speed with the CPU load at each update
+    pub fn with_feed_forward(mut self, feed_forward: FeedForward) -> Self {
+        self.feed_forward = Some(feed_forward);
+        self
+    }
+
+    /// Reads the fan speed back at each update, raising the alarm on stalls
+    pub fn with_tachometer(mut self, tachometer: Tachometer) -> Self {
+        self.tachometer = Some(tachometer);
+        self
+    }
+
+    /// Unit of the temperature in the status
+    pub fn with_units(mut self, units: TemperatureUnit) -> Self {
+        self.units = units;
+        self
+    }
+
+    /// Name of the profile in use, if any
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub fn controller(&self) -> &Controller<T, F> {
+        &self.controller
+    }
+
+    /// Runs the control loop until `running` is non-zero
+    pub fn run(&mut self, running: &AtomicUsize) -> Result<(), ControlError> {
+        let fan_speed = FanSpeed::default();
+        debug!("Setting default fan speed {}", fan_speed);
+        self.controller.set_speed(fan_speed)?;
+        for f in self.fans.iter_mut() {
+            if let Err(e) = f.controller.set_speed(fan_speed) {
+                warn!("Fan {}, {}", f.name, e);
+            }
+        }
+
+        if let Err(e) = self.notifier.ready() {
+            warn!("{}", e);
+        }
+
+        let mut result = Ok(());
+        while running.load(Ordering::SeqCst) == 0 {
+            result = self.step();
+            if result.is_err() {
+                break;
+            }
+            let now = self.clock.now();
+            let timeout = self.next_deadline(now).saturating_duration_since(now);
+            if self.clock.wait(timeout, &self.wakeup) {
+                debug!("Woken up early");
+            }
+        }
+
+        if let Err(e) = self.notifier.stopping() {
+            warn!("{}", e);
+        }
+        if let Some(mqtt) = self.mqtt.as_mut() {
+            if let Err(e) = mqtt.disconnect() {
+                warn!("{}", e);
+            }
+        }
+        result
+    }
+
+    /// A single loop iteration, updates the fan speed if the interval was reached
+    /// or the override changed
+    pub fn step(&mut self) -> Result<(), ControlError> {
+        let now = self.clock.now();
+        if let Err(e) = self.notifier.watchdog(now) {
+            warn!("{}", e);
+        }
+
+        let mut force_update = self.poll_mqtt(now);
+        for c in
+            iter::once(&mut self.controller).chain(self.fans.iter_mut().map(|f| &mut f.controller))
+        {
+            if c.expire_override(now) {
+                force_update = true;
+            }
+        }
+        if self.poll_control() {
+            force_update = true;
+        }
+        if self.update_profile() {
+            force_update = true;
+        }
+
+        if self.scheduler.update(now) || force_update {
+            if let Some(ff) = self.feed_forward.as_mut() {
+                let boost = ff.boost().unwrap_or_else(|e| {
+                    warn!("{}", e);
+                    FanSpeed::MIN
+                });
+                self.controller.set_boost(boost);
+            }
+            let mut tick = self.controller.tick(self.clock.utc_now());
+            if let Some(t) = tick.raw_temperature {
+                self.scheduler.observe(now, t);
+            }
+            if let (Some(tach), Some(s)) = (self.tachometer.as_mut(), tick.fan_speed) {
+                match tach.read(now, s) {
+                    Ok(r) => tick.tach = Some(r),
+                    Err(e) => warn!("{}", e),
+                }
+            }
+            let timestamp = self.clock.utc_now();
+            let fan_ticks: Vec<Tick> = self
+                .fans
+                .iter_mut()
+                .map(|f| f.controller.tick(timestamp))
+                .collect();
+            self.report(&tick, &fan_ticks);
+            if let Some(e) = tick.error.take() {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// The earliest of the next update, watchdog ping and override expiry
+    pub fn next_deadline(&self, now: Instant) -> Instant {
+        let mut deadline = self.scheduler.next_deadline();
+        if let Some(t) = self.notifier.next_watchdog(now) {
+            deadline = deadline.min(t);
+        }
+        for c in iter::once(&self.controller).chain(self.fans.iter().map(|f| &f.controller)) {
+            if let Some(t) = c.fan_override().and_then(|o| o.expires()) {
+                deadline = deadline.min(t);
+            }
+        }
+        deadline
+    }
+
+    /// Returns true if the profile changed
+    fn update_profile(&mut self) -> bool {
+        let profiles = match &self.profiles {
+            Some(p) => p,
+            None => return false,
+        };
+        if let Some(name) = &self.selected {
+            if profiles.get(name).is_none() {
+                warn!(
+                    "Selected profile {} no longer exists, using the schedule",
+                    name
+                );
+                self.selected = None;
+            }
+        }
+        let (name, profile) = match &self.selected {
+            Some(name) => (name.as_str(), profiles.get(name).unwrap()),
+            None => profiles.active(self.clock.local_time()),
+        };
+        if self.profile.as_deref() == Some(name) {
+            return false;
+        }
+        match self.controller.set_profile(profile) {
+            Ok(()) => {
+                info!("Using profile {}", name);
+                self.profile = Some(name.to_string());
+                self.scheduler.set_temperature_max(profile.temperature_max);
+                for f in self.fans.iter_mut().filter(|f| f.follow_profile) {
+                    if let Err(e) = f.controller.set_profile(profile) {
+                        warn!("Fan {} can't use profile {}, {}", f.name, name, e);
+                    }
+                }
+                true
+            }
+            Err(e) => {
+                warn!("Profile {} is invalid, {}", name, e);
+                false
+            }
+        }
+    }
+
+    /// Returns true if the profile changed
+    fn poll_control(&mut self) -> bool {
+        let mut changed = false;
+        while let Some(req) = self.control.as_ref().and_then(|c| c.try_recv()) {
+            debug!("Control command {}", req.command);
+            let result = match &req.command {
+                ControlCommand::Status | ControlCommand::Report => Ok(()),
+                ControlCommand::SetProfile(selection) => self.select_profile(selection.clone()),
+                ControlCommand::NextProfile => self.next_profile(),
+            };
+            changed |= self.update_profile();
+            let reply = match result {
+                Ok(()) if req.command == ControlCommand::Report => {
+                    match serde_json::to_string(&self.daemon_report()) {
+                        Ok(json) => format!("ok {}", json),
+                        Err(e) => format!("error {}", e),
+                    }
+                }
+                Ok(()) => format!("ok {}", self.profile_status()),
+                Err(e) => {
+                    warn!("{}", e);
+                    format!("error {}", e)
+                }
+            };
+            req.respond(reply);
+        }
+        changed
+    }
+
+    fn daemon_report(&self) -> DaemonReport {
+        DaemonReport {
+            profile: self.profile.clone(),
+            selected: self.selected.is_some(),
+            temperature: self.reading.map(|r| r.0),
+            fan_speed: self.reading.map(|r| r.1),
+            overridden: self.reading.map(|r| r.2).unwrap_or(false),
+            tach: self.tach,
+            fans: self
+                .fans
+                .iter()
+                .map(|f| {
+                    let report = FanReport {
+                        temperature: f.reading.map(|r| r.0),
+                        fan_speed: f.reading.map(|r| r.1),
+                        overridden: f.reading.map(|r| r.2).unwrap_or(false),
+                    };
+                    (f.name.clone(), report)
+                })
+                .collect(),
+        }
+    }
+
+    fn profile_status(&self) -> String {
+        match (&self.profile, &self.selected) {
+            (None, _) => "no profiles".to_string(),
+            (Some(p), Some(_)) => format!("profile {} (selected)", p),
+            (Some(p), None) => format!("profile {} (scheduled)", p),
+        }
+    }
+
+    fn select_profile(&mut self, selection: ProfileSelection) -> Result<(), String> {
+        let profiles = self.profiles.as_ref().ok_or("no profiles")?;
+        let selected = match selection {
+            ProfileSelection::Auto => None,
+            ProfileSelection::Named(name) if profiles.get(&name).is_some() => Some(name),
+            ProfileSelection::Named(name) => return Err(format!("unknown profile '{}'", name)),
+        };
+        if selected != self.selected {
+            match &selected {
+                Some(p) => info!("Selected profile {}", p),
+                None => info!("Selected the scheduled profile"),
+            }
+            self.selected = selected;
+            if let Some(f) = &self.state_file {
+                let state = State {
+                    profile: self.selected.clone(),
+                };
+                if let Err(e) = f.save(&state) {
+                    warn!("{}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Selects the profile after the one in use, in name order
+    fn next_profile(&mut self) -> Result<(), String> {
+        let profiles = self.profiles.as_ref().ok_or("no profiles")?;
+        let names: Vec<&str> = profiles.names().collect();
+        let next = self
+            .profile
+            .as_deref()
+            .and_then(|p| names.iter().position(|n| *n == p))
+            .map(|i| names[(i + 1) % names.len()])
+            .unwrap_or(names[0])
+            .to_string();
+        self.select_profile(ProfileSelection::Named(next))
+    }
+
+    fn controller_mut(&mut self, fan: &str) -> Option<&mut Controller<T, F>> {
+        if fan == MAIN_FAN {
+            Some(&mut self.controller)
+        } else {
+            self.fans
+                .iter_mut()
+                .find(|f| f.name == fan)
+                .map(|f| &mut f.controller)
+        }
+    }
+
+    /// Returns true if an override changed
+    fn poll_mqtt(&mut self, now: Instant) -> bool {
+        let cmds = match self.mqtt.as_mut().map(|m| m.poll()) {
+            None => return false,
+            Some(Ok(cmds)) => cmds,
+            Some(Err(e)) => {
+                warn!("{}", e);
+                return false;
+            }
+        };
+        let timeout = self.override_timeout;
+        let mut changed = false;
+        for (fan, cmd) in cmds {
+            let controller = match self.controller_mut(&fan) {
+                Some(c) => c,
+                None => continue,
+            };
+            match cmd {
+                FanCommand::Override(fan_speed) => {
+                    info!("Fan {} speed overridden to {}", fan, fan_speed);
+                    controller.set_override(Some(FanOverride::new(fan_speed, now, timeout)));
+                }
+                FanCommand::Auto => {
+                    info!("Fan {} speed override cleared", fan);
+                    controller.set_override(None);
+                }
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    /// Reports the main fan's tick and the other fans' ticks, in the same order as the fans
+    fn report(&mut self, tick: &Tick, fan_ticks: &[Tick]) {
+        if let Some(telemetry) = self.telemetry.as_mut() {
+            let names = iter::once(MAIN_FAN).chain(self.fans.iter().map(|f| f.name.as_str()));
+            for (name, t) in names.zip(iter::once(tick).chain(fan_ticks)) {
+                if let Err(e) = telemetry.record(name, t) {
+                    warn!("{}", e);
+                }
+            }
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            if let Err(e) = trace.record(tick) {
+                warn!("{}", e);
+            }
+        }
+        for (f, t) in self.fans.iter_mut().zip(fan_ticks) {
+            if let Some(e) = &t.error {
+                warn!("Fan {}, {}", f.name, e);
+            }
+            if let (Some(temp_c), Some(fan_speed)) = (t.temperature, t.fan_speed) {
+                f.reading = Some((temp_c, fan_speed, t.overridden));
+                if let Some(mqtt) = self.mqtt.as_mut() {
+                    let r = mqtt.publish_state(&f.name, temp_c, fan_speed, t.overridden, None);
+                    if let Err(e) = r {
+                        warn!("{}", e);
+                    }
+                }
+            }
+        }
+
+        let (temp_c, fan_speed) = match (tick.temperature, tick.fan_speed) {
+            (Some(t), Some(s)) => (t, s),
+            _ => return,
+        };
+        self.reading = Some((temp_c, fan_speed, tick.overridden));
+        self.tach = tick.tach;
+        let mut status = format!(
+            "Temp {}, fan speed {}",
+            temp_c.in_unit(self.units),
+            fan_speed
+        );
+        if tick.overridden {
+            status.push_str(" (override)");
+        } else if let Some(p) = &self.profile {
+            status.push_str(&format!(" ({} profile)", p));
+        }
+        if tick.boost > FanSpeed::MIN {
+            status.push_str(&format!(" (+{} load boost)", tick.boost));
+        }
+        if let Some(t) = tick.tach {
+            status.push_str(&format!(", {} RPM", t.rpm));
+            if t.stalled {
+                status.push_str(", STALLED");
+            }
+        }
+        for f in self.fans.iter() {
+            if let Some((_, fan_speed, overridden)) = f.reading {
+                status.push_str(&format!(", {} {}", f.name, fan_speed));
+                if overridden {
+                    status.push_str(" (override)");
+                }
+            }
+        }
+        if let Err(e) = self.notifier.status(&status) {
+            warn!("{}", e);
+        }
+        if let Some(mqtt) = self.mqtt.as_mut() {
+            let r = mqtt.publish_state(MAIN_FAN, temp_c, fan_speed, tick.overridden, tick.tach);
+            if let Err(e) = r {
+                warn!("{}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::test::{map, FakeError, FakeFan, FakeSensor};
+    use crate::tach::test::FakeTach;
+    use crate::{
+        AdaptiveInterval, ManualClock, MqttConfig, MqttError, MqttEvent, TachometerConfig,
+        TelemetryConfig, TelemetryFormat, DEFAULT_PROFILE,
+    };
+    use std::collections::VecDeque;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    /// Hands out the queued events, records the publications
+    #[derive(Clone, Default)]
+    struct FakeMqtt {
+        events: Arc<Mutex<VecDeque<MqttEvent>>>,
+        published: Arc<Mutex<Vec<(String, serde_json::Value)>>>,
+    }
+
+    impl MqttClient for FakeMqtt {
+        fn publish(&mut self, topic: &str, payload: &[u8], _: bool) -> Result<(), MqttError> {
+            let payload = serde_json::from_slice(payload).unwrap_or(serde_json::Value::Null);
+            self.published
+                .lock()
+                .unwrap()
+                .push((topic.to_string(), payload));
+            Ok(())
+        }
+
+        fn subscribe(&mut self, _: &str) -> Result<(), MqttError> {
+            Ok(())
+        }
+
+        fn try_recv(&mut self) -> Option<MqttEvent> {
+            self.events.lock().unwrap().pop_front()
+        }
+
+        fn disconnect(&mut self) -> Result<(), MqttError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn telemetry_per_tick() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("telemetry.jsonl");
+        let telemetry = TelemetrySink::new(TelemetryConfig {
+            path: path.clone(),
+            format: TelemetryFormat::JsonLines,
+            max_size_bytes: 1024 * 1024,
+            retention: 1,
+        })
+        .unwrap();
+        let fan = FakeFan::default();
+        let sensor = FakeSensor::new(&[Ok(35.2), Ok(50.0), Ok(60.0), Err(FakeError("mailbox"))]);
+        let interval = Duration::from_secs(30);
+        let clock = ManualClock::new();
+        let mut daemon: Daemon<_, _, RumqttClient, _> = Daemon::new(
+            clock.clone(),
+            Controller::new(sensor, fan.clone(), map()),
+            Scheduler::new(clock.now(), interval),
+            SystemdNotifier::new(None, None).unwrap(),
+        )
+        .with_telemetry(telemetry);
+
+        // Nothing happens until the interval is reached
+        clock.advance(Duration::from_secs(1));
+        daemon.step().unwrap();
+        assert!(fan.speeds().is_empty());
+
+        clock.advance(interval - Duration::from_secs(1));
+        daemon.step().unwrap();
+        clock.advance(interval);
+        daemon.step().unwrap();
+        *fan.fail.lock().unwrap() = true;
+        clock.advance(interval);
+        daemon.step().unwrap_err();
+        *fan.fail.lock().unwrap() = false;
+        clock.advance(interval);
+        daemon.step().unwrap_err();
+
+        assert_eq!(
+            fan.speeds(),
+            vec![FanSpeed::new(13).unwrap(), FanSpeed::new(50).unwrap()]
+        );
+
+        let records: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                serde_json::json!({
+                    "timestamp": "2022-01-01T00:00:30.000Z",
+                    "fan": "case",
+                    "raw_temperature": 35.2,
+                    "temperature": 35.2,
+                    "fan_speed": 13,
+                    "overridden": false,
+                    "written": true,
+                    "error": null,
+                }),
+                serde_json::json!({
+                    "timestamp": "2022-01-01T00:01:00.000Z",
+                    "fan": "case",
+                    "raw_temperature": 50.0,
+                    "temperature": 50,
+                    "fan_speed": 50,
+                    "overridden": false,
+                    "written": true,
+                    "error": null,
+                }),
+                serde_json::json!({
+                    "timestamp": "2022-01-01T00:01:30.000Z",
+                    "fan": "case",
+                    "raw_temperature": 60.0,
+                    "temperature": 60,
+                    "fan_speed": 75,
+                    "overridden": false,
+                    "written": false,
+                    "error": "Failed to set the fan speed, I2C write failed",
+                }),
+                serde_json::json!({
+                    "timestamp": "2022-01-01T00:02:00.000Z",
+                    "fan": "case",
+                    "raw_temperature": null,
+                    "temperature": null,
+                    "fan_speed": null,
+                    "overridden": false,
+                    "written": false,
+                    "error": "Failed to read the temperature, mailbox",
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn tachometer_stall() {
+        let fan = FakeFan::default();
+        let interval = Duration::from_secs(30);
+        let clock = ManualClock::new();
+        let tach = Tachometer::with_feedback(
+            Box::new(FakeTach(vec![0, 1500, 0].into_iter().collect())),
+            &TachometerConfig {
+                stall_seconds: 45,
+                ..Default::default()
+            },
+        );
+        let mut daemon: Daemon<_, _, RumqttClient, _> = Daemon::new(
+            clock.clone(),
+            Controller::new(FakeSensor::new(&[Ok(60.0)]), fan, map()),
+            Scheduler::new(clock.now(), interval),
+            SystemdNotifier::new(None, None).unwrap(),
+        )
+        .with_tachometer(tach);
+
+        let mut tach = || {
+            clock.advance(interval);
+            daemon.step().unwrap();
+            daemon.daemon_report().tach.unwrap()
+        };
+        assert_eq!(
+            tach(),
+            TachReading {
+                rpm: 0,
+                stalled: false
+            }
+        );
+        assert_eq!(
+            tach(),
+            TachReading {
+                rpm: 1500,
+                stalled: false
+            }
+        );
+        // Stopped at 75% for 30 s, then 60 s
+        assert_eq!(
+            tach(),
+            TachReading {
+                rpm: 0,
+                stalled: false
+            }
+        );
+        assert_eq!(
+            tach(),
+            TachReading {
+                rpm: 0,
+                stalled: false
+            }
+        );
+        assert_eq!(
+            tach(),
+            TachReading {
+                rpm: 0,
+                stalled: true
+            }
+        );
+    }
+
+    #[test]
+    fn multiple_fans() {
+        let config: crate::Config = toml::from_str(
+            r#"
+            update_interval_seconds = 60
+            temperature_min = 30
+            temperature_max = 70
+            fan_speed_min = 0
+            fan_speed_max = 100
+
+            [profiles.quiet]
+            temperature_min = 30
+            temperature_max = 70
+            fan_speed_min = 0
+            fan_speed_max = 40
+            "#,
+        )
+        .unwrap();
+        let mqtt_config = MqttConfig {
+            host: "localhost".to_string(),
+            port: crate::MQTT_PORT,
+            client_id: "pi".to_string(),
+            username: None,
+            password: None,
+            base_topic: None,
+            discovery_prefix: crate::HA_DISCOVERY_PREFIX.to_string(),
+            override_timeout_seconds: None,
+        };
+        let mqtt = FakeMqtt::default();
+        let (case, hat, nvme) = (FakeFan::default(), FakeFan::default(), FakeFan::default());
+        let clock = ManualClock::new();
+        let mut quiet = Controller::new(FakeSensor::new(&[Ok(60.0)]), hat.clone(), map());
+        quiet
+            .set_profile(&config.profile("quiet").unwrap())
+            .unwrap();
+        let mut daemon = Daemon::new(
+            clock.clone(),
+            Controller::new(FakeSensor::new(&[Ok(60.0)]), case.clone(), map()),
+            Scheduler::new(clock.now(), Duration::from_secs(60)),
+            SystemdNotifier::new(None, None).unwrap(),
+        )
+        .with_fan("hat", quiet, false)
+        .with_fan(
+            "nvme",
+            Controller::new(
+                FakeSensor::new(&[Ok(50.0), Err(FakeError("hwmon"))]),
+                nvme.clone(),
+                map(),
+            ),
+            true,
+        )
+        .with_profiles(config.profile_schedule())
+        .with_mqtt(
+            MqttBridge::new(mqtt.clone(), mqtt_config.clone())
+                .with_fans(vec!["hat".to_string(), "nvme".to_string()]),
+            None,
+        );
+
+        daemon.step().unwrap();
+        let percentage_command = mqtt_config.fan_topics("hat").percentage_command;
+        mqtt.events.lock().unwrap().push_back(MqttEvent::Message {
+            topic: percentage_command,
+            payload: b"80".to_vec(),
+        });
+        // An override of one fan updates all of them, and failures of the others
+        // don't stop the loop
+        daemon.step().unwrap();
+
+        let speeds = |f: &FakeFan| -> Vec<u8> { f.speeds().into_iter().map(u8::from).collect() };
+        assert_eq!(speeds(&case), vec![75, 75]);
+        assert_eq!(speeds(&hat), vec![30, 80]);
+        assert_eq!(speeds(&nvme), vec![50]);
+
+        let report = daemon.daemon_report();
+        assert_eq!(report.fan_speed, FanSpeed::new(75));
+        assert_eq!(
+            report.fans.into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    "hat".to_string(),
+                    FanReport {
+                        temperature: Some(DegreesC::new(60)),
+                        fan_speed: FanSpeed::new(80),
+                        overridden: true,
+                    }
+                ),
+                (
+                    "nvme".to_string(),
+                    FanReport {
+                        temperature: Some(DegreesC::new(50)),
+                        fan_speed: FanSpeed::new(50),
+                        overridden: false,
+                    }
+                ),
+            ]
+        );
+        let published = mqtt.published.lock().unwrap();
+        let states: Vec<(&str, &serde_json::Value)> = published
+            .iter()
+            .map(|(t, p)| (t.as_str(), &p["fan_speed"]))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ("argonone/pi/fans/hat/state", &serde_json::json!(30)),
+                ("argonone/pi/fans/nvme/state", &serde_json::json!(50)),
+                ("argonone/pi/state", &serde_json::json!(75)),
+                ("argonone/pi/fans/hat/state", &serde_json::json!(80)),
+                ("argonone/pi/state", &serde_json::json!(75)),
+            ]
+        );
+    }
+
+    #[test]
+    fn next_deadline() {
+        let fan = FakeFan::default();
+        let interval = Duration::from_secs(30);
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut daemon: Daemon<_, _, RumqttClient, _> = Daemon::new(
+            clock.clone(),
+            Controller::new(FakeSensor::new(&[Ok(50.0)]), fan.clone(), map()),
+            Scheduler::new(start, interval),
+            SystemdNotifier::new(None, None).unwrap(),
+        );
+        assert_eq!(daemon.next_deadline(start), start + interval);
+
+        let timeout = Duration::from_secs(10);
+        daemon
+            .controller
+            .set_override(Some(FanOverride::new(FanSpeed::MAX, start, Some(timeout))));
+        assert_eq!(daemon.next_deadline(start), start + timeout);
+
+        // Waking at the expiry reverts the override without waiting for the interval
+        clock.advance(timeout);
+        daemon.step().unwrap();
+        assert_eq!(fan.speeds(), vec![FanSpeed::new(50).unwrap()]);
+        assert_eq!(daemon.next_deadline(clock.now()), start + interval);
+    }
+
+    #[test]
+    fn scheduled_profiles() {
+        let config: crate::Config = toml::from_str(
+            r#"
+            update_interval_seconds = 60
+            temperature_min = 30
+            temperature_max = 70
+            fan_speed_min = 0
+            fan_speed_max = 100
+
+            [profiles.quiet]
+            temperature_min = 30
+            temperature_max = 70
+            fan_speed_min = 0
+            fan_speed_max = 40
+
+            [[schedule]]
+            profile = "quiet"
+            start = "22:00"
+            end = "07:00"
+            "#,
+        )
+        .unwrap();
+        let fan = FakeFan::default();
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut daemon: Daemon<_, _, RumqttClient, _> = Daemon::new(
+            clock.clone(),
+            Controller::new(FakeSensor::new(&[Ok(70.0)]), fan.clone(), map()),
+            Scheduler::new(start, Duration::from_secs(3600)),
+            SystemdNotifier::new(None, None).unwrap(),
+        )
+        .with_profiles(config.profile_schedule());
+
+        // Midnight, the profile applies straight away
+        daemon.step().unwrap();
+        assert_eq!(daemon.profile(), Some("quiet"));
+        clock.advance(Duration::from_secs(7 * 3600));
+        daemon.step().unwrap();
+        assert_eq!(daemon.profile(), Some(DEFAULT_PROFILE));
+        daemon.step().unwrap();
+        assert_eq!(
+            fan.speeds(),
+            vec![FanSpeed::new(40).unwrap(), FanSpeed::MAX]
+        );
+    }
+
+    #[test]
+    fn adaptive_interval_follows_profile() {
+        let config: crate::Config = toml::from_str(
+            r#"
+            update_interval_seconds = 60
+            temperature_min = 30
+            temperature_max = 70
+            fan_speed_min = 0
+            fan_speed_max = 100
+
+            [profiles.quiet]
+            temperature_min = 30
+            temperature_max = 50
+            fan_speed_min = 0
+            fan_speed_max = 40
+
+            [[schedule]]
+            profile = "quiet"
+            start = "22:00"
+            end = "07:00"
+            "#,
+        )
+        .unwrap();
+        let clock = ManualClock::new();
+        let adaptive = AdaptiveInterval::new(&Default::default(), config.temperature_max);
+        let mut daemon: Daemon<_, _, RumqttClient, _> = Daemon::new(
+            clock.clone(),
+            Controller::new(FakeSensor::new(&[Ok(47.0)]), FakeFan::default(), map()),
+            Scheduler::new(clock.now(), Duration::from_secs(60)).with_adaptive(adaptive),
+            SystemdNotifier::new(None, None).unwrap(),
+        )
+        .with_profiles(config.profile_schedule());
+
+        // Within 5 C of the quiet profile's max
+        daemon.step().unwrap();
+        assert_eq!(daemon.profile(), Some("quiet"));
+        assert_eq!(daemon.scheduler.interval(), Duration::from_secs(5));
+        // But not of the default profile's
+        clock.advance(Duration::from_secs(7 * 3600));
+        daemon.step().unwrap();
+        assert_eq!(daemon.profile(), Some(DEFAULT_PROFILE));
+        assert_eq!(daemon.scheduler.interval(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn invalid_profile_is_not_used() {
+        let config: crate::Config = toml::from_str(
+            r#"
+            update_interval_seconds = 60
+            temperature_min = 30
+            temperature_max = 70
+            fan_speed_min = 0
+            fan_speed_max = 100
+
+            [profiles.broken]
+            temperature_min = 70
+            temperature_max = 30
+            fan_speed_min = 0
+            fan_speed_max = 40
+
+            [[schedule]]
+            profile = "broken"
+            start = "22:00"
+            end = "07:00"
+            "#,
+        )
+        .unwrap();
+        let fan = FakeFan::default();
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut daemon: Daemon<_, _, RumqttClient, _> = Daemon::new(
+            clock.clone(),
+            Controller::new(FakeSensor::new(&[Ok(70.0)]), fan.clone(), map()),
+            Scheduler::new(start, Duration::from_secs(3600)),
+            SystemdNotifier::new(None, None).unwrap(),
+        )
+        .with_profiles(config.profile_schedule());
+
+        daemon.step().unwrap();
+        assert_eq!(daemon.profile(), None);
+        assert!(fan.speeds().is_empty());
+        clock.advance(Duration::from_secs(7 * 3600));
+        daemon.step().unwrap();
+        assert_eq!(daemon.profile(), Some(DEFAULT_PROFILE));
+        assert_eq!(fan.speeds(), vec![FanSpeed::MAX]);
+    }
+
+    #[test]
+    fn runtime_profile_selection() {
+        let config: crate::Config = toml::from_str(
+            r#"
+            update_interval_seconds = 60
+            temperature_min = 30
+            temperature_max = 70
+            fan_speed_min = 0
+            fan_speed_max = 100
+
+            [profiles.balanced]
+            temperature_min = 30
+            temperature_max = 70
+            fan_speed_min = 0
+            fan_speed_max = 60
+
+            [profiles.silent]
+            temperature_min = 30
+            temperature_max = 70
+            fan_speed_min = 0
+            fan_speed_max = 20
+            "#,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = StateFile::new(dir.path().join("state.toml"));
+        let fan = FakeFan::default();
+        let clock = ManualClock::new();
+        let new_daemon = || -> Daemon<_, _, RumqttClient, _> {
+            let control = ControlServer::new(Wakeup::new());
+            Daemon::new(
+                clock.clone(),
+                Controller::new(FakeSensor::new(&[Ok(70.0)]), fan.clone(), map()),
+                Scheduler::new(clock.now(), Duration::from_secs(3600)),
+                SystemdNotifier::new(None, None).unwrap(),
+            )
+            .with_profiles(config.profile_schedule())
+            .with_control(control)
+            .with_state_file(state_file.clone())
+        };
+        let mut daemon = new_daemon();
+        daemon.step().unwrap();
+        assert_eq!(daemon.profile(), Some(DEFAULT_PROFILE));
+
+        let command = |daemon: &mut Daemon<_, _, RumqttClient, _>, c: &str| {
+            let reply = daemon.control.as_ref().unwrap().submit(c.parse().unwrap());
+            daemon.step().unwrap();
+            reply.try_recv().unwrap()
+        };
+        assert_eq!(
+            command(&mut daemon, "profile silent"),
+            "ok profile silent (selected)"
+        );
+        assert_eq!(
+            command(&mut daemon, "profile turbo"),
+            "error unknown profile 'turbo'"
+        );
+        assert_eq!(
+            command(&mut daemon, "status"),
+            "ok profile silent (selected)"
+        );
+        let report = command(&mut daemon, "report");
+        assert_eq!(
+            serde_json::from_str::<DaemonReport>(report.trim_start_matches("ok ")).unwrap(),
+            DaemonReport {
+                profile: Some("silent".to_string()),
+                selected: true,
+                temperature: Some(DegreesC::new(70)),
+                fan_speed: FanSpeed::new(20),
+                overridden: false,
+                tach: None,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            state_file.load().unwrap().profile.as_deref(),
+            Some("silent")
+        );
+
+        // Persists across restarts
+        let mut daemon = new_daemon();
+        daemon.step().unwrap();
+        assert_eq!(daemon.profile(), Some("silent"));
+        assert_eq!(
+            command(&mut daemon, "next-profile"),
+            "ok profile balanced (selected)"
+        );
+        assert_eq!(
+            command(&mut daemon, "profile auto"),
+            "ok profile default (scheduled)"
+        );
+        assert_eq!(state_file.load().unwrap(), State::default());
+
+        let speeds: Vec<u8> = fan.speeds().into_iter().map(u8::from).collect();
+        assert_eq!(speeds, vec![100, 20, 20, 60, 100]);
+    }
+}
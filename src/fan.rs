@@ -0,0 +1,308 @@
+use crate::{Clock, Fan, FanSpeed, I2cAddress, I2cBus, I2C_BUS};
+use log::{debug, info};
+use rppal::gpio::{self, Gpio, OutputPin};
+use rppal::i2c::{self, I2c};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Name of the fan set up by the top level fields and the I2C options, the Argon ONE's
+pub const MAIN_FAN: &str = "case";
+
+#[derive(Debug, err_derive::Error)]
+pub enum FanDeviceError {
+    #[error(display = "Failed to open the I2C fan controller, {}", _0)]
+    I2c(#[error(from)] i2c::Error),
+
+    #[error(display = "Failed to open the fan's GPIO pin, {}", _0)]
+    Gpio(#[error(from)] gpio::Error),
+
+    #[error(display = "No fan i2c_address or gpio_pin configured")]
+    NoDriver,
+}
+
+/// Argon ONE fan controller, takes the fan speed percentage as a single SMBus byte
+#[derive(Debug)]
+pub struct I2cFan(I2c);
+
+impl I2cFan {
+    pub fn new(bus: I2cBus, addr: I2cAddress) -> Result<Self, i2c::Error> {
+        let mut i2c = I2c::with_bus(bus.into())?;
+        i2c.set_slave_address(addr.into())?;
+        Ok(I2cFan(i2c))
+    }
+}
+
+impl Fan for I2cFan {
+    type Error = i2c::Error;
+
+    fn set_speed(&mut self, speed: FanSpeed) -> Result<(), Self::Error> {
+        self.0.smbus_send_byte(speed.into())
+    }
+}
+
+/// Fan with a PWM input on a GPIO pin, driven by software PWM
+#[derive(Debug)]
+pub struct PwmFan {
+    pin: OutputPin,
+    frequency_hz: f64,
+}
+
+impl PwmFan {
+    pub fn new(pin: u8, frequency_hz: u32) -> Result<Self, gpio::Error> {
+        Ok(PwmFan {
+            pin: Gpio::new()?.get(pin)?.into_output(),
+            frequency_hz: frequency_hz.into(),
+        })
+    }
+}
+
+impl Fan for PwmFan {
+    type Error = gpio::Error;
+
+    fn set_speed(&mut self, speed: FanSpeed) -> Result<(), Self::Error> {
+        let duty_cycle = f64::from(u8::from(speed)) / 100.0;
+        self.pin.set_pwm_frequency(self.frequency_hz, duty_cycle)
+    }
+}
+
+/// Logs the fan speed instead of writing it, for trying out a configuration
+#[derive(Debug, Default)]
+pub struct DryRunFan;
+
+impl Fan for DryRunFan {
+    type Error = Infallible;
+
+    fn set_speed(&mut self, speed: FanSpeed) -> Result<(), Self::Error> {
+        info!("Dry run, would set the fan speed to {}", speed);
+        Ok(())
+    }
+}
+
+/// Any of the fan drivers, so fans driven differently share a controller type
+#[derive(Debug)]
+pub enum FanDevice {
+    I2c(I2cFan),
+    Pwm(PwmFan),
+    DryRun(DryRunFan),
+}
+
+impl FanDevice {
+    /// Opens the fan's driver, or logs its speeds for a dry run
+    pub fn open(config: &FanOutputConfig, dry_run: bool) -> Result<Self, FanDeviceError> {
+        if dry_run {
+            return Ok(FanDevice::DryRun(DryRunFan));
+        }
+        match (config.i2c_address, config.gpio_pin) {
+            (Some(addr), None) => Ok(FanDevice::I2c(I2cFan::new(
+                I2cBus(config.i2c_bus.unwrap_or(I2C_BUS)),
+                I2cAddress(addr),
+            )?)),
+            (None, Some(pin)) => Ok(FanDevice::Pwm(PwmFan::new(pin, config.pwm_frequency_hz)?)),
+            _ => Err(FanDeviceError::NoDriver),
+        }
+    }
+}
+
+impl Fan for FanDevice {
+    type Error = FanDeviceError;
+
+    fn set_speed(&mut self, speed: FanSpeed) -> Result<(), Self::Error> {
+        match self {
+            FanDevice::I2c(f) => f.set_speed(speed)?,
+            FanDevice::Pwm(f) => f.set_speed(speed)?,
+            FanDevice::DryRun(f) => match f.set_speed(speed) {
+                Ok(()) => (),
+                Err(e) => match e {},
+            },
+        }
+        Ok(())
+    }
+}
+
+/// A fan besides the main one, with its own driver, sensor and curve
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct FanOutputConfig {
+    /// I2C bus of a fan controller taking the speed as a single SMBus byte, like the
+    /// Argon ONE's, bus 1 when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub i2c_bus: Option<u8>,
+    /// I2C address of the fan controller
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub i2c_address: Option<u16>,
+    /// BCM GPIO pin wired to the fan's PWM input
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpio_pin: Option<u8>,
+    /// Software PWM frequency on gpio_pin
+    #[serde(default = "FanOutputConfig::default_pwm_frequency_hz")]
+    pub pwm_frequency_hz: u32,
+    /// Temperature file in millidegrees C the fan follows, such as a thermal zone or
+    /// hwmon input, the CPU temperature when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor: Option<PathBuf>,
+    /// Profile giving the fan's curve, the main fan's profile in use when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// Run the fan faster for a moment when starting it, disabled when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kick: Option<KickConfig>,
+}
+
+impl FanOutputConfig {
+    fn default_pwm_frequency_hz() -> u32 {
+        1000
+    }
+}
+
+impl Default for FanOutputConfig {
+    fn default() -> Self {
+        FanOutputConfig {
+            i2c_bus: None,
+            i2c_address: None,
+            gpio_pin: None,
+            pwm_frequency_hz: Self::default_pwm_frequency_hz(),
+            sensor: None,
+            profile: None,
+            kick: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct KickConfig {
+    /// Fan speed written first when starting the fan from stopped at a lower speed
+    pub fan_speed: FanSpeed,
+    /// How long the kick lasts
+    #[serde(default = "KickConfig::default_milliseconds")]
+    pub milliseconds: u32,
+}
+
+impl KickConfig {
+    fn default_milliseconds() -> u32 {
+        1000
+    }
+}
+
+impl Default for KickConfig {
+    fn default() -> Self {
+        KickConfig {
+            fan_speed: FanSpeed(40),
+            milliseconds: Self::default_milliseconds(),
+        }
+    }
+}
+
+/// Briefly runs the fan faster when starting it, for fans that keep turning at
+/// speeds they can't start at. Blocks on the clock for the kick's duration.
+#[derive(Debug)]
+pub struct KickFan<F, K> {
+    clock: K,
+    fan: F,
+    kick: Option<KickConfig>,
+    /// Last speed written, None until the first write
+    speed: Option<FanSpeed>,
+}
+
+impl<F: Fan, K: Clock> KickFan<F, K> {
+    /// Passes the speeds through when `kick` is None
+    pub fn new(clock: K, fan: F, kick: Option<KickConfig>) -> Self {
+        KickFan {
+            clock,
+            fan,
+            kick,
+            speed: None,
+        }
+    }
+}
+
+impl<F: Fan, K: Clock> Fan for KickFan<F, K> {
+    type Error = F::Error;
+
+    fn set_speed(&mut self, speed: FanSpeed) -> Result<(), Self::Error> {
+        let stopped = self.speed.unwrap_or(FanSpeed::MIN) == FanSpeed::MIN;
+        if let Some(k) = self.kick {
+            if stopped && speed > FanSpeed::MIN && speed < k.fan_speed {
+                debug!("Kicking the fan at {} to start it", k.fan_speed);
+                self.fan.set_speed(k.fan_speed)?;
+                self.clock
+                    .sleep(Duration::from_millis(k.milliseconds.into()));
+            }
+        }
+        self.fan.set_speed(speed)?;
+        self.speed = Some(speed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::test::FakeFan;
+    use crate::ManualClock;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    /// Records when each speed was written
+    #[derive(Clone)]
+    struct TimedFan {
+        clock: ManualClock,
+        writes: Arc<Mutex<Vec<(Instant, FanSpeed)>>>,
+    }
+
+    impl Fan for TimedFan {
+        type Error = Infallible;
+
+        fn set_speed(&mut self, speed: FanSpeed) -> Result<(), Self::Error> {
+            self.writes.lock().unwrap().push((self.clock.now(), speed));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn kick_from_stopped() {
+        let inner = FakeFan::default();
+        let kick = KickConfig {
+            fan_speed: FanSpeed::new(40).unwrap(),
+            milliseconds: 0,
+        };
+        let mut fan = KickFan::new(ManualClock::new(), inner.clone(), Some(kick));
+        for s in [20, 30, 0, 50, 0, 10].iter() {
+            fan.set_speed(FanSpeed::new(*s).unwrap()).unwrap();
+        }
+        let speeds: Vec<u8> = inner.speeds().into_iter().map(u8::from).collect();
+        assert_eq!(speeds, vec![40, 20, 30, 0, 50, 0, 40, 10]);
+
+        let inner = FakeFan::default();
+        let mut fan = KickFan::new(ManualClock::new(), inner.clone(), None);
+        fan.set_speed(FanSpeed::new(20).unwrap()).unwrap();
+        assert_eq!(inner.speeds(), vec![FanSpeed::new(20).unwrap()]);
+    }
+
+    #[test]
+    fn kick_waits_on_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let inner = TimedFan {
+            clock: clock.clone(),
+            writes: Default::default(),
+        };
+        let kick = KickConfig {
+            fan_speed: FanSpeed::new(60).unwrap(),
+            milliseconds: 1500,
+        };
+        let mut fan = KickFan::new(clock.clone(), inner.clone(), Some(kick));
+        fan.set_speed(FanSpeed::new(20).unwrap()).unwrap();
+        let writes = inner.writes.lock().unwrap().clone();
+        assert_eq!(
+            writes,
+            vec![
+                (start, FanSpeed::new(60).unwrap()),
+                (
+                    start + Duration::from_millis(1500),
+                    FanSpeed::new(20).unwrap()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,53 @@
+use crate::FanSpeed;
+use std::time::{Duration, Instant};
+
+/// A fixed fan speed that takes precedence over the fan speed map,
+/// optionally reverting to automatic control after a timeout
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct FanOverride {
+    speed: FanSpeed,
+    expires: Option<Instant>,
+}
+
+impl FanOverride {
+    pub fn new(speed: FanSpeed, now: Instant, timeout: Option<Duration>) -> Self {
+        FanOverride {
+            speed,
+            expires: timeout.and_then(|t| now.checked_add(t)),
+        }
+    }
+
+    pub fn speed(&self) -> FanSpeed {
+        self.speed
+    }
+
+    pub fn expires(&self) -> Option<Instant> {
+        self.expires
+    }
+
+    /// True if the timeout was reached
+    pub fn is_expired(&self, now: Instant) -> bool {
+        match self.expires {
+            None => false,
+            Some(expires) => now >= expires,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        let dur = Duration::from_secs(100);
+        let now = Instant::now();
+        let o = FanOverride::new(FanSpeed::MAX, now, Some(dur));
+        assert_eq!(o.speed(), FanSpeed::MAX);
+        assert!(!o.is_expired(now));
+        assert!(o.is_expired(now + dur));
+
+        let o = FanOverride::new(FanSpeed::MIN, now, None);
+        assert!(!o.is_expired(now + dur));
+    }
+}
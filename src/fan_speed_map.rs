@@ -1,14 +1,33 @@
 use crate::{DegreesC, FanSpeed};
-use num::clamp;
-use std::collections::HashMap;
 
+#[derive(Debug, Clone, PartialEq, err_derive::Error)]
+pub enum FanSpeedMapError {
+    #[error(
+        display = "temperature_min ({}) must be below temperature_max ({})",
+        _0,
+        _1
+    )]
+    InvalidTemperatureRange(DegreesC, DegreesC),
+
+    #[error(
+        display = "fan_speed_min ({}) must be below fan_speed_max ({})",
+        _0,
+        _1
+    )]
+    InvalidFanSpeedRange(FanSpeed, FanSpeed),
+
+    #[error(display = "fan speed {} is above 100%", _0)]
+    InvalidFanSpeed(FanSpeed),
+}
+
+/// Linear interpolation between the min and max fan speeds, continuous over the
+/// temperature range down to tenths of a degree
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FanSpeedMap {
     temperature_min: DegreesC,
     temperature_max: DegreesC,
     fan_speed_min: FanSpeed,
     fan_speed_max: FanSpeed,
-    map: HashMap<DegreesC, FanSpeed>,
 }
 
 impl FanSpeedMap {
@@ -17,54 +36,51 @@ impl FanSpeedMap {
         temperature_max: DegreesC,
         fan_speed_min: FanSpeed,
         fan_speed_max: FanSpeed,
-    ) -> Self {
-        let t_min = u8::from(temperature_min);
-        let t_max = u8::from(temperature_max);
-        let s_min = u8::from(fan_speed_min);
-        let s_max = u8::from(fan_speed_max);
-        // TODO - make an error type or use Config ref
-        assert!(t_max > t_min, "Invalid temperature range");
-        assert!(s_max > s_min, "Invalid fan speed range");
-
-        let mut map = HashMap::new();
-        for t in t_min..=t_max {
-            let s_f64 = map_range((t_min as _, t_max as _), (s_min as _, s_max as _), t as _);
-            let s = FanSpeed::new_unchecked(clamp(s_f64 as _, s_min, s_max));
-            let t = DegreesC::from(t);
-            log::debug!("{} -> {}", t, s);
-            map.insert(t, s);
+    ) -> Result<Self, FanSpeedMapError> {
+        if let Some(s) = [fan_speed_min, fan_speed_max]
+            .iter()
+            .find(|s| **s > FanSpeed::MAX)
+        {
+            return Err(FanSpeedMapError::InvalidFanSpeed(*s));
+        }
+        if temperature_max <= temperature_min {
+            return Err(FanSpeedMapError::InvalidTemperatureRange(
+                temperature_min,
+                temperature_max,
+            ));
+        }
+        if fan_speed_max <= fan_speed_min {
+            return Err(FanSpeedMapError::InvalidFanSpeedRange(
+                fan_speed_min,
+                fan_speed_max,
+            ));
         }
 
-        FanSpeedMap {
+        Ok(FanSpeedMap {
             temperature_min,
             temperature_max,
             fan_speed_min,
             fan_speed_max,
-            map,
-        }
+        })
     }
 
+    /// Rounds down to the whole percentage
     pub fn get(&self, temp: DegreesC) -> FanSpeed {
-        if temp < self.temperature_min {
+        if temp <= self.temperature_min {
             self.fan_speed_min
-        } else if temp > self.temperature_max {
+        } else if temp >= self.temperature_max {
             self.fan_speed_max
         } else {
-            *self.map.get(&temp).unwrap_or_else(|| {
-                panic!(
-                    "Internal map corrupt? No value for key {}\n{:?}",
-                    temp, self.map
-                )
-            })
+            let t_min = i32::from(self.temperature_min.tenths());
+            let t_max = i32::from(self.temperature_max.tenths());
+            let s_min = i32::from(u8::from(self.fan_speed_min));
+            let s_max = i32::from(u8::from(self.fan_speed_max));
+            let s = s_min + (i32::from(temp.tenths()) - t_min) * (s_max - s_min) / (t_max - t_min);
+            FanSpeed::new_unchecked(s as u8)
         }
     }
 }
 
-// https://rosettacode.org/wiki/Map_range#Rust
-fn map_range(from_range: (f64, f64), to_range: (f64, f64), s: f64) -> f64 {
-    to_range.0 + (s - from_range.0) * (to_range.1 - to_range.0) / (from_range.1 - from_range.0)
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -80,10 +96,73 @@ mod test {
                 config.temperature_max,
                 config.fan_speed_min,
                 config.fan_speed_max,
-            );
+            )
+            .unwrap();
             let fs = map.get(temp);
             prop_assert!(fs >= config.fan_speed_min);
             prop_assert!(fs <= config.fan_speed_max);
         }
+
+        #[test]
+        fn monotonic(config in gen_config(), a in gen_degrees_c(), b in gen_degrees_c()) {
+            let map = FanSpeedMap::new(
+                config.temperature_min,
+                config.temperature_max,
+                config.fan_speed_min,
+                config.fan_speed_max,
+            )
+            .unwrap();
+            prop_assert!(map.get(a.min(b)) <= map.get(a.max(b)));
+        }
+    }
+
+    #[test]
+    fn tenths() {
+        let map = FanSpeedMap::new(
+            DegreesC::new(40),
+            DegreesC::new(60),
+            FanSpeed::MIN,
+            FanSpeed::MAX,
+        )
+        .unwrap();
+        assert_eq!(map.get(DegreesC::new(50)), FanSpeed(50));
+        assert_eq!(map.get(DegreesC::from_tenths(501)), FanSpeed(50));
+        assert_eq!(map.get(DegreesC::from_tenths(502)), FanSpeed(51));
+        assert_eq!(map.get(DegreesC::from_tenths(599)), FanSpeed(99));
+        assert_eq!(map.get(DegreesC::from_tenths(-50)), FanSpeed::MIN);
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        let new = |t_min, t_max, s_min, s_max| {
+            FanSpeedMap::new(
+                DegreesC::new(t_min),
+                DegreesC::new(t_max),
+                FanSpeed(s_min),
+                FanSpeed(s_max),
+            )
+        };
+        assert_eq!(
+            new(50, 50, 0, 100),
+            Err(FanSpeedMapError::InvalidTemperatureRange(
+                DegreesC::new(50),
+                DegreesC::new(50)
+            ))
+        );
+        assert_eq!(
+            new(30, 60, 40, 20),
+            Err(FanSpeedMapError::InvalidFanSpeedRange(
+                FanSpeed(40),
+                FanSpeed(20)
+            ))
+        );
+        assert_eq!(
+            new(30, 60, 0, 101),
+            Err(FanSpeedMapError::InvalidFanSpeed(FanSpeed(101)))
+        );
+        assert_eq!(
+            FanSpeedMapError::InvalidFanSpeedRange(FanSpeed(40), FanSpeed(20)).to_string(),
+            "fan_speed_min (40%) must be below fan_speed_max (20%)"
+        );
     }
 }
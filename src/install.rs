@@ -0,0 +1,292 @@
+use crate::{Config, CONFIG_SYS_PATH, VCIO_DEV};
+use log::{info, warn};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{fs, io};
+
+pub const BIN_SYS_PATH: &str = "/usr/bin/argon-fan-ctl";
+pub const SYSTEMD_UNIT_NAME: &str = "argon-fan-ctl.service";
+pub const SYSTEMD_UNIT_DIR: &str = "/etc/systemd/system";
+pub const SYSTEMD_WANTS_DIR: &str = "/etc/systemd/system/multi-user.target.wants";
+
+#[derive(Debug, err_derive::Error)]
+pub enum InstallError {
+    #[error(display = "Failed to install {:?}, {}", _0, _1)]
+    Io(PathBuf, io::Error),
+
+    #[error(display = "Failed to serialize the default configuration, {}", _0)]
+    Config(#[error(from)] toml::ser::Error),
+}
+
+/// Installs the binary, systemd unit and default configuration file under a root
+/// directory, "/" for the running system or an image staging directory
+#[derive(Clone, Debug)]
+pub struct Installer {
+    root: PathBuf,
+}
+
+impl Installer {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Installer {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn bin_path(&self) -> PathBuf {
+        self.rooted(BIN_SYS_PATH)
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.rooted(CONFIG_SYS_PATH)
+    }
+
+    pub fn unit_path(&self) -> PathBuf {
+        self.rooted(SYSTEMD_UNIT_DIR).join(SYSTEMD_UNIT_NAME)
+    }
+
+    pub fn wants_path(&self) -> PathBuf {
+        self.rooted(SYSTEMD_WANTS_DIR).join(SYSTEMD_UNIT_NAME)
+    }
+
+    /// Copies the executable, writes the systemd unit and a default configuration
+    /// file if none exists, and enables the service
+    pub fn install<P: AsRef<Path>>(&self, exe: P) -> Result<(), InstallError> {
+        let bin = self.bin_path();
+        if same_file(exe.as_ref(), &bin) {
+            info!("Binary already installed at {}", bin.display());
+        } else {
+            create_parent(&bin)?;
+            // Copy to a temporary file first, the binary may be running
+            let tmp = bin.with_extension("new");
+            fs::copy(exe.as_ref(), &tmp).map_err(|e| InstallError::Io(tmp.clone(), e))?;
+            fs::set_permissions(&tmp, fs::Permissions::from_mode(0o755))
+                .map_err(|e| InstallError::Io(tmp.clone(), e))?;
+            fs::rename(&tmp, &bin).map_err(|e| InstallError::Io(bin.clone(), e))?;
+            info!("Installed {}", bin.display());
+        }
+
+        let config = self.config_path();
+        if config.exists() {
+            info!("Keeping existing configuration file {}", config.display());
+        } else {
+            create_parent(&config)?;
+            let content = toml::to_string_pretty(&Config::default())?;
+            fs::write(&config, content.as_bytes())
+                .map_err(|e| InstallError::Io(config.clone(), e))?;
+            info!("Wrote default configuration file {}", config.display());
+        }
+
+        let unit = self.unit_path();
+        create_parent(&unit)?;
+        fs::write(&unit, systemd_unit().as_bytes())
+            .map_err(|e| InstallError::Io(unit.clone(), e))?;
+        info!("Wrote systemd unit {}", unit.display());
+
+        // Equivalent to systemctl enable, which also works for an offline root
+        let wants = self.wants_path();
+        create_parent(&wants)?;
+        remove_if_exists(&wants)?;
+        let target = Path::new(SYSTEMD_UNIT_DIR).join(SYSTEMD_UNIT_NAME);
+        symlink(&target, &wants).map_err(|e| InstallError::Io(wants.clone(), e))?;
+        info!("Enabled {}", SYSTEMD_UNIT_NAME);
+
+        self.systemctl(&["daemon-reload"]);
+        Ok(())
+    }
+
+    /// Disables the service and removes the binary and systemd unit,
+    /// the configuration file is only removed when purging
+    pub fn uninstall(&self, purge: bool) -> Result<(), InstallError> {
+        self.systemctl(&["stop", SYSTEMD_UNIT_NAME]);
+
+        let mut paths = vec![self.wants_path(), self.unit_path(), self.bin_path()];
+        if purge {
+            paths.push(self.config_path());
+        }
+        for path in paths.iter() {
+            if remove_if_exists(path)? {
+                info!("Removed {}", path.display());
+            }
+        }
+        if purge {
+            let config_dir = self.config_path();
+            if let Some(dir) = config_dir.parent() {
+                // Only removed if empty
+                if fs::remove_dir(dir).is_ok() {
+                    info!("Removed {}", dir.display());
+                }
+            }
+        }
+
+        self.systemctl(&["daemon-reload"]);
+        Ok(())
+    }
+
+    fn rooted(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    /// Only applies to the running system
+    fn systemctl(&self, args: &[&str]) {
+        if self.root != Path::new("/") {
+            return;
+        }
+        match Command::new("systemctl").args(args).status() {
+            Ok(status) if status.success() => (),
+            Ok(status) => warn!("systemctl {} failed, {}", args.join(" "), status),
+            Err(e) => warn!("Failed to run systemctl {}, {}", args.join(" "), e),
+        }
+    }
+}
+
+/// Hardened systemd unit, only the I2C, GPIO and VideoCore devices are accessible
+pub fn systemd_unit() -> String {
+    format!(
+        r#"[Unit]
+Description=Argon ONE M.2 fan controller
+After=systemd-modules-load.service
+
+[Service]
+Type=notify
+ExecStart={bin} --config {config} run
+Restart=on-failure
+RestartSec=5
+WatchdogSec=60
+RuntimeDirectory=argon-fan-ctl
+StateDirectory=argon-fan-ctl
+DevicePolicy=closed
+DeviceAllow={vcio} rw
+DeviceAllow=char-i2c rw
+DeviceAllow=/dev/gpiomem rw
+DeviceAllow=char-gpiochip rw
+CapabilityBoundingSet=
+NoNewPrivileges=yes
+ProtectSystem=strict
+ProtectHome=yes
+PrivateTmp=yes
+ProtectClock=yes
+ProtectHostname=yes
+ProtectKernelLogs=yes
+ProtectKernelModules=yes
+ProtectKernelTunables=yes
+ProtectControlGroups=yes
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6
+RestrictNamespaces=yes
+RestrictRealtime=yes
+RestrictSUIDSGID=yes
+LockPersonality=yes
+MemoryDenyWriteExecute=yes
+SystemCallArchitectures=native
+SystemCallFilter=@system-service
+
+[Install]
+WantedBy=multi-user.target
+"#,
+        bin = BIN_SYS_PATH,
+        config = CONFIG_SYS_PATH,
+        vcio = VCIO_DEV,
+    )
+}
+
+fn create_parent(path: &Path) -> Result<(), InstallError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| InstallError::Io(dir.to_path_buf(), e))?;
+    }
+    Ok(())
+}
+
+/// Returns true if the path existed
+fn remove_if_exists(path: &Path) -> Result<bool, InstallError> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(InstallError::Io(path.to_path_buf(), e)),
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fake_exe(dir: &Path) -> PathBuf {
+        let exe = dir.join("argon-fan-ctl");
+        fs::write(&exe, b"#!/bin/sh\n").unwrap();
+        exe
+    }
+
+    #[test]
+    fn install_and_uninstall() {
+        let build_dir = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let exe = fake_exe(build_dir.path());
+        let installer = Installer::new(root.path());
+
+        installer.install(&exe).unwrap();
+        let bin = root.path().join("usr/bin/argon-fan-ctl");
+        assert_eq!(installer.bin_path(), bin);
+        assert_eq!(fs::read(&bin).unwrap(), b"#!/bin/sh\n");
+        assert_eq!(
+            fs::metadata(&bin).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+
+        let config = Config::load(root.path().join("etc/argonone/config.toml")).unwrap();
+        assert_eq!(config, Config::default());
+
+        let unit = fs::read_to_string(root.path().join("etc/systemd/system/argon-fan-ctl.service"))
+            .unwrap();
+        assert_eq!(unit, systemd_unit());
+        assert!(unit.contains("Type=notify"));
+        assert!(unit
+            .contains("ExecStart=/usr/bin/argon-fan-ctl --config /etc/argonone/config.toml run"));
+        assert!(unit.contains("DeviceAllow=/dev/vcio rw"));
+        assert!(unit.contains("DeviceAllow=char-i2c rw"));
+        // PWM fans and tachometers
+        assert!(unit.contains("DeviceAllow=/dev/gpiomem rw"));
+        assert!(unit.contains("DeviceAllow=char-gpiochip rw"));
+
+        let wants = root
+            .path()
+            .join("etc/systemd/system/multi-user.target.wants/argon-fan-ctl.service");
+        assert_eq!(
+            fs::read_link(&wants).unwrap(),
+            Path::new("/etc/systemd/system/argon-fan-ctl.service")
+        );
+
+        // Reinstalling is fine
+        installer.install(&exe).unwrap();
+
+        installer.uninstall(false).unwrap();
+        assert!(!bin.exists());
+        assert!(!installer.unit_path().exists());
+        assert!(fs::symlink_metadata(&wants).is_err());
+        assert!(installer.config_path().exists());
+
+        installer.uninstall(true).unwrap();
+        assert!(!installer.config_path().exists());
+        assert!(!root.path().join("etc/argonone").exists());
+    }
+
+    #[test]
+    fn keeps_existing_config() {
+        let build_dir = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let exe = fake_exe(build_dir.path());
+        let installer = Installer::new(root.path());
+
+        let config_path = installer.config_path();
+        fs::create_dir_all(config_path.parent().unwrap()).unwrap();
+        fs::write(&config_path, b"# custom\n").unwrap();
+
+        installer.install(&exe).unwrap();
+        assert_eq!(fs::read(&config_path).unwrap(), b"# custom\n");
+    }
+}
@@ -0,0 +1,598 @@
+use crate::config::locate_key;
+use crate::{
+    migrate_config, Config, ConfigLoadError, DegreesC, Temperature, TemperatureUnit,
+    UnitTemperature, CONFIG_VERSION,
+};
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use toml::value::{Table, Value};
+
+/// Drop-in directory, next to the configuration file
+pub const CONFIG_DROP_IN_DIR: &str = "config.d";
+
+/// Environment variables overriding configuration keys, "__" separates nested keys,
+/// e.g. ARGON_MQTT__HOST sets mqtt.host
+pub const CONFIG_ENV_PREFIX: &str = "ARGON_";
+
+/// Temperatures read in the configured unit, unless they have a C or F suffix
+const TEMPERATURE_KEYS: [&str; 3] = ["temperature_min", "temperature_max", "safety_temperature"];
+/// Likewise, temperature differences
+const TEMPERATURE_DIFFERENCE_KEYS: [&str; 3] = ["hysteresis", "rise_rate", "top_margin"];
+
+/// Where a configuration value came from
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum ConfigSource {
+    Default,
+    File(PathBuf),
+    Env(String),
+    Cli,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => f.write_str("default"),
+            ConfigSource::File(p) => write!(f, "{}", p.display()),
+            ConfigSource::Env(name) => write!(f, "env {}", name),
+            ConfigSource::Cli => f.write_str("--set"),
+        }
+    }
+}
+
+/// Built-in defaults, then the configuration file, then the drop-in files in name
+/// order, then environment variables, then `key=value` overrides from the CLI.
+/// Tables are merged key by key, any other value replaces the previous one.
+#[derive(Clone, Debug)]
+pub struct ConfigLayers {
+    path: PathBuf,
+    drop_in_dir: Option<PathBuf>,
+    env: Vec<(String, String)>,
+    overrides: Vec<String>,
+}
+
+/// The merged configuration, and where each value came from
+#[derive(Clone, Debug)]
+pub struct EffectiveConfig {
+    pub config: Config,
+    /// Version of the configuration file, before migration
+    pub version: u32,
+    /// Sources of the values that didn't come from the defaults, by dotted key
+    pub sources: BTreeMap<String, ConfigSource>,
+}
+
+impl ConfigLayers {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let drop_in_dir = path.parent().map(|p| p.join(CONFIG_DROP_IN_DIR));
+        ConfigLayers {
+            path,
+            drop_in_dir,
+            env: Vec::new(),
+            overrides: Vec::new(),
+        }
+    }
+
+    /// None disables drop-in files
+    pub fn with_drop_in_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.drop_in_dir = dir;
+        self
+    }
+
+    /// Only the variables starting with CONFIG_ENV_PREFIX are used
+    pub fn with_env<I: IntoIterator<Item = (String, String)>>(mut self, vars: I) -> Self {
+        self.env = vars
+            .into_iter()
+            .filter(|(k, _)| k.starts_with(CONFIG_ENV_PREFIX))
+            .collect();
+        self.env.sort();
+        self
+    }
+
+    /// `key=value` entries, applied in order
+    pub fn with_overrides(mut self, overrides: &[String]) -> Self {
+        self.overrides = overrides.to_vec();
+        self
+    }
+
+    pub fn load(&self) -> Result<EffectiveConfig, ConfigLoadError> {
+        let mut merged = Value::try_from(Config::default())?;
+        let mut sources = BTreeMap::new();
+        let mut contents = BTreeMap::new();
+
+        let content = fs::read_to_string(&self.path)
+            .map_err(|e| ConfigLoadError::Io(self.path.clone(), e))?;
+        let mut value = self.parse_file(&self.path, &content)?;
+        let version = migrate_config(&mut value)
+            .map_err(|e| ConfigLoadError::Migration(self.path.clone(), e))?;
+        if version != CONFIG_VERSION {
+            warn!(
+                "Configuration file {} is version {}, loaded as version {}, \
+                 run config migrate to update the file",
+                self.path.display(),
+                version,
+                CONFIG_VERSION
+            );
+        }
+        merge(
+            &mut merged,
+            value,
+            "",
+            &ConfigSource::File(self.path.clone()),
+            &mut sources,
+        );
+        contents.insert(self.path.clone(), content);
+
+        for path in self.drop_ins()? {
+            debug!("Merging drop-in configuration file {}", path.display());
+            let content =
+                fs::read_to_string(&path).map_err(|e| ConfigLoadError::Io(path.clone(), e))?;
+            let value = self.parse_file(&path, &content)?;
+            merge(
+                &mut merged,
+                value,
+                "",
+                &ConfigSource::File(path.clone()),
+                &mut sources,
+            );
+            contents.insert(path, content);
+        }
+
+        for (name, raw) in self.env.iter() {
+            let key = name[CONFIG_ENV_PREFIX.len()..]
+                .to_lowercase()
+                .replace("__", ".");
+            set_key(&mut merged, &key, parse_value(raw))
+                .ok_or_else(|| ConfigLoadError::InvalidOverride(format!("{}={}", name, raw)))?;
+            sources.insert(key, ConfigSource::Env(name.clone()));
+        }
+
+        for entry in self.overrides.iter() {
+            let invalid = || ConfigLoadError::InvalidOverride(entry.clone());
+            let mut parts = entry.splitn(2, '=');
+            let key = parts.next().map(str::trim).filter(|k| !k.is_empty());
+            let (key, raw) = key.zip(parts.next()).ok_or_else(invalid)?;
+            set_key(&mut merged, key, parse_value(raw.trim())).ok_or_else(invalid)?;
+            sources.insert(key.to_string(), ConfigSource::Cli);
+        }
+
+        let units = merged
+            .get("units")
+            .and_then(Value::as_str)
+            .and_then(|u| u.parse().ok())
+            .unwrap_or_default();
+        for key in sources.keys() {
+            temperature_to_celsius(&mut merged, key, units);
+        }
+
+        let config: Config = merged.try_into().map_err(ConfigLoadError::Merged)?;
+        if let Err(mut report) = config.check() {
+            for issue in report.issues.iter_mut() {
+                let source = source_of(&sources, &issue.key);
+                if let ConfigSource::File(p) = &source {
+                    issue.location = contents.get(p).and_then(|c| locate_key(c, &issue.key));
+                }
+                if source != ConfigSource::File(self.path.clone()) {
+                    issue.origin = Some(source.to_string());
+                }
+            }
+            return Err(ConfigLoadError::Check(self.path.clone(), report));
+        }
+        Ok(EffectiveConfig {
+            config,
+            version,
+            sources,
+        })
+    }
+
+    fn parse_file(&self, path: &Path, content: &str) -> Result<Value, ConfigLoadError> {
+        toml::from_str(content).map_err(|e| ConfigLoadError::Invalid(path.to_path_buf(), e))
+    }
+
+    /// The *.toml files in the drop-in directory, sorted by name
+    fn drop_ins(&self) -> Result<Vec<PathBuf>, ConfigLoadError> {
+        let dir = match &self.drop_in_dir {
+            Some(d) => d,
+            None => return Ok(Vec::new()),
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(ConfigLoadError::Io(dir.clone(), e)),
+        };
+        let mut paths = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|e| ConfigLoadError::Io(dir.clone(), e))?
+                .path();
+            if path.is_file() && path.extension().map(|e| e == "toml").unwrap_or(false) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+impl EffectiveConfig {
+    pub fn source(&self, key: &str) -> ConfigSource {
+        source_of(&self.sources, key)
+    }
+
+    /// One `key = value` line per value, with its source
+    pub fn show(&self) -> String {
+        let mut lines = Vec::new();
+        if let Ok(mut value) = Value::try_from(&self.config) {
+            temperatures_in_unit(&mut value, self.config.units);
+            flatten(&value, "", &mut lines);
+        }
+        let width = lines.iter().map(|(k, v)| k.len() + v.len() + 3).max();
+        let mut out = String::new();
+        for (key, value) in lines.iter() {
+            let line = format!("{} = {}", key, value);
+            out.push_str(&format!(
+                "{:<w$}  # {}\n",
+                line,
+                self.source(key),
+                w = width.unwrap_or(0)
+            ));
+        }
+        out
+    }
+}
+
+/// Merges `layer` into `base`, recording the source of each value set
+fn merge(
+    base: &mut Value,
+    layer: Value,
+    prefix: &str,
+    source: &ConfigSource,
+    sources: &mut BTreeMap<String, ConfigSource>,
+) {
+    match (base, layer) {
+        (Value::Table(base), Value::Table(layer)) => {
+            for (k, v) in layer.into_iter() {
+                let key = join(prefix, &k);
+                match base.get_mut(&k) {
+                    Some(b) if b.is_table() && v.is_table() => merge(b, v, &key, source, sources),
+                    _ => {
+                        record(&v, &key, source, sources);
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, layer) => {
+            record(&layer, prefix, source, sources);
+            *base = layer;
+        }
+    }
+}
+
+fn record(
+    value: &Value,
+    key: &str,
+    source: &ConfigSource,
+    sources: &mut BTreeMap<String, ConfigSource>,
+) {
+    // A replaced table no longer has values from earlier layers
+    let stale: Vec<String> = sources
+        .keys()
+        .filter(|k| k.starts_with(&format!("{}.", key)))
+        .cloned()
+        .collect();
+    for k in stale {
+        sources.remove(&k);
+    }
+    match value {
+        Value::Table(t) => {
+            for (k, v) in t.iter() {
+                record(v, &join(key, k), source, sources);
+            }
+        }
+        _ => {
+            sources.insert(key.to_string(), source.clone());
+        }
+    }
+}
+
+/// Sets a dotted key, creating tables as needed. None if a parent isn't a table.
+fn set_key(root: &mut Value, key: &str, value: Value) -> Option<()> {
+    let mut table: &mut Table = root.as_table_mut()?;
+    let mut parts = key.split('.').peekable();
+    while let Some(part) = parts.next() {
+        if part.is_empty() {
+            return None;
+        }
+        if parts.peek().is_none() {
+            table.insert(part.to_string(), value);
+            return Some(());
+        }
+        table = table
+            .entry(part.to_string())
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()?;
+    }
+    None
+}
+
+fn get_key_mut<'a>(root: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    key.split('.')
+        .try_fold(root, |v, part| v.as_table_mut()?.get_mut(part))
+}
+
+/// Converts a temperature key written in `unit`, or with a C or F suffix, to Celsius.
+/// Invalid values are left for deserialization to report.
+fn temperature_to_celsius(root: &mut Value, key: &str, unit: TemperatureUnit) {
+    let name = key.rsplit('.').next().unwrap_or(key);
+    let difference = TEMPERATURE_DIFFERENCE_KEYS.contains(&name);
+    if !difference && !TEMPERATURE_KEYS.contains(&name) {
+        return;
+    }
+    let value = match get_key_mut(root, key) {
+        Some(v) => v,
+        None => return,
+    };
+    let raw = match value {
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::String(s) => s.clone(),
+        _ => return,
+    };
+    if let Ok(t) = raw.parse::<Temperature>() {
+        let c = if difference {
+            t.difference_to_celsius(unit)
+        } else {
+            t.to_celsius(unit)
+        };
+        if let Ok(v) = Value::try_from(c) {
+            *value = v;
+        }
+    }
+}
+
+/// Converts the temperatures of a serialized configuration from Celsius to `unit`
+pub(crate) fn temperatures_in_unit(value: &mut Value, unit: TemperatureUnit) {
+    if unit == TemperatureUnit::Celsius {
+        return;
+    }
+    let table = match value.as_table_mut() {
+        Some(t) => t,
+        None => return,
+    };
+    for (k, v) in table.iter_mut() {
+        if v.is_table() {
+            temperatures_in_unit(v, unit);
+            continue;
+        }
+        let difference = TEMPERATURE_DIFFERENCE_KEYS.contains(&k.as_str());
+        if !difference && !TEMPERATURE_KEYS.contains(&k.as_str()) {
+            continue;
+        }
+        if let Ok(c) = v.clone().try_into::<DegreesC>() {
+            let value = if difference {
+                unit.difference_from_celsius(c)
+            } else {
+                unit.from_celsius(c)
+            };
+            if let Ok(converted) = Value::try_from(UnitTemperature { value, unit }) {
+                *v = converted;
+            }
+        }
+    }
+}
+
+/// TOML values as written in a file, anything else is a string
+fn parse_value(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("v = {}", raw))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+/// The source of a key, of the values within a table key, or of the array it's in
+fn source_of(sources: &BTreeMap<String, ConfigSource>, key: &str) -> ConfigSource {
+    if let Some(s) = sources.get(key) {
+        return s.clone();
+    }
+    if let Some((_, s)) = sources
+        .range(format!("{}.", key)..)
+        .take_while(|(k, _)| k.starts_with(&format!("{}.", key)))
+        .next()
+    {
+        return s.clone();
+    }
+    let mut key = key;
+    while let Some(i) = key.rfind(['.', '['].as_ref()) {
+        key = &key[..i];
+        if let Some(s) = sources.get(key) {
+            return s.clone();
+        }
+    }
+    ConfigSource::Default
+}
+
+fn flatten(value: &Value, prefix: &str, out: &mut Vec<(String, String)>) {
+    match value {
+        Value::Table(t) => {
+            for (k, v) in t.iter() {
+                flatten(v, &join(prefix, k), out);
+            }
+        }
+        v => out.push((prefix.to_string(), inline(v))),
+    }
+}
+
+/// A value on a single line, tables as inline tables
+fn inline(value: &Value) -> String {
+    match value {
+        Value::Array(a) => format!("[{}]", a.iter().map(inline).collect::<Vec<_>>().join(", ")),
+        Value::Table(t) => format!(
+            "{{ {} }}",
+            t.iter()
+                .map(|(k, v)| format!("{} = {}", k, inline(v)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        v => v.to_string(),
+    }
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ConfigCheckError, FanSpeedMapError};
+
+    #[test]
+    fn layer_order_and_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            "version = 1\nupdate_interval_seconds = 30\ntemperature_min = 40\n\n[mqtt]\nhost = \"a.local\"\nclient_id = \"pi\"\n",
+        )
+        .unwrap();
+        let drop_ins = dir.path().join(CONFIG_DROP_IN_DIR);
+        fs::create_dir(&drop_ins).unwrap();
+        fs::write(drop_ins.join("20-b.toml"), "temperature_min = 42\n").unwrap();
+        fs::write(
+            drop_ins.join("10-a.toml"),
+            "temperature_min = 41\ntemperature_max = 70\n[mqtt]\nhost = \"b.local\"\n",
+        )
+        .unwrap();
+        fs::write(drop_ins.join("README"), "not toml").unwrap();
+        let env = vec![
+            ("ARGON_FAN_SPEED_MIN".to_string(), "10".to_string()),
+            ("ARGON_MQTT__HOST".to_string(), "c.local".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let layers = ConfigLayers::new(&path)
+            .with_env(env)
+            .with_overrides(&["fan_speed_min = 20".to_string()]);
+        let e = layers.load().unwrap();
+
+        assert_eq!(e.config.update_interval_seconds.to_string(), "30s");
+        assert_eq!(e.config.temperature_min, 42.into());
+        assert_eq!(e.config.temperature_max, 70.into());
+        assert_eq!(u8::from(e.config.fan_speed_min), 20);
+        let mqtt = e.config.mqtt.as_ref().unwrap();
+        assert_eq!(mqtt.host, "c.local");
+        assert_eq!(mqtt.client_id, "pi");
+
+        let file = |name: &str| ConfigSource::File(dir.path().join(name));
+        assert_eq!(e.source("update_interval_seconds"), file("config.toml"));
+        assert_eq!(e.source("temperature_min"), file("config.d/20-b.toml"));
+        assert_eq!(e.source("temperature_max"), file("config.d/10-a.toml"));
+        assert_eq!(e.source("fan_speed_min"), ConfigSource::Cli);
+        assert_eq!(
+            e.source("mqtt.host"),
+            ConfigSource::Env("ARGON_MQTT__HOST".to_string())
+        );
+        assert_eq!(e.source("mqtt.client_id"), file("config.toml"));
+        assert_eq!(e.source("fan_speed_max"), ConfigSource::Default);
+        assert_eq!(e.source("mqtt.port"), ConfigSource::Default);
+
+        let show = e.show();
+        assert!(show.contains("fan_speed_max = 100"));
+        assert_eq!(
+            inline(&parse_value(r#"[{ profile = "quiet", days = ["mon"] }]"#)),
+            r#"[{ days = ["mon"], profile = "quiet" }]"#
+        );
+        assert!(show
+            .lines()
+            .any(|l| l.starts_with("mqtt.host = \"c.local\"")
+                && l.ends_with("# env ARGON_MQTT__HOST")));
+        assert!(show.lines().any(|l| l.starts_with("temperature_min = 42")
+            && l.ends_with(&format!("# {}", drop_ins.join("20-b.toml").display()))));
+
+        // Without drop-ins, and an invalid override
+        let e = ConfigLayers::new(&path)
+            .with_drop_in_dir(None)
+            .load()
+            .unwrap();
+        assert_eq!(e.config.temperature_min, 40.into());
+        assert!(matches!(
+            ConfigLayers::new(&path)
+                .with_overrides(&["temperature_min".to_string()])
+                .load(),
+            Err(ConfigLoadError::InvalidOverride(_))
+        ));
+    }
+
+    #[test]
+    fn issue_origins() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "update_interval_seconds = 30\n").unwrap();
+        let drop_ins = dir.path().join(CONFIG_DROP_IN_DIR);
+        fs::create_dir(&drop_ins).unwrap();
+        fs::write(drop_ins.join("quiet.toml"), "\nfan_speed_max = 0\n").unwrap();
+        let env = vec![("ARGON_TEMPERATURE_MAX".to_string(), "20".to_string())];
+        let report = match ConfigLayers::new(&path).with_env(env).load() {
+            Err(ConfigLoadError::Check(_, report)) => report,
+            r => panic!("Unexpected {:?}", r),
+        };
+        assert_eq!(
+            report.issues[0].error,
+            ConfigCheckError::FanSpeedMap(FanSpeedMapError::InvalidTemperatureRange(
+                33.into(),
+                20.into()
+            ))
+        );
+        assert_eq!(
+            report.to_string(),
+            format!(
+                "  temperature_max: temperature_min (33 C) must be below temperature_max (20 C) (env ARGON_TEMPERATURE_MAX)\n  \
+                 fan_speed_max: fan_speed_min (0%) must be below fan_speed_max (0%) ({}, line 2, column 1)",
+                drop_ins.join("quiet.toml").display()
+            )
+        );
+    }
+
+    #[test]
+    fn fahrenheit_thresholds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            "version = 1\nupdate_interval_seconds = 30\nunits = \"fahrenheit\"\n\
+             temperature_min = 95\ntemperature_max = \"65C\"\nhysteresis = 4.5\n\
+             fan_speed_min = 0\nfan_speed_max = 100\n\
+             [adaptive_interval]\nmin_interval_seconds = 5\nmax_interval_seconds = 60\n",
+        )
+        .unwrap();
+        let e = ConfigLayers::new(&path)
+            .with_overrides(&["safety_temperature=170F".to_string()])
+            .load()
+            .unwrap();
+        let c = &e.config;
+        assert_eq!(c.temperature_min, DegreesC::new(35));
+        assert_eq!(c.temperature_max, DegreesC::new(65));
+        assert_eq!(c.hysteresis, DegreesC::from_tenths(25));
+        assert_eq!(c.safety_temperature, Some(DegreesC::from_tenths(767)));
+        // The defaults are in Celsius
+        assert_eq!(c.adaptive_interval.unwrap().top_margin, DegreesC::new(5));
+
+        let show = e.show();
+        assert!(show.contains("temperature_max = 149 "));
+        assert!(show.contains("hysteresis = 4.5 "));
+        assert!(show.contains("adaptive_interval.top_margin = 9 "));
+        let written = c.to_toml_string().unwrap();
+        assert!(written.contains("temperature_min = 95\n"));
+        fs::write(&path, written).unwrap();
+        let reread = ConfigLayers::new(&path).load().unwrap().config;
+        assert_eq!(reread.temperature_max, c.temperature_max);
+        assert_eq!(reread.hysteresis, c.hysteresis);
+    }
+}
@@ -2,19 +2,71 @@
 
 use num::clamp;
 use serde::{Deserialize, Serialize};
-use std::num::{NonZeroU32, ParseIntError};
+use std::num::{NonZeroU32, ParseFloatError, ParseIntError};
 use std::time::Duration;
 use std::{fmt, str::FromStr};
 
+mod calibrate;
+mod clock;
 mod config;
+mod control;
+mod controller;
+mod curve;
+mod daemon;
+mod fan;
+mod fan_override;
 mod fan_speed_map;
+mod install;
+mod layers;
+mod load;
 mod mailbox;
+mod migration;
+mod monitor;
+mod mqtt;
+mod output;
+mod profile;
 mod scheduler;
-
+mod sensor;
+mod sim;
+mod state;
+mod systemd;
+mod tach;
+mod telemetry;
+mod throttle;
+mod trace;
+mod tune;
+mod units;
+
+pub use calibrate::*;
+pub use clock::*;
 pub use config::*;
+pub use control::*;
+pub use controller::*;
+pub use curve::*;
+pub use daemon::*;
+pub use fan::*;
+pub use fan_override::*;
 pub use fan_speed_map::*;
+pub use install::*;
+pub use layers::*;
+pub use load::*;
 pub use mailbox::*;
+pub use migration::*;
+pub use monitor::*;
+pub use mqtt::*;
+pub use output::*;
+pub use profile::*;
 pub use scheduler::*;
+pub use sensor::*;
+pub use sim::*;
+pub use state::*;
+pub use systemd::*;
+pub use tach::*;
+pub use telemetry::*;
+pub use throttle::*;
+pub use trace::*;
+pub use tune::*;
+pub use units::*;
 
 pub const VCIO_DEV: &str = "/dev/vcio";
 pub const I2C_BUS: u8 = 1;
@@ -148,43 +200,128 @@ impl FromStr for FanSpeed {
     }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
-pub struct DegreesC(pub u8);
+#[derive(Debug, Clone, PartialEq, err_derive::Error)]
+pub enum ParseDegreesCError {
+    #[error(display = "Failed to parse temperature {}", _0)]
+    ParseFloatError(#[error(from)] ParseFloatError),
+
+    #[error(display = "Invalid temperature {}", _0)]
+    Invalid(String),
+}
+
+/// Temperature in degrees C, in tenths of a degree. Whole degrees serialize as
+/// integers, like the configuration files written before tenths were supported.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub struct DegreesC(i16);
 
 impl DegreesC {
-    pub const MAX: Self = DegreesC(std::u8::MAX);
-    pub const MIN: Self = DegreesC(0);
+    pub const MAX: Self = DegreesC(i16::MAX);
+    pub const MIN: Self = DegreesC(i16::MIN);
+
+    /// Whole degrees
+    pub const fn new(degrees: i16) -> Self {
+        DegreesC(degrees.saturating_mul(10))
+    }
+
+    pub const fn from_tenths(tenths: i16) -> Self {
+        DegreesC(tenths)
+    }
 
+    pub const fn tenths(self) -> i16 {
+        self.0
+    }
+
+    /// Rounds to the nearest tenth, saturating at MIN and MAX
     pub fn from_f32(t: f32) -> Self {
-        let int_t = clamp(t, u8::MIN as f32, u8::MAX as f32) as u8;
-        DegreesC(int_t)
+        Self::from_f64(f64::from(t))
     }
-}
 
-impl From<u8> for DegreesC {
-    fn from(t: u8) -> Self {
-        DegreesC(t)
+    pub fn from_f64(t: f64) -> Self {
+        DegreesC(clamp((t * 10.0).round(), f64::from(i16::MIN), f64::from(i16::MAX)) as i16)
+    }
+
+    pub fn as_f32(self) -> f32 {
+        f32::from(self.0) / 10.0
+    }
+
+    pub fn as_f64(self) -> f64 {
+        f64::from(self.0) / 10.0
+    }
+
+    pub fn saturating_add(self, other: Self) -> Self {
+        DegreesC(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Self) -> Self {
+        DegreesC(self.0.saturating_sub(other.0))
     }
 }
 
-impl From<DegreesC> for u8 {
-    fn from(t: DegreesC) -> Self {
-        t.0
+impl From<u8> for DegreesC {
+    fn from(t: u8) -> Self {
+        DegreesC::new(i16::from(t))
     }
 }
 
 impl fmt::Display for DegreesC {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} C", self.0)
+        if self.0 % 10 == 0 {
+            write!(f, "{} C", self.0 / 10)
+        } else {
+            write!(f, "{:.1} C", self.as_f64())
+        }
     }
 }
 
+/// A C or F suffix is optional, Celsius without one
 impl FromStr for DegreesC {
-    type Err = ParseIntError;
+    type Err = ParseDegreesCError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let num = s.trim().parse::<u8>()?;
-        Ok(DegreesC(num))
+        DegreesC::parse_in(s, TemperatureUnit::Celsius)
+    }
+}
+
+impl Serialize for DegreesC {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.0 % 10 == 0 {
+            serializer.serialize_i16(self.0 / 10)
+        } else {
+            serializer.serialize_f64(self.as_f64())
+        }
+    }
+}
+
+/// From a number, or a string with a C or F suffix
+impl<'de> Deserialize<'de> for DegreesC {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = DegreesC;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a temperature, e.g. 65, 65.5 or \"150F\"")
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<DegreesC, E> {
+                self.visit_f64(v as f64)
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<DegreesC, E> {
+                self.visit_f64(v as f64)
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<DegreesC, E> {
+                self.visit_str(&v.to_string())
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<DegreesC, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
     }
 }
 
@@ -253,13 +390,13 @@ pub(crate) mod test {
     }
 
     prop_compose! {
-        pub(crate) fn gen_degrees_c()(val in proptest::num::u8::ANY) -> DegreesC {
-            DegreesC(val)
+        pub(crate) fn gen_degrees_c()(tenths in -400..=1500i16) -> DegreesC {
+            DegreesC(tenths)
         }
     }
 
     prop_compose! {
-        pub(crate) fn gen_update_interval_seconds()(val in 1..=std::u32::MAX) -> UpdateIntervalSeconds {
+        pub(crate) fn gen_update_interval_seconds()(val in 1..=u32::MAX) -> UpdateIntervalSeconds {
             UpdateIntervalSeconds(NonZeroU32::new(val).unwrap())
         }
     }
@@ -304,8 +441,11 @@ pub(crate) mod test {
     proptest! {
         #[test]
         fn degrees_c_from_str(t in gen_degrees_c()) {
-            let s = format!("{}", t.0);
+            let s = format!("{:.1}", t.as_f64());
             prop_assert_eq!(DegreesC::from_str(&s), Ok(t));
+            let s = toml::to_string(&[("t", t)].iter().cloned().collect::<std::collections::BTreeMap<_, _>>()).unwrap();
+            let back: std::collections::BTreeMap<String, DegreesC> = toml::from_str(&s).unwrap();
+            prop_assert_eq!(back["t"], t);
         }
     }
 
@@ -313,16 +453,27 @@ pub(crate) mod test {
         #[test]
         fn degrees_c_from_f32(t_f in proptest::num::f32::ANY) {
             let t = DegreesC::from_f32(t_f);
-            if t_f <= 0.0 {
+            if t_f <= -3276.8 {
                 prop_assert_eq!(t, DegreesC::MIN);
-            } else if t_f >= 255.0 {
+            } else if t_f >= 3276.7 {
                 prop_assert_eq!(t, DegreesC::MAX);
-            } else {
-                prop_assert_eq!(t, DegreesC(t_f as u8));
+            } else if t_f.is_finite() {
+                prop_assert!((t.as_f64() - f64::from(t_f)).abs() <= 0.05 + 1e-6);
             }
         }
     }
 
+    #[test]
+    fn degrees_c_tenths() {
+        assert_eq!(DegreesC::from_f32(64.96), DegreesC::new(65));
+        assert_eq!(DegreesC::from_f32(64.94).to_string(), "64.9 C");
+        assert_eq!(DegreesC::from_f32(-5.25).to_string(), "-5.3 C");
+        assert_eq!(DegreesC::new(40).to_string(), "40 C");
+        assert_eq!("-0.5".parse::<DegreesC>(), Ok(DegreesC(-5)));
+        assert!("hot".parse::<DegreesC>().is_err());
+        assert!("1e9".parse::<DegreesC>().is_err());
+    }
+
     proptest! {
         #[test]
         fn update_interval_seconds_from_str(i in gen_update_interval_seconds()) {
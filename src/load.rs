@@ -0,0 +1,240 @@
+use crate::FanSpeed;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+pub const PROC_ROOT: &str = "/proc";
+
+#[derive(Debug, err_derive::Error)]
+pub enum LoadError {
+    #[error(display = "Failed to read {:?}, {}", _0, _1)]
+    Io(PathBuf, io::Error),
+
+    #[error(display = "Failed to parse {:?}", _0)]
+    Parse(PathBuf),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct FeedForwardConfig {
+    /// CPU utilisation percentage the boost starts ramping up from
+    #[serde(default = "FeedForwardConfig::default_utilisation_min")]
+    pub utilisation_min: u8,
+    /// CPU utilisation percentage at and above which the full boost applies
+    #[serde(default = "FeedForwardConfig::default_utilisation_max")]
+    pub utilisation_max: u8,
+    /// Fan speed percentage added at full utilisation
+    #[serde(default = "FeedForwardConfig::default_boost")]
+    pub boost: FanSpeed,
+    /// Also use the 1 minute load average per CPU, whichever is higher
+    #[serde(default)]
+    pub load_average: bool,
+}
+
+impl FeedForwardConfig {
+    fn default_utilisation_min() -> u8 {
+        50
+    }
+
+    fn default_utilisation_max() -> u8 {
+        90
+    }
+
+    fn default_boost() -> FanSpeed {
+        FanSpeed(30)
+    }
+}
+
+impl Default for FeedForwardConfig {
+    fn default() -> Self {
+        FeedForwardConfig {
+            utilisation_min: Self::default_utilisation_min(),
+            utilisation_max: Self::default_utilisation_max(),
+            boost: Self::default_boost(),
+            load_average: false,
+        }
+    }
+}
+
+/// Aggregate CPU time counters, in clock ticks
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+struct CpuTimes {
+    busy: u64,
+    total: u64,
+}
+
+/// Reads CPU utilisation and load average from a procfs root
+#[derive(Clone, Debug)]
+pub struct CpuLoad {
+    root: PathBuf,
+    prev: CpuTimes,
+}
+
+impl CpuLoad {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        CpuLoad {
+            root: root.as_ref().to_path_buf(),
+            prev: CpuTimes::default(),
+        }
+    }
+
+    /// Utilisation in 0..=1 since the previous call, or since boot on the first call
+    pub fn utilisation(&mut self) -> Result<f32, LoadError> {
+        let (times, _) = self.read_stat()?;
+        let busy = times.busy.saturating_sub(self.prev.busy);
+        let total = times.total.saturating_sub(self.prev.total);
+        self.prev = times;
+        if total == 0 {
+            Ok(0.0)
+        } else {
+            Ok(busy as f32 / total as f32)
+        }
+    }
+
+    /// The 1 minute load average divided by the number of CPUs
+    pub fn load_average(&self) -> Result<f32, LoadError> {
+        let (_, cpus) = self.read_stat()?;
+        let path = self.root.join("loadavg");
+        let content = fs::read_to_string(&path).map_err(|e| LoadError::Io(path.clone(), e))?;
+        let load: f32 = content
+            .split_whitespace()
+            .next()
+            .and_then(|l| l.parse().ok())
+            .ok_or(LoadError::Parse(path))?;
+        Ok(load / cpus.max(1) as f32)
+    }
+
+    /// The aggregate "cpu" line and the number of "cpuN" lines
+    fn read_stat(&self) -> Result<(CpuTimes, usize), LoadError> {
+        let path = self.root.join("stat");
+        let content = fs::read_to_string(&path).map_err(|e| LoadError::Io(path.clone(), e))?;
+        let mut times = None;
+        let mut cpus = 0;
+        for line in content.lines() {
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("cpu") => {
+                    // user nice system idle iowait irq softirq steal, guest time is
+                    // already included in user
+                    let values: Vec<u64> = fields.take(8).filter_map(|v| v.parse().ok()).collect();
+                    if values.len() < 4 {
+                        return Err(LoadError::Parse(path));
+                    }
+                    let total: u64 = values.iter().sum();
+                    let idle = values[3] + values.get(4).copied().unwrap_or(0);
+                    times = Some(CpuTimes {
+                        busy: total - idle,
+                        total,
+                    });
+                }
+                Some(f) if f.starts_with("cpu") => cpus += 1,
+                _ => (),
+            }
+        }
+        times.map(|t| (t, cpus)).ok_or(LoadError::Parse(path))
+    }
+}
+
+/// Fan speed boost from the CPU load, which rises well before the temperature does
+#[derive(Clone, Debug)]
+pub struct FeedForward {
+    config: FeedForwardConfig,
+    load: CpuLoad,
+}
+
+impl FeedForward {
+    pub fn new<P: AsRef<Path>>(config: FeedForwardConfig, proc_root: P) -> Self {
+        FeedForward {
+            config,
+            load: CpuLoad::new(proc_root),
+        }
+    }
+
+    /// Ramps from nothing at utilisation_min to the full boost at utilisation_max
+    pub fn boost(&mut self) -> Result<FanSpeed, LoadError> {
+        let mut load = self.load.utilisation()?;
+        if self.config.load_average {
+            load = load.max(self.load.load_average()?);
+        }
+        let min = f32::from(self.config.utilisation_min);
+        let max = f32::from(self.config.utilisation_max);
+        let level = ((load * 100.0 - min) / (max - min)).clamp(0.0, 1.0);
+        let boost = (level * f32::from(self.config.boost.0)).round() as u8;
+        Ok(FanSpeed::new_unchecked(boost))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_stat(root: &Path, busy: u64, idle: u64) {
+        // Split across fields to cover the summing
+        fs::write(
+            root.join("stat"),
+            format!(
+                "cpu  {} 0 {} {} {} 0 0 0 0 0\ncpu0 0 0 0 0 0 0 0 0 0 0\ncpu1 0 0 0 0 0 0 0 0 0 0\nintr 1 2 3\n",
+                busy / 2,
+                busy - busy / 2,
+                idle / 2,
+                idle - idle / 2
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn utilisation_and_load_average() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut load = CpuLoad::new(dir.path());
+        assert!(matches!(load.utilisation(), Err(LoadError::Io(_, _))));
+
+        write_stat(dir.path(), 100, 300);
+        assert_eq!(load.utilisation().unwrap(), 0.25);
+        write_stat(dir.path(), 190, 310);
+        assert_eq!(load.utilisation().unwrap(), 0.9);
+        assert_eq!(load.utilisation().unwrap(), 0.0);
+
+        fs::write(dir.path().join("loadavg"), "3.00 1.50 0.75 2/389 12345\n").unwrap();
+        assert_eq!(load.load_average().unwrap(), 1.5);
+
+        fs::write(dir.path().join("stat"), "cpu  1 2\n").unwrap();
+        assert!(matches!(load.utilisation(), Err(LoadError::Parse(_))));
+    }
+
+    #[test]
+    fn boost_ramp() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FeedForwardConfig {
+            utilisation_min: 50,
+            utilisation_max: 90,
+            boost: FanSpeed(40),
+            load_average: false,
+        };
+        let mut ff = FeedForward::new(config, dir.path());
+        let mut busy = 0;
+        let mut idle = 0;
+        let mut boost_at = |percent: u64| {
+            busy += percent;
+            idle += 100 - percent;
+            write_stat(dir.path(), busy, idle);
+            u8::from(ff.boost().unwrap())
+        };
+        assert_eq!(boost_at(10), 0);
+        assert_eq!(boost_at(50), 0);
+        assert_eq!(boost_at(70), 20);
+        assert_eq!(boost_at(90), 40);
+        assert_eq!(boost_at(100), 40);
+
+        // A high load average boosts even with idle CPUs
+        let mut ff = FeedForward::new(
+            FeedForwardConfig {
+                load_average: true,
+                ..config
+            },
+            dir.path(),
+        );
+        fs::write(dir.path().join("loadavg"), "1.60 1.00 1.00 2/389 12345\n").unwrap();
+        write_stat(dir.path(), 0, 100);
+        assert_eq!(u8::from(ff.boost().unwrap()), 30);
+    }
+}
@@ -1,6 +1,8 @@
 use chrono::prelude::*;
 use log::info;
 use rpi_mailbox::{firmware_revision, get_board_model, get_board_revision, get_temperature};
+use serde::Serialize;
+use std::fmt;
 use std::path::Path;
 
 #[derive(Debug, Clone, err_derive::Error)]
@@ -13,30 +15,62 @@ impl From<rpi_mailbox::error::Error> for MailboxError {
     }
 }
 
-pub struct Mailbox(rpi_mailbox::Mailbox);
+/// Firmware and board revisions, as reported by the firmware
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize)]
+pub struct BoardInfo {
+    /// Firmware build time, seconds since the Unix epoch
+    pub firmware_revision: u32,
+    pub board_model: u32,
+    pub board_revision: u32,
+}
+
+impl BoardInfo {
+    pub fn firmware_date(&self) -> DateTime<Utc> {
+        Utc.timestamp_opt(self.firmware_revision.into(), 0).unwrap()
+    }
+}
+
+impl fmt::Display for BoardInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Firmware revision: {}",
+            self.firmware_date().format("%b %e %Y %T")
+        )?;
+        writeln!(f, "Board model: 0x{:08x}", self.board_model)?;
+        write!(f, "Board revision: 0x{:08x}", self.board_revision)
+    }
+}
+
+pub struct Mailbox {
+    mb: rpi_mailbox::Mailbox,
+    board_info: BoardInfo,
+}
 
 impl Mailbox {
     const SOC_SENSOR_ID: u32 = 0;
 
     pub fn new<P: AsRef<Path>>(vcio_dev: P) -> Result<Self, MailboxError> {
         let mb = rpi_mailbox::Mailbox::new(vcio_dev.as_ref())?;
+        let board_info = BoardInfo {
+            firmware_revision: firmware_revision(&mb)?,
+            board_model: get_board_model(&mb)?,
+            board_revision: get_board_revision(&mb)?,
+        };
+        for line in board_info.to_string().lines() {
+            info!("{}", line);
+        }
+        Ok(Mailbox { mb, board_info })
+    }
 
-        let rev = firmware_revision(&mb)?;
-        let date = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(rev as i64, 0), Utc);
-        info!("Firmware revision: {}", date.format("%b %e %Y %T"));
-
-        let model = get_board_model(&mb)?;
-        info!("Board model: 0x{:08x}", model);
-
-        let rev = get_board_revision(&mb)?;
-        info!("Board revision: 0x{:08x}", rev);
-
-        Ok(Mailbox(mb))
+    /// Firmware and board revisions, read when opened
+    pub fn board_info(&self) -> BoardInfo {
+        self.board_info
     }
 
     /// Returns the temperature in degrees C
     pub fn temperature(&mut self) -> Result<f32, MailboxError> {
-        let raw = get_temperature(&self.0, Self::SOC_SENSOR_ID)?;
+        let raw = get_temperature(&self.mb, Self::SOC_SENSOR_ID)?;
         Ok(raw as f32 / 1000.0)
     }
 }
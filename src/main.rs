@@ -2,59 +2,408 @@
 
 use lib::*;
 use log::{debug, error, info, warn};
-use rppal::i2c::I2c;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
+    atomic::{AtomicBool, AtomicUsize, Ordering},
     Arc,
 };
-use std::{
-    fs,
-    path::PathBuf,
-    process, thread,
-    time::{Duration, Instant},
-};
+use std::time::{Duration, Instant};
+use std::{env, fs, io, process, thread};
+use structopt::clap::{AppSettings, Shell};
 use structopt::StructOpt;
 
 const ABOUT: &str = r#"Argon ONE M.2 Fan Controller
 
 Examples:
     Write a default configuration file
-    argon-fan-ctl --write-default-config ./config.toml
+    argon-fan-ctl config write-default ./config.toml
 
     Run with debug logging
-    RUST_LOG=lib,argon_fan_ctl=debug argon-fan-ctl -c ./config.toml
+    RUST_LOG=lib,argon_fan_ctl=debug argon-fan-ctl -c ./config.toml run
+
+    Install and enable the systemd service
+    argon-fan-ctl install
+
+    Check a configuration file, listing every problem found
+    argon-fan-ctl config check ./config.toml
+
+    Show the merged configuration and where each value came from
+    ARGON_FAN_SPEED_MAX=80 argon-fan-ctl --set mqtt.host=broker.local config show --effective
+
+    Add the version stamp to a configuration file written by an older release
+    argon-fan-ctl config migrate ./config.toml
+
+    Preview the fan speed curve of a configuration file
+    argon-fan-ctl -c ./config.toml curve
+
+    Preview it in Fahrenheit, from 70 F to 180 F
+    argon-fan-ctl -c ./config.toml --units fahrenheit curve --from 70 --to 180
+
+    Log the fan speeds a configuration would use, without setting them
+    RUST_LOG=info argon-fan-ctl -c ./config.toml run --dry-run
+
+    Print the temperature as JSON, for scripts
+    argon-fan-ctl --format json temp
+
+    Switch the running daemon to the quiet profile, then back to the schedule
+    argon-fan-ctl profile quiet
+    argon-fan-ctl profile auto
+
+    Find the fan speeds the fan starts and keeps turning at, with the daemon stopped
+    argon-fan-ctl calibrate
+
+    Propose a curve holding 60 C under full load, with the daemon stopped
+    argon-fan-ctl tune --target 60 -o ./tune
+
+    Watch the temperature and fan speed over SSH, Ctrl-C quits
+    argon-fan-ctl monitor
+
+    Install bash completions
+    argon-fan-ctl completions bash > /etc/bash_completion.d/argon-fan-ctl
 "#;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "argon-fan-ctl", about = ABOUT)]
 pub struct Opts {
     /// I2C bus
-    #[structopt(long, default_value)]
+    #[structopt(long, global = true, default_value)]
     pub i2c_bus: I2cBus,
 
     /// Fan controller I2C address
-    #[structopt(long, default_value)]
+    #[structopt(long, global = true, default_value)]
     pub i2c_addr: I2cAddress,
 
     /// VideoCore IO device path
-    #[structopt(long, name = "vcio device path", default_value = VCIO_DEV)]
+    #[structopt(long, global = true, name = "vcio device path", default_value = VCIO_DEV)]
     pub vcio: PathBuf,
 
     /// Configuration file path
-    #[structopt(long, short = "c", default_value = CONFIG_SYS_PATH)]
+    #[structopt(long, short = "c", global = true, default_value = CONFIG_SYS_PATH)]
     pub config: PathBuf,
 
-    /// Write the default configuration file to path and exit
-    #[structopt(long, name = "path")]
-    pub write_default_config: Option<PathBuf>,
+    /// Override a configuration value, e.g. --set fan_speed_max=80 or --set mqtt.host=broker,
+    /// applied over the configuration file, its config.d drop-ins and the ARGON_* variables
+    #[structopt(long = "set", global = true, name = "key=value", number_of_values = 1)]
+    pub set: Vec<String>,
+
+    /// Output format of temp, fan-speed, status, board-info, list-sensors, curve and profile,
+    /// text or json
+    #[structopt(long, global = true, default_value)]
+    pub format: OutputFormat,
+
+    /// Unit temperatures are printed in, and command line temperatures without a C or F
+    /// suffix are read in, celsius or fahrenheit [default: the configuration's units]
+    #[structopt(long, global = true)]
+    pub units: Option<TemperatureUnit>,
 
-    /// Set the fan speed (percentage, 0..=100) and exit
-    #[structopt(long, name = "percentage", conflicts_with = "get_fan_speed")]
+    /// Control socket path, for the profile and fan-speed subcommands
+    #[structopt(long, global = true, default_value = CONTROL_SOCKET_PATH)]
+    pub control_socket: PathBuf,
+
+    /// State file path, keeps the selected profile across restarts
+    #[structopt(long, global = true, default_value = STATE_SYS_PATH)]
+    pub state_file: PathBuf,
+
+    /// procfs root, read for the CPU load when feed_forward is configured
+    #[structopt(long, global = true, default_value = PROC_ROOT)]
+    pub proc_root: PathBuf,
+
+    /// Deprecated, use the set subcommand
+    #[structopt(long, hidden = true, name = "percentage")]
     pub set_fan_speed: Option<FanSpeed>,
 
-    /// Print the temperature and exit
-    #[structopt(long, conflicts_with = "percentage")]
+    /// Deprecated, use the temp subcommand
+    #[structopt(long, hidden = true)]
     pub get_temp: bool,
+
+    /// Deprecated, use the fan-speed subcommand
+    #[structopt(long, hidden = true)]
+    pub get_fan_speed: bool,
+
+    /// Deprecated, use config write-default
+    #[structopt(long, hidden = true, name = "path")]
+    pub write_default_config: Option<PathBuf>,
+
+    /// Deprecated, use run --dry-run
+    #[structopt(long, hidden = true)]
+    pub dry_run: bool,
+
+    /// Deprecated, use run --record-trace
+    #[structopt(long, hidden = true, name = "trace path")]
+    pub record_trace: Option<PathBuf>,
+
+    /// Runs the fan control loop when not given
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Run the fan control loop, the default without a subcommand
+    Run(RunArgs),
+
+    /// Set the fan speed and exit
+    Set {
+        /// Percentage, 0..=100
+        speed: FanSpeed,
+    },
+
+    /// Print the temperature
+    Temp,
+
+    /// Print the firmware and board revisions
+    BoardInfo,
+
+    /// Print the temperature files under /sys, with their readings
+    ListSensors,
+
+    /// Step the fan through 0..=100% to find the speeds it starts and keeps turning at,
+    /// measured with the tachometer or by asking, and write the suggested fan_speed_min
+    /// and kick to the config.d drop-in calibration.toml. Stop the daemon first.
+    Calibrate {
+        /// Percentage points between steps
+        #[structopt(long, default_value = "5")]
+        step: u8,
+
+        /// Seconds to let the fan settle at each step
+        #[structopt(long, default_value = "3")]
+        settle: u64,
+
+        /// Ask whether the fan spins, even with a tachometer configured
+        #[structopt(long)]
+        interactive: bool,
+
+        /// Only print the suggestions
+        #[structopt(long)]
+        no_write: bool,
+    },
+
+    /// Hold the fan at fixed speeds under a CPU load it generates, measure the temperature
+    /// each settles at, and propose the fan speed curve holding a target temperature with
+    /// the least fan. Writes a report, the samples and a configuration snippet. Stop the
+    /// daemon first.
+    Tune(TuneArgs),
+
+    /// Print the fan speed the running daemon last set, the fan controller can't be read back
+    FanSpeed {
+        /// One of the fans configured under [fans], rather than the main one
+        #[structopt(long)]
+        fan: Option<String>,
+    },
+
+    /// Print the running daemon's profile, temperature and fan speeds
+    Status,
+
+    /// Write, check, show or migrate a configuration file
+    Config(ConfigCommand),
+    /// Install the binary, systemd unit and default configuration file, and enable the service
+    Install {
+        /// Root directory to install into
+        #[structopt(long, default_value = "/")]
+        root: PathBuf,
+    },
+
+    /// Disable the service and remove the installed binary and systemd unit
+    Uninstall {
+        /// Root directory to uninstall from
+        #[structopt(long, default_value = "/")]
+        root: PathBuf,
+
+        /// Also remove the configuration file
+        #[structopt(long)]
+        purge: bool,
+    },
+
+    /// Print the fan speed curve of the configuration file
+    Curve {
+        /// Lowest temperature to show, e.g. 20 or 68F [default: 20 C]
+        #[structopt(long)]
+        from: Option<Temperature>,
+
+        /// Highest temperature to show, e.g. 80 or 176F [default: 80 C]
+        #[structopt(long)]
+        to: Option<Temperature>,
+
+        /// Table temperature step, in whole degrees of the units
+        #[structopt(long, default_value = "5")]
+        step: u8,
+    },
+
+    /// Run the configuration against a thermal model of the Pi and case, in accelerated time
+    Sim {
+        /// Simulated duration, seconds
+        #[structopt(long, default_value = "3600")]
+        duration: u64,
+
+        /// CPU load over time, comma separated <seconds>:<load> entries with load in 0..=1
+        /// [default: 0:0.05,600:1,1800:0.05]
+        #[structopt(long)]
+        load: Option<LoadProfile>,
+
+        /// Ambient temperature, C
+        #[structopt(long, default_value = "25")]
+        ambient: f64,
+
+        /// Heat capacity, J/C
+        #[structopt(long, default_value = "60")]
+        heat_capacity: f64,
+
+        /// Power at idle, W
+        #[structopt(long, default_value = "2.5")]
+        idle_power: f64,
+
+        /// Power at full load, W
+        #[structopt(long, default_value = "7")]
+        max_power: f64,
+
+        /// Thermal conductance to ambient with the fan off, W/C
+        #[structopt(long, default_value = "0.15")]
+        passive_conductance: f64,
+
+        /// Additional thermal conductance with the fan at 100%, W/C
+        #[structopt(long, default_value = "0.2")]
+        fan_conductance: f64,
+
+        /// Profile to run, the default one when not set
+        #[structopt(long)]
+        profile: Option<String>,
+
+        /// Write the trace (one CSV row per simulated second) to path instead of stdout
+        #[structopt(long, short = "o")]
+        output: Option<PathBuf>,
+    },
+
+    /// Replay a recorded temperature trace through the configuration, printing the fan speeds
+    Replay {
+        /// Trace file, as written by --record-trace
+        trace: PathBuf,
+
+        /// Profile to run, the default one when not set
+        #[structopt(long)]
+        profile: Option<String>,
+
+        /// Write the fan speeds (CSV) to path instead of stdout
+        #[structopt(long, short = "o")]
+        output: Option<PathBuf>,
+    },
+
+    /// Select the running daemon's profile, or print it when no name is given.
+    /// "auto" returns to the schedule. SIGUSR1 switches to the next profile, SIGUSR2 to "auto".
+    Profile {
+        /// Profile name, or "auto"
+        name: Option<ProfileSelection>,
+    },
+
+    /// Full screen view of the temperature, fan speed, throttling and curve, read from the
+    /// running daemon, or the hardware when there's none. Ctrl-C quits.
+    Monitor {
+        /// Seconds between updates
+        #[structopt(long, default_value = "1")]
+        interval: u64,
+
+        /// Minutes of temperature history to show
+        #[structopt(long, default_value = "10")]
+        minutes: u64,
+    },
+
+    /// Print a shell completion script
+    Completions {
+        /// bash, zsh, fish, powershell or elvish
+        #[structopt(possible_values = &Shell::variants())]
+        shell: Shell,
+    },
+
+    /// Deprecated, use config check
+    #[structopt(setting = AppSettings::Hidden)]
+    CheckConfig { path: Option<PathBuf> },
+
+    /// Deprecated, use config show
+    #[structopt(setting = AppSettings::Hidden)]
+    ShowConfig {
+        path: Option<PathBuf>,
+
+        #[structopt(long)]
+        effective: bool,
+    },
+
+    /// Deprecated, use config migrate
+    #[structopt(setting = AppSettings::Hidden)]
+    MigrateConfig { path: Option<PathBuf> },
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct RunArgs {
+    /// Run without opening the I2C bus, only logging the fan speeds
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    /// Append the temperature read at each update to a trace file, for the replay subcommand
+    #[structopt(long, name = "trace path")]
+    pub record_trace: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct TuneArgs {
+    /// Temperature to hold under full load, e.g. 60 or 140F [default: 60 C]
+    #[structopt(long)]
+    pub target: Option<Temperature>,
+
+    /// Degrees below the target the fan starts ramping up from [default: 10 C]
+    #[structopt(long)]
+    pub band: Option<Temperature>,
+
+    /// Fan speeds to hold, the fastest first
+    #[structopt(long, use_delimiter = true, default_value = "100,75,50,25,0")]
+    pub fan_speeds: Vec<FanSpeed>,
+
+    /// Longest to hold a fan speed for, minutes
+    #[structopt(long, default_value = "30")]
+    pub max_minutes: u64,
+
+    /// Skip the lower fan speeds once the temperature reaches this [default: 80 C]
+    #[structopt(long)]
+    pub limit: Option<Temperature>,
+
+    /// Analyse the samples of an earlier run instead of running the experiment
+    #[structopt(long)]
+    pub samples: Option<PathBuf>,
+
+    /// Directory to write tune-report.txt, tune-samples.csv and tune.toml to
+    #[structopt(long, short = "o", default_value = ".")]
+    pub output_dir: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ConfigCommand {
+    /// Write the default configuration file
+    WriteDefault {
+        /// Path to write to
+        path: PathBuf,
+    },
+
+    /// Check a configuration file and print every problem found, exits non-zero if there are any
+    Check {
+        /// Configuration file path [default: --config]
+        path: Option<PathBuf>,
+    },
+
+    /// Print the configuration file, or with --effective, the merged configuration and the
+    /// source of each value
+    Show {
+        /// Configuration file path [default: --config]
+        path: Option<PathBuf>,
+
+        /// Include the defaults, config.d drop-ins, ARGON_* variables and --set overrides
+        #[structopt(long)]
+        effective: bool,
+    },
+
+    /// Rewrite a configuration file of an older version in the current one, keeping a backup
+    Migrate {
+        /// Configuration file path [default: --config]
+        path: Option<PathBuf>,
+    },
 }
 
 fn main() {
@@ -69,36 +418,292 @@ fn main() {
 
 fn do_main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
-    let opts = Opts::from_args();
+    let mut opts = Opts::from_args();
+    let cmd = match opts.cmd.take() {
+        Some(cmd) => deprecated_command(cmd),
+        None => deprecated_flags(&opts),
+    };
 
-    if let Some(fan_speed) = opts.set_fan_speed {
-        let mut i2c = I2c::with_bus(opts.i2c_bus.into())?;
-        i2c.set_slave_address(opts.i2c_addr.into())?;
-        i2c.smbus_send_byte(fan_speed.into())?;
-        debug!("Set the fan speed to {}", fan_speed);
-        return Ok(());
+    match cmd {
+        Command::Run(args) => run_daemon(&opts, &args)?,
+        Command::Set { speed } => {
+            let mut fan = I2cFan::new(opts.i2c_bus, opts.i2c_addr)?;
+            fan.set_speed(speed)?;
+            debug!("Set the fan speed to {}", speed);
+        }
+        Command::Temp => {
+            let mut mb = Mailbox::new(&opts.vcio)?;
+            let temp_c = mb.temperature()?;
+            let report = TemperatureReport::new(DegreesC::from_f32(temp_c), units(&opts));
+            println!("{}", opts.format.render(&report)?);
+        }
+        Command::BoardInfo => {
+            let report = Mailbox::new(&opts.vcio)?.board_info();
+            println!("{}", opts.format.render(&report)?);
+        }
+        Command::ListSensors => {
+            let sensors = find_sensor_files("/sys")
+                .into_iter()
+                .map(|f| {
+                    let t = FileSensor::new(&f.path).temperature().ok();
+                    (f, t.map(DegreesC::from_f32))
+                })
+                .collect();
+            let report = SensorsReport::new(sensors, units(&opts));
+            println!("{}", opts.format.render(&report)?);
+        }
+        Command::Calibrate {
+            step,
+            settle,
+            interactive,
+            no_write,
+        } => calibrate_fan(
+            &opts,
+            step,
+            Duration::from_secs(settle),
+            interactive,
+            no_write,
+        )?,
+        Command::Tune(args) => tune(&opts, &args)?,
+        Command::FanSpeed { fan } => {
+            let report = daemon_report(&opts)?;
+            let (fan_speed, overridden, tach) = match &fan {
+                None => (report.fan_speed, report.overridden, report.tach),
+                Some(name) => {
+                    let f = report
+                        .fans
+                        .get(name)
+                        .ok_or_else(|| format!("The daemon has no fan named '{}'", name))?;
+                    (f.fan_speed, f.overridden, None)
+                }
+            };
+            let fan_speed = fan_speed.ok_or("The daemon hasn't set the fan speed yet")?;
+            let report = FanSpeedReport::new(fan_speed, overridden, tach);
+            println!("{}", opts.format.render(&report)?);
+        }
+        Command::Config(c) => config_command(&opts, c)?,
+        Command::Install { root } => Installer::new(root).install(env::current_exe()?)?,
+        Command::Uninstall { root, purge } => Installer::new(root).uninstall(purge)?,
+        Command::Curve { from, to, step } => {
+            let config = load_config(&opts, &opts.config)?.config;
+            let map = config.default_profile().fan_speed_map()?;
+            let units = opts.units.unwrap_or(config.units);
+            let from = from.map_or(DegreesC::new(20), |t| t.to_celsius(units));
+            let to = to.map_or(DegreesC::new(80), |t| t.to_celsius(units));
+            let report = CurveReport::new(&map, from, to, step, units);
+            match opts.format {
+                OutputFormat::Text => {
+                    print!("{}", report);
+                    println!();
+                    print!("{}", curve_plot(&map, from, to, 80, units));
+                }
+                OutputFormat::Json => println!("{}", opts.format.render(&report)?),
+            }
+        }
+        Command::Sim {
+            duration,
+            load,
+            ambient,
+            heat_capacity,
+            idle_power,
+            max_power,
+            passive_conductance,
+            fan_conductance,
+            profile,
+            output,
+        } => {
+            let config = load_config(&opts, &opts.config)?.config;
+            let profile = selected_profile(&config, profile)?;
+            let model = ThermalModel {
+                heat_capacity,
+                idle_power,
+                max_power,
+                passive_conductance,
+                fan_conductance,
+                ambient,
+            };
+            let report = simulate(
+                model,
+                &load.unwrap_or_default(),
+                &profile,
+                config.update_interval_seconds.into(),
+                Duration::from_secs(duration),
+                Duration::from_secs(1),
+            )?;
+            match output {
+                Some(path) => fs::write(path, report.trace_csv())?,
+                None => print!("{}", report.trace_csv()),
+            }
+            eprintln!(
+                "Max temperature {:.1} C, mean fan speed {:.1}%, {} fan writes, {} speed changes",
+                report.max_temperature,
+                report.mean_fan_speed,
+                report.fan_writes,
+                report.speed_changes
+            );
+        }
+        Command::Replay {
+            trace,
+            profile,
+            output,
+        } => {
+            let config = load_config(&opts, &opts.config)?.config;
+            let profile = selected_profile(&config, profile)?;
+            let samples = read_trace(&trace)?;
+            let report = replay(&samples, &profile, config.update_interval_seconds.into())?;
+            match output {
+                Some(path) => fs::write(path, report.ticks_csv())?,
+                None => print!("{}", report.ticks_csv()),
+            }
+            eprint!("{}", report.summary());
+        }
+        Command::Status => {
+            let report = StatusReport::new(daemon_report(&opts)?, units(&opts));
+            println!("{}", opts.format.render(&report)?);
+        }
+        Command::Profile { name } => {
+            if let Some(p) = name {
+                let command = ControlCommand::SetProfile(p);
+                let reply = send_command(&opts.control_socket, &command)?;
+                if reply.starts_with("error") {
+                    return Err(reply.into());
+                }
+            }
+            let report = StatusReport::new(daemon_report(&opts)?, units(&opts));
+            println!("{}", opts.format.render(&report)?);
+        }
+        Command::Monitor { interval, minutes } => monitor(&opts, interval, minutes)?,
+        Command::Completions { shell } => {
+            Opts::clap().gen_completions_to("argon-fan-ctl", shell, &mut io::stdout());
+        }
+        Command::CheckConfig { .. }
+        | Command::ShowConfig { .. }
+        | Command::MigrateConfig { .. } => {
+            unreachable!("Replaced by deprecated_command")
+        }
     }
+    Ok(())
+}
 
-    if opts.get_temp {
-        let mut mb = Mailbox::new(&opts.vcio)?;
-        let temp_c = mb.temperature()?;
-        println!("Temperature: {}", temp_c);
-        return Ok(());
+fn config_command(opts: &Opts, cmd: ConfigCommand) -> Result<(), Box<dyn std::error::Error>> {
+    match cmd {
+        ConfigCommand::WriteDefault { path } => {
+            let config = Config::default();
+            fs::write(&path, toml::to_string_pretty(&config)?.as_bytes())?;
+            info!("Wrote default configuration file to {}", path.display());
+        }
+        ConfigCommand::Check { path } => {
+            let path = path.as_ref().unwrap_or(&opts.config);
+            match load_config(opts, path) {
+                Ok(_) => println!("Configuration file {} is valid", path.display()),
+                Err(e) => {
+                    println!("{}", e);
+                    process::exit(exitcode::CONFIG);
+                }
+            }
+        }
+        ConfigCommand::Show { path, effective } => {
+            let path = path.as_ref().unwrap_or(&opts.config);
+            if effective {
+                print!("{}", load_config(opts, path)?.show());
+            } else {
+                let e = ConfigLayers::new(path).with_drop_in_dir(None).load()?;
+                print!("{}", e.config.to_toml_string()?);
+            }
+        }
+        ConfigCommand::Migrate { path } => {
+            let path = path.as_ref().unwrap_or(&opts.config);
+            match Config::migrate_file(path)? {
+                Some(backup) => println!(
+                    "Migrated {} to version {}, the original is {}",
+                    path.display(),
+                    CONFIG_VERSION,
+                    backup.display()
+                ),
+                None => println!(
+                    "{} is already at version {}",
+                    path.display(),
+                    CONFIG_VERSION
+                ),
+            }
+        }
     }
+    Ok(())
+}
 
-    if let Some(path) = &opts.write_default_config {
-        let config = Config::default();
-        fs::write(path, toml::to_string_pretty(&config)?.as_bytes())?;
-        info!("Wrote default configuration file to {}", path.display());
-        return Ok(());
-    }
+/// The subcommands replaced in this release, kept for one more
+fn deprecated_command(cmd: Command) -> Command {
+    let (old, new, cmd) = match cmd {
+        Command::CheckConfig { path } => (
+            "check-config",
+            "config check",
+            ConfigCommand::Check { path },
+        ),
+        Command::ShowConfig { path, effective } => (
+            "show-config",
+            "config show",
+            ConfigCommand::Show { path, effective },
+        ),
+        Command::MigrateConfig { path } => (
+            "migrate-config",
+            "config migrate",
+            ConfigCommand::Migrate { path },
+        ),
+        cmd => return cmd,
+    };
+    warn!("The {} subcommand is deprecated, use {}", old, new);
+    Command::Config(cmd)
+}
+
+/// The flags replaced by subcommands in this release, kept for one more, otherwise run
+fn deprecated_flags(opts: &Opts) -> Command {
+    let (old, new, cmd) = if let Some(speed) = opts.set_fan_speed {
+        ("--set-fan-speed", "set", Command::Set { speed })
+    } else if opts.get_temp {
+        ("--get-temp", "temp", Command::Temp)
+    } else if opts.get_fan_speed {
+        (
+            "--get-fan-speed",
+            "fan-speed",
+            Command::FanSpeed { fan: None },
+        )
+    } else if let Some(path) = &opts.write_default_config {
+        let cmd = ConfigCommand::WriteDefault { path: path.clone() };
+        (
+            "--write-default-config",
+            "config write-default",
+            Command::Config(cmd),
+        )
+    } else {
+        let cmd = Command::Run(RunArgs {
+            dry_run: opts.dry_run,
+            record_trace: opts.record_trace.clone(),
+        });
+        if opts.dry_run {
+            ("--dry-run", "run --dry-run", cmd)
+        } else if opts.record_trace.is_some() {
+            ("--record-trace", "run --record-trace", cmd)
+        } else {
+            return cmd;
+        }
+    };
+    warn!("{} is deprecated, use the {} subcommand", old, new);
+    cmd
+}
 
-    let config = Config::load(&opts.config)?;
+fn run_daemon(opts: &Opts, args: &RunArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(opts, &opts.config)?.config;
+
+    // Before any threads are spawned, so they all leave the signals to the control server
+    let signals = block_profile_signals()?;
 
     let running = Arc::new(AtomicUsize::new(0));
+    let wakeup = Wakeup::new();
     let r = running.clone();
+    let w = wakeup.clone();
     ctrlc::set_handler(move || {
         let prev = r.fetch_add(1, Ordering::SeqCst);
+        w.notify();
         if prev == 0 {
             info!("Shutting down");
         } else {
@@ -107,31 +712,388 @@ fn do_main() -> Result<(), Box<dyn std::error::Error>> {
         }
     })?;
 
-    let mut i2c = I2c::with_bus(opts.i2c_bus.into())?;
-    i2c.set_slave_address(opts.i2c_addr.into())?;
-    let mut mb = Mailbox::new(&opts.vcio)?;
-    let map = FanSpeedMap::new(
-        config.temperature_min,
-        config.temperature_max,
-        config.fan_speed_min,
-        config.fan_speed_max,
+    if args.dry_run {
+        info!(
+            "Dry run, the I2C bus, GPIO pins, tachometer, control socket, state file, MQTT \
+             and systemd notifications will not be used"
+        );
+    }
+    run(opts, &config, args, &running, wakeup, signals)
+}
+
+/// The named profile, or the default one
+fn selected_profile(config: &Config, name: Option<String>) -> Result<Profile, ConfigCheckError> {
+    let name = name.unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+    config
+        .profile(&name)
+        .ok_or(ConfigCheckError::UnknownProfile(name))
+}
+
+/// The configuration file, layered with its drop-ins, ARGON_* variables and --set overrides
+fn load_config(opts: &Opts, path: &Path) -> Result<EffectiveConfig, ConfigLoadError> {
+    let e = ConfigLayers::new(path)
+        .with_env(env::vars())
+        .with_overrides(&opts.set)
+        .load()?;
+    info!("Loaded configuration file {}", path.display());
+    e.config.log_summary();
+    Ok(e)
+}
+
+/// --units, or the configuration's, the configuration file is optional here
+fn units(opts: &Opts) -> TemperatureUnit {
+    opts.units.unwrap_or_else(|| {
+        load_config(opts, &opts.config)
+            .map(|e| e.config.units)
+            .unwrap_or_default()
+    })
+}
+
+/// Asks the running daemon what it's doing, over the control socket
+fn daemon_report(opts: &Opts) -> Result<DaemonReport, Box<dyn std::error::Error>> {
+    let reply = send_command(&opts.control_socket, &ControlCommand::Report)?;
+    match reply.strip_prefix("ok ") {
+        Some(json) => Ok(serde_json::from_str(json)?),
+        None => Err(reply.into()),
+    }
+}
+
+/// The calibrate subcommand
+fn calibrate_fan(
+    opts: &Opts,
+    step: u8,
+    settle: Duration,
+    interactive: bool,
+    no_write: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if daemon_report(opts).is_ok() {
+        return Err("The daemon is running, stop it first so it doesn't set the fan speed".into());
+    }
+    let config = load_config(opts, &opts.config)?.config;
+    let mut fan = I2cFan::new(opts.i2c_bus, opts.i2c_addr)?;
+    let calibration = match config.tachometer.filter(|_| !interactive) {
+        Some(c) => {
+            let feedback = open_feedback(SystemClock, &c, opts.i2c_bus, opts.i2c_addr)?;
+            println!("Measuring the fan speed at every {}%", step);
+            calibrate(
+                &mut fan,
+                &mut TachProbe::new(SystemClock, feedback, settle),
+                step,
+            )?
+        }
+        None => {
+            let stdin = io::stdin();
+            let mut probe = InteractiveProbe::new(SystemClock, stdin.lock(), io::stdout(), settle);
+            calibrate(&mut fan, &mut probe, step)?
+        }
+    };
+    println!("{}", calibration);
+    if no_write {
+        print!("\n{}", calibration.drop_in());
+    } else {
+        let path = write_calibration(&opts.config, &calibration)?;
+        println!("Wrote {}, restart the daemon to use it", path.display());
+    }
+    Ok(())
+}
+
+/// The tune subcommand
+fn tune(opts: &Opts, args: &TuneArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(opts, &opts.config)?.config;
+    let units = opts.units.unwrap_or(config.units);
+    let settings = TuneSettings {
+        fan_speeds: args.fan_speeds.clone(),
+        max_step_time: Duration::from_secs(args.max_minutes * 60),
+        limit: args
+            .limit
+            .map_or(DegreesC::new(80), |t| t.to_celsius(units)),
+        ..Default::default()
+    };
+    let samples = match &args.samples {
+        Some(path) => read_tune_samples(path)?,
+        None => {
+            if daemon_report(opts).is_ok() {
+                return Err(
+                    "The daemon is running, stop it first so it doesn't set the fan speed".into(),
+                );
+            }
+            let mut mb = Mailbox::new(&opts.vcio)?;
+            let mut fan = I2cFan::new(opts.i2c_bus, opts.i2c_addr)?;
+            let (bus, addr) = (opts.i2c_bus, opts.i2c_addr);
+            ctrlc::set_handler(move || {
+                // Don't leave the fan off under load
+                if let Ok(mut fan) = I2cFan::new(bus, addr) {
+                    let _ = fan.set_speed(FanSpeed::MAX);
+                }
+                process::exit(exitcode::SOFTWARE);
+            })?;
+            println!("Holding each fan speed until the temperature settles, this takes a while");
+            let _load = BusyLoad::start();
+            run_tune(&mut mb, &mut fan, &settings, &SystemClock)?
+        }
+    };
+    let states = steady_states(&samples, settings.window, settings.tolerance);
+    let report = TuneReport::new(
+        states,
+        args.target
+            .map_or(DegreesC::new(60), |t| t.to_celsius(units)),
+        args.band
+            .map_or(DegreesC::new(10), |t| t.difference_to_celsius(units)),
+        &config.default_profile(),
+        units,
+    )?;
+    println!("{}", report);
+
+    let dir = &args.output_dir;
+    fs::create_dir_all(dir)?;
+    if args.samples.is_none() {
+        fs::write(dir.join("tune-samples.csv"), tune_samples_csv(&samples))?;
+    }
+    fs::write(dir.join("tune-report.txt"), format!("{}\n", report))?;
+    fs::write(dir.join("tune.toml"), report.snippet(config.units)?)?;
+    println!(
+        "Wrote the report and tune.toml to {}, copy tune.toml to config.d to use the curve",
+        dir.display()
     );
+    Ok(())
+}
+
+/// The monitor subcommand, redraws on the alternate screen until Ctrl-C
+fn monitor(opts: &Opts, interval: u64, minutes: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let config = load_config(opts, &opts.config)?.config;
+    let interval = Duration::from_secs(interval.max(1));
+    let mut monitor = Monitor::new(
+        config.profile_schedule(),
+        opts.units.unwrap_or(config.units),
+        Duration::from_secs(minutes.max(1) * 60),
+        interval,
+    );
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
+    ctrlc::set_handler(move || r.store(false, Ordering::SeqCst))?;
+
+    let mut out = io::stdout();
+    write!(out, "\x1b[?1049h\x1b[?25l")?;
+    let result = monitor_loop(opts, &mut monitor, interval, &running, &mut out);
+    write!(out, "\x1b[?25h\x1b[?1049l")?;
+    out.flush()?;
+    result
+}
 
-    let fan_speed = FanSpeed::default();
-    debug!("Setting default fan speed {}", fan_speed);
-    i2c.smbus_send_byte(fan_speed.into())?;
+fn monitor_loop(
+    opts: &Opts,
+    monitor: &mut Monitor,
+    interval: Duration,
+    running: &AtomicBool,
+    out: &mut impl Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut mb = None;
+    while running.load(Ordering::SeqCst) {
+        let daemon = daemon_report(opts).ok();
+        let temperature = match daemon.as_ref().and_then(|d| d.temperature) {
+            Some(t) => t,
+            None => {
+                let mb = match &mut mb {
+                    Some(mb) => mb,
+                    None => mb.insert(Mailbox::new(&opts.vcio)?),
+                };
+                DegreesC::from_f32(mb.temperature()?)
+            }
+        };
+        monitor.update(MonitorSample {
+            temperature,
+            daemon,
+            throttled: Throttled::read(THROTTLED_SYS_PATH).ok(),
+            local_time: SystemClock.local_time(),
+        });
+        write!(out, "\x1b[H\x1b[2J{}", monitor.render(terminal_width()))?;
+        out.flush()?;
 
-    let mut sched = Scheduler::new(Instant::now(), config.update_interval_seconds.into());
-    while running.load(Ordering::SeqCst) == 0 {
-        if sched.update(Instant::now()) {
-            let temp_c = DegreesC::from_f32(mb.temperature()?);
-            let fan_speed = map.get(temp_c);
-            i2c.smbus_send_byte(fan_speed.into())?;
-            debug!("Temp {}, fan speed {}", temp_c, fan_speed);
+        let start = Instant::now();
+        while running.load(Ordering::SeqCst) && start.elapsed() < interval {
+            thread::sleep(Duration::from_millis(100));
         }
+    }
+    Ok(())
+}
 
-        thread::sleep(Duration::from_secs(1));
+/// Columns of the terminal on stdout, 80 when it isn't one
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    if ok && size.ws_col > 0 {
+        usize::from(size.ws_col)
+    } else {
+        80
     }
+}
+
+fn run(
+    opts: &Opts,
+    config: &Config,
+    args: &RunArgs,
+    running: &AtomicUsize,
+    wakeup: Wakeup,
+    signals: ProfileSignals,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let fan = if args.dry_run {
+        FanDevice::DryRun(DryRunFan)
+    } else {
+        FanDevice::I2c(I2cFan::new(opts.i2c_bus, opts.i2c_addr)?)
+    };
+    let units = opts.units.unwrap_or(config.units);
+
+    let clock = SystemClock;
+    let mut scheduler = Scheduler::new(clock.now(), config.update_interval_seconds.into())
+        .with_missed_ticks(config.missed_ticks);
+    if let Some(a) = &config.adaptive_interval {
+        scheduler = scheduler.with_adaptive(AdaptiveInterval::new(a, config.temperature_max));
+    }
+    let mut daemon = Daemon::new(
+        clock,
+        Controller::new(
+            Sensor::open(None, &opts.vcio)?,
+            KickFan::new(clock, fan, config.kick),
+            config.default_profile().fan_speed_map()?,
+        ),
+        scheduler,
+        if args.dry_run {
+            SystemdNotifier::new(None, None)?
+        } else {
+            SystemdNotifier::from_env()?
+        },
+    )
+    .with_wakeup(wakeup.clone())
+    .with_profiles(config.profile_schedule())
+    .with_units(units);
+    for (name, c) in config.fans.iter() {
+        let profile = c.profile.as_deref().and_then(|p| config.profile(p));
+        let mut controller = Controller::new(
+            Sensor::open(c.sensor.as_deref(), &opts.vcio)?,
+            KickFan::new(clock, FanDevice::open(c, args.dry_run)?, c.kick),
+            profile
+                .as_ref()
+                .unwrap_or(&config.default_profile())
+                .fan_speed_map()?,
+        );
+        if let Some(p) = &profile {
+            controller.set_profile(p)?;
+        }
+        daemon = daemon.with_fan(name, controller, profile.is_none());
+    }
+    if let Some(c) = config.feed_forward {
+        daemon = daemon.with_feed_forward(FeedForward::new(c, &opts.proc_root));
+    }
+    let tachometer = config.tachometer.filter(|_| !args.dry_run);
+    if let Some(c) = &tachometer {
+        daemon = daemon.with_tachometer(Tachometer::new(clock, c, opts.i2c_bus, opts.i2c_addr)?);
+    }
+    daemon = with_services(daemon, opts, config, args, wakeup, signals)?;
+    if let Some(c) = &config.telemetry {
+        daemon = daemon.with_telemetry(TelemetrySink::new(c.clone())?);
+    }
+    if let Some(path) = &args.record_trace {
+        daemon = daemon.with_trace(TraceRecorder::new(path)?);
+    }
+
+    daemon.run(running)?;
 
     Ok(())
 }
+
+/// Attaches the control socket, state file and MQTT. A dry run leaves them out, so it can't
+/// take them over from a daemon already running.
+fn with_services<T: TemperatureSource, F: Fan>(
+    mut daemon: Daemon<T, F>,
+    opts: &Opts,
+    config: &Config,
+    args: &RunArgs,
+    wakeup: Wakeup,
+    signals: ProfileSignals,
+) -> Result<Daemon<T, F>, Box<dyn std::error::Error>> {
+    if args.dry_run {
+        return Ok(daemon);
+    }
+    let control = ControlServer::new(wakeup.clone());
+    match control.listen(&opts.control_socket) {
+        Err(e @ ControlSocketError::InUse(_)) => return Err(e.into()),
+        Err(e) => warn!("{}", e),
+        Ok(()) => (),
+    }
+    control.handle_signals(signals)?;
+    daemon = daemon
+        .with_control(control)
+        .with_state_file(StateFile::new(&opts.state_file));
+    if let Some(c) = &config.mqtt {
+        let units = opts.units.unwrap_or(config.units);
+        let mut bridge =
+            MqttBridge::new(RumqttClient::new(c, wakeup)?, c.clone()).with_units(units);
+        if config.tachometer.is_some() {
+            bridge = bridge.with_tachometer();
+        }
+        bridge = bridge.with_fans(config.fans.keys().cloned());
+        daemon = daemon.with_mqtt(bridge, c.override_timeout());
+    }
+    Ok(daemon)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn daemon(sensor: &Path) -> Daemon<FileSensor, DryRunFan> {
+        let config = Config::default();
+        Daemon::new(
+            SystemClock,
+            Controller::new(
+                FileSensor::new(sensor),
+                DryRunFan,
+                config.default_profile().fan_speed_map().unwrap(),
+            ),
+            Scheduler::new(Instant::now(), Duration::from_secs(30)),
+            SystemdNotifier::new(None, None).unwrap(),
+        )
+    }
+
+    #[test]
+    fn dry_run_leaves_services_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("run/control.sock");
+        let state = dir.path().join("lib/state.toml");
+        let sensor = dir.path().join("temp");
+        let config = Config::default();
+        let start = |extra: &[&str]| {
+            let mut argv = vec![
+                "argon-fan-ctl",
+                "--control-socket",
+                socket.to_str().unwrap(),
+                "--state-file",
+                state.to_str().unwrap(),
+                "run",
+            ];
+            argv.extend_from_slice(extra);
+            let opts = Opts::from_iter(argv);
+            let args = match &opts.cmd {
+                Some(Command::Run(args)) => args,
+                _ => unreachable!(),
+            };
+            let signals = block_profile_signals().unwrap();
+            with_services(
+                daemon(&sensor),
+                &opts,
+                &config,
+                args,
+                Wakeup::new(),
+                signals,
+            )
+            .unwrap();
+        };
+
+        start(&["--dry-run"]);
+        assert!(!dir.path().join("run").exists());
+        assert!(!dir.path().join("lib").exists());
+
+        start(&[]);
+        assert!(socket.exists());
+    }
+}
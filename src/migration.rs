@@ -0,0 +1,100 @@
+use toml::value::{Table, Value};
+
+/// Version of the configuration file layout written by this release
+pub const CONFIG_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, err_derive::Error)]
+pub enum MigrationError {
+    #[error(display = "the configuration file is not a table")]
+    NotATable,
+
+    #[error(display = "version must be a non-negative integer")]
+    InvalidVersion,
+
+    #[error(
+        display = "version {} is newer than the supported version {}",
+        _0,
+        CONFIG_VERSION
+    )]
+    UnsupportedVersion(u32),
+}
+
+/// Upgrades a table from version N to N + 1, indexed by N
+type MigrationStep = fn(&mut Table);
+
+const STEPS: [MigrationStep; CONFIG_VERSION as usize] = [v0_to_v1];
+
+/// Files without a version field are the flat v0.2 layout, version 0
+pub fn config_version(value: &Value) -> Result<u32, MigrationError> {
+    let table = value.as_table().ok_or(MigrationError::NotATable)?;
+    match table.get("version") {
+        None => Ok(0),
+        Some(Value::Integer(v)) if *v >= 0 && *v <= i64::from(u32::MAX) => Ok(*v as u32),
+        Some(_) => Err(MigrationError::InvalidVersion),
+    }
+}
+
+/// Migrates the configuration in place to the current version, returns the version it was at
+pub fn migrate_config(value: &mut Value) -> Result<u32, MigrationError> {
+    let from = config_version(value)?;
+    if from > CONFIG_VERSION {
+        return Err(MigrationError::UnsupportedVersion(from));
+    }
+    let table = value.as_table_mut().ok_or(MigrationError::NotATable)?;
+    for step in STEPS[from as usize..].iter() {
+        step(table);
+    }
+    Ok(from)
+}
+
+/// Only adds the version stamp, the v0.2 fields are already the version 1 layout
+fn v0_to_v1(table: &mut Table) {
+    table.insert("version".to_string(), Value::Integer(1));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Golden files, the output of each step must match the next version's file
+    const GOLDEN: [&str; CONFIG_VERSION as usize + 1] = [
+        include_str!("../testdata/config/v0.toml"),
+        include_str!("../testdata/config/v1.toml"),
+    ];
+
+    fn parse(s: &str) -> Value {
+        toml::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn golden_steps() {
+        for (n, step) in STEPS.iter().enumerate() {
+            let mut value = parse(GOLDEN[n]);
+            assert_eq!(config_version(&value), Ok(n as u32));
+            step(value.as_table_mut().unwrap());
+            assert_eq!(value, parse(GOLDEN[n + 1]), "Step from version {}", n);
+            assert_eq!(config_version(&value), Ok(n as u32 + 1));
+        }
+    }
+
+    #[test]
+    fn migrate_to_current() {
+        let current = parse(GOLDEN[CONFIG_VERSION as usize]);
+        for (n, golden) in GOLDEN.iter().enumerate() {
+            let mut value = parse(golden);
+            assert_eq!(migrate_config(&mut value), Ok(n as u32));
+            assert_eq!(value, current);
+        }
+
+        let mut value = parse("version = 99");
+        assert_eq!(
+            migrate_config(&mut value),
+            Err(MigrationError::UnsupportedVersion(99))
+        );
+        let mut value = parse("version = \"1\"");
+        assert_eq!(
+            migrate_config(&mut value),
+            Err(MigrationError::InvalidVersion)
+        );
+    }
+}
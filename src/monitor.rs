@@ -0,0 +1,299 @@
+use crate::{curve_plot_at, DaemonReport, DegreesC, ProfileSchedule, TemperatureUnit, Throttled};
+use chrono::NaiveDateTime;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// One poll of the monitor subcommand
+#[derive(Clone, PartialEq, Debug)]
+pub struct MonitorSample {
+    pub temperature: DegreesC,
+    /// The running daemon's report, None when reading the hardware directly
+    pub daemon: Option<DaemonReport>,
+    /// None when the firmware's flags can't be read
+    pub throttled: Option<Throttled>,
+    /// Local time of the poll, for the scheduled profile when there's no daemon
+    pub local_time: NaiveDateTime,
+}
+
+/// The monitor subcommand's view, the last samples over a window and the peak since start
+#[derive(Clone, Debug)]
+pub struct Monitor {
+    profiles: ProfileSchedule,
+    unit: TemperatureUnit,
+    window: Duration,
+    capacity: usize,
+    history: VecDeque<DegreesC>,
+    peak: Option<DegreesC>,
+    last: Option<MonitorSample>,
+}
+
+impl Monitor {
+    const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    /// Keeps `window` of samples taken every `interval`
+    pub fn new(
+        profiles: ProfileSchedule,
+        unit: TemperatureUnit,
+        window: Duration,
+        interval: Duration,
+    ) -> Self {
+        let capacity = (window.as_millis() / interval.as_millis().max(1)).max(1) as usize;
+        Monitor {
+            profiles,
+            unit,
+            window,
+            capacity,
+            history: VecDeque::with_capacity(capacity),
+            peak: None,
+            last: None,
+        }
+    }
+
+    pub fn update(&mut self, sample: MonitorSample) {
+        if self.history.len() == self.capacity {
+            self.history.pop_front();
+        }
+        self.history.push_back(sample.temperature);
+        self.peak = Some(
+            self.peak
+                .map_or(sample.temperature, |p| p.max(sample.temperature)),
+        );
+        self.last = Some(sample);
+    }
+
+    /// The full screen text, `width` columns wide
+    pub fn render(&self, width: usize) -> String {
+        let mut out = String::new();
+        let sample = match &self.last {
+            Some(s) => s,
+            None => return "Waiting for the first reading\n".to_string(),
+        };
+        let (scheduled, scheduled_profile) = self.profiles.active(sample.local_time);
+        let profile = match sample.daemon.as_ref().and_then(|d| d.profile.as_deref()) {
+            Some(name) => self.profiles.get(name),
+            None => Some(scheduled_profile),
+        };
+        let map = profile.and_then(|p| p.fan_speed_map().ok());
+
+        let (fan_speed, mut fan_note) = match &sample.daemon {
+            Some(d) => {
+                match d.profile.as_deref() {
+                    Some(p) if d.selected => writeln!(out, "Daemon, profile {} (selected)", p),
+                    Some(p) => writeln!(out, "Daemon, profile {} (scheduled)", p),
+                    None => writeln!(out, "Daemon, no profiles"),
+                }
+                .unwrap();
+                let note = if d.overridden { " (override)" } else { "" };
+                (d.fan_speed, note.to_string())
+            }
+            None => {
+                writeln!(
+                    out,
+                    "No daemon, reading the hardware, profile {} (scheduled)",
+                    scheduled
+                )
+                .unwrap();
+                (
+                    map.as_ref().map(|m| m.get(sample.temperature)),
+                    " by the curve".to_string(),
+                )
+            }
+        };
+        if let Some(t) = sample.daemon.as_ref().and_then(|d| d.tach) {
+            fan_note.push_str(&format!(", {} RPM", t.rpm));
+            if t.stalled {
+                fan_note.push_str(", STALLED");
+            }
+        }
+        writeln!(out).unwrap();
+
+        let temperature = sample.temperature.in_unit(self.unit).to_string();
+        match self.peak {
+            Some(p) => writeln!(
+                out,
+                "Temperature  {:<10}peak {}",
+                temperature,
+                p.in_unit(self.unit)
+            ),
+            None => writeln!(out, "Temperature  {}", temperature),
+        }
+        .unwrap();
+        match fan_speed {
+            Some(s) => writeln!(out, "Fan speed    {}{}", s, fan_note),
+            None => writeln!(out, "Fan speed    unknown"),
+        }
+        .unwrap();
+        match sample.throttled {
+            Some(t) => writeln!(out, "Throttling   {}", t),
+            None => writeln!(out, "Throttling   unknown"),
+        }
+        .unwrap();
+        writeln!(out).unwrap();
+
+        let shown: Vec<DegreesC> = self
+            .history
+            .iter()
+            .skip(self.history.len().saturating_sub(width.max(1)))
+            .copied()
+            .collect();
+        let lo = shown.iter().min().copied().unwrap_or(sample.temperature);
+        let hi = shown.iter().max().copied().unwrap_or(sample.temperature);
+        writeln!(
+            out,
+            "Last {} min, {} to {}",
+            self.window.as_secs() / 60,
+            lo.in_unit(self.unit),
+            hi.in_unit(self.unit)
+        )
+        .unwrap();
+        writeln!(out, "{}", Self::sparkline(&shown, lo, hi)).unwrap();
+
+        if let (Some(map), Some(s)) = (map, fan_speed) {
+            writeln!(out).unwrap();
+            out.push_str(&curve_plot_at(
+                &map,
+                DegreesC::new(20),
+                DegreesC::new(80),
+                width.saturating_sub(8).max(1),
+                self.unit,
+                (sample.temperature, s),
+            ));
+        }
+        out
+    }
+
+    fn sparkline(temperatures: &[DegreesC], lo: DegreesC, hi: DegreesC) -> String {
+        let span = i32::from(hi.tenths()) - i32::from(lo.tenths());
+        temperatures
+            .iter()
+            .map(|t| {
+                let level = if span == 0 {
+                    0
+                } else {
+                    (i32::from(t.tenths()) - i32::from(lo.tenths())) * 7 / span
+                };
+                Self::SPARKS[level as usize]
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FanSpeed, Profile, ScheduleEntry, TachReading, DEFAULT_PROFILE};
+    use chrono::NaiveDate;
+    use std::collections::BTreeMap;
+
+    /// The quiet profile, at half the speed, from 22:00 to 07:00
+    fn monitor() -> Monitor {
+        let profile = Profile {
+            temperature_min: DegreesC::new(40),
+            temperature_max: DegreesC::new(60),
+            fan_speed_min: FanSpeed::MIN,
+            fan_speed_max: FanSpeed::MAX,
+            hysteresis: DegreesC::new(0),
+            safety_temperature: None,
+        };
+        let quiet = Profile {
+            fan_speed_max: FanSpeed::new(50).unwrap(),
+            ..profile.clone()
+        };
+        let mut profiles = BTreeMap::new();
+        profiles.insert(DEFAULT_PROFILE.to_string(), profile);
+        profiles.insert("quiet".to_string(), quiet);
+        let schedule = vec![ScheduleEntry {
+            profile: "quiet".to_string(),
+            start: "22:00".parse().unwrap(),
+            end: "07:00".parse().unwrap(),
+            days: Vec::new(),
+        }];
+        Monitor::new(
+            ProfileSchedule::new(profiles, schedule),
+            TemperatureUnit::Celsius,
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+        )
+    }
+
+    fn at(hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2022, 1, 3)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    fn sample(tenths: i16, daemon: Option<DaemonReport>) -> MonitorSample {
+        MonitorSample {
+            temperature: DegreesC::from_tenths(tenths),
+            daemon,
+            throttled: Some(Throttled(0x50000)),
+            local_time: at(12),
+        }
+    }
+
+    #[test]
+    fn history_and_peak() {
+        let mut m = monitor();
+        assert_eq!(m.render(80), "Waiting for the first reading\n");
+        for t in [500, 520, 480, 400, 410, 420, 430, 440].iter() {
+            m.update(sample(*t, None));
+        }
+        let screen = m.render(80);
+        let lines: Vec<&str> = screen.lines().collect();
+        assert_eq!(
+            lines[0],
+            "No daemon, reading the hardware, profile default (scheduled)"
+        );
+        assert_eq!(lines[2], "Temperature  44 C      peak 52 C");
+        assert_eq!(lines[3], "Fan speed    20% by the curve");
+        assert_eq!(
+            lines[4],
+            "Throttling   none now, under-voltage, throttled since boot"
+        );
+        // Six samples in a minute every 10 s
+        assert_eq!(lines[6], "Last 1 min, 40 C to 48 C");
+        assert_eq!(lines[7], "█▁▁▂▃▄");
+        assert!(lines[9..].iter().any(|l| l.contains('O')));
+
+        // At night the curve is the scheduled profile's
+        m.update(MonitorSample {
+            local_time: at(23),
+            ..sample(440, None)
+        });
+        let screen = m.render(80);
+        let lines: Vec<&str> = screen.lines().collect();
+        assert_eq!(
+            lines[0],
+            "No daemon, reading the hardware, profile quiet (scheduled)"
+        );
+        assert_eq!(lines[3], "Fan speed    10% by the curve");
+    }
+
+    #[test]
+    fn daemon() {
+        let mut m = monitor();
+        let report = DaemonReport {
+            profile: Some(DEFAULT_PROFILE.to_string()),
+            selected: true,
+            temperature: Some(DegreesC::new(50)),
+            fan_speed: FanSpeed::new(100),
+            overridden: true,
+            tach: Some(TachReading {
+                rpm: 0,
+                stalled: true,
+            }),
+            ..Default::default()
+        };
+        m.update(sample(500, Some(report)));
+        let screen = m.render(40);
+        let lines: Vec<&str> = screen.lines().collect();
+        assert_eq!(lines[0], "Daemon, profile default (selected)");
+        assert_eq!(lines[3], "Fan speed    100% (override), 0 RPM, STALLED");
+        assert_eq!(lines[7], "▁");
+        // The operating point is off the curve, on the 100% row
+        assert!(lines[9].contains('O'));
+        assert!(lines[9..].iter().all(|l| l.chars().count() <= 40));
+    }
+}
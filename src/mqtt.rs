@@ -0,0 +1,837 @@
+use crate::{DegreesC, FanSpeed, TachReading, TemperatureUnit, Wakeup, MAIN_FAN};
+use log::{debug, info, warn};
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::num::NonZeroU32;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+use std::{io, thread};
+
+pub const MQTT_PORT: u16 = 1883;
+pub const HA_DISCOVERY_PREFIX: &str = "homeassistant";
+
+const PAYLOAD_ONLINE: &str = "online";
+const PAYLOAD_OFFLINE: &str = "offline";
+const PAYLOAD_ON: &str = "ON";
+const PAYLOAD_OFF: &str = "OFF";
+const PRESET_AUTO: &str = "auto";
+
+#[derive(Debug, err_derive::Error)]
+pub enum MqttError {
+    #[error(display = "MQTT client error, {}", _0)]
+    Client(#[error(from)] rumqttc::ClientError),
+
+    #[error(display = "Failed to serialize MQTT payload, {}", _0)]
+    Json(#[error(from)] serde_json::Error),
+
+    #[error(display = "Failed to start the MQTT connection thread, {}", _0)]
+    Io(#[error(from)] io::Error),
+}
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct MqttConfig {
+    /// Broker host name or address
+    pub host: String,
+    /// Broker port
+    #[serde(default = "MqttConfig::default_port")]
+    pub port: u16,
+    /// Client ID, also used as the Home Assistant device identifier
+    pub client_id: String,
+    /// Broker username
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Broker password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Prefix of the state and command topics, defaults to argonone/<client_id>
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_topic: Option<String>,
+    /// Home Assistant discovery topic prefix
+    #[serde(default = "MqttConfig::default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Seconds until a fan speed override reverts to automatic control,
+    /// overrides are held until cleared when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub override_timeout_seconds: Option<NonZeroU32>,
+}
+
+impl MqttConfig {
+    fn default_port() -> u16 {
+        MQTT_PORT
+    }
+
+    fn default_discovery_prefix() -> String {
+        HA_DISCOVERY_PREFIX.to_string()
+    }
+
+    pub fn override_timeout(&self) -> Option<Duration> {
+        self.override_timeout_seconds
+            .map(|s| Duration::from_secs(s.get() as _))
+    }
+
+    pub fn base_topic(&self) -> String {
+        self.base_topic
+            .clone()
+            .unwrap_or_else(|| format!("argonone/{}", self.client_id))
+    }
+
+    pub fn availability_topic(&self) -> String {
+        format!("{}/availability", self.base_topic())
+    }
+
+    pub fn state_topic(&self) -> String {
+        self.fan_topics(MAIN_FAN).state
+    }
+
+    pub fn command_topic(&self) -> String {
+        self.fan_topics(MAIN_FAN).command
+    }
+
+    pub fn percentage_command_topic(&self) -> String {
+        self.fan_topics(MAIN_FAN).percentage_command
+    }
+
+    pub fn preset_mode_command_topic(&self) -> String {
+        self.fan_topics(MAIN_FAN).preset_mode_command
+    }
+
+    /// Topics of the named fan, the main fan's are under the base topic and the
+    /// others' under <base_topic>/fans/<name>
+    pub fn fan_topics(&self, fan: &str) -> FanTopics {
+        let base = if fan == MAIN_FAN {
+            self.base_topic()
+        } else {
+            format!("{}/fans/{}", self.base_topic(), fan)
+        };
+        FanTopics {
+            state: format!("{}/state", base),
+            command: format!("{}/fan/set", base),
+            percentage_command: format!("{}/fan/percentage/set", base),
+            preset_mode_command: format!("{}/fan/preset_mode/set", base),
+        }
+    }
+
+    /// Home Assistant node ID, the client ID restricted to [a-zA-Z0-9_-]
+    pub fn node_id(&self) -> String {
+        self.client_id
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+}
+
+/// State and command topics of a fan
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct FanTopics {
+    pub state: String,
+    pub command: String,
+    pub percentage_command: String,
+    pub preset_mode_command: String,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum MqttEvent {
+    /// The client (re)connected to the broker
+    Connected,
+    /// A message was received on a subscribed topic
+    Message { topic: String, payload: Vec<u8> },
+}
+
+pub trait MqttClient {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), MqttError>;
+
+    fn subscribe(&mut self, topic: &str) -> Result<(), MqttError>;
+
+    /// Returns the next pending event without blocking
+    fn try_recv(&mut self) -> Option<MqttEvent>;
+
+    fn disconnect(&mut self) -> Result<(), MqttError>;
+}
+
+/// MQTT client backed by rumqttc, the connection is driven by a background thread
+/// which reconnects as needed
+pub struct RumqttClient {
+    client: Client,
+    events: Receiver<MqttEvent>,
+}
+
+impl RumqttClient {
+    const KEEP_ALIVE: Duration = Duration::from_secs(30);
+    const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+    const REQUEST_CAPACITY: usize = 32;
+
+    /// Notifies `wakeup` on every incoming event
+    pub fn new(config: &MqttConfig, wakeup: Wakeup) -> Result<Self, MqttError> {
+        let mut opts = MqttOptions::new(&config.client_id, &config.host, config.port);
+        opts.set_keep_alive(Self::KEEP_ALIVE);
+        opts.set_last_will(LastWill::new(
+            config.availability_topic(),
+            PAYLOAD_OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(username) = &config.username {
+            opts.set_credentials(username, config.password.as_deref().unwrap_or(""));
+        }
+
+        let (client, connection) = Client::new(opts, Self::REQUEST_CAPACITY);
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("mqtt".to_string())
+            .spawn(move || Self::run(connection, tx, wakeup))?;
+
+        Ok(RumqttClient { client, events: rx })
+    }
+
+    fn run(mut connection: Connection, tx: Sender<MqttEvent>, wakeup: Wakeup) {
+        for event in connection.iter() {
+            let event = match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to the MQTT broker");
+                    MqttEvent::Connected
+                }
+                Ok(Event::Incoming(Packet::Publish(p))) => MqttEvent::Message {
+                    topic: p.topic,
+                    payload: p.payload.to_vec(),
+                },
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("MQTT connection error, {}", e);
+                    thread::sleep(Self::RECONNECT_DELAY);
+                    continue;
+                }
+            };
+            if tx.send(event).is_err() {
+                break;
+            }
+            wakeup.notify();
+        }
+        debug!("MQTT connection closed");
+    }
+}
+
+impl MqttClient for RumqttClient {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), MqttError> {
+        self.client
+            .try_publish(topic, QoS::AtLeastOnce, retain, payload)?;
+        Ok(())
+    }
+
+    fn subscribe(&mut self, topic: &str) -> Result<(), MqttError> {
+        self.client.try_subscribe(topic, QoS::AtLeastOnce)?;
+        Ok(())
+    }
+
+    fn try_recv(&mut self) -> Option<MqttEvent> {
+        self.events.try_recv().ok()
+    }
+
+    fn disconnect(&mut self) -> Result<(), MqttError> {
+        self.client.try_disconnect()?;
+        Ok(())
+    }
+}
+
+/// A fan command received on one of the command topics
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FanCommand {
+    /// Hold the fan at a fixed speed
+    Override(FanSpeed),
+    /// Return to automatic control
+    Auto,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+struct State {
+    temperature: DegreesC,
+    fan_speed: FanSpeed,
+    overridden: bool,
+    tach: Option<TachReading>,
+}
+
+/// Publishes the temperature and fan speed along with Home Assistant discovery
+/// payloads, and receives fan commands
+pub struct MqttBridge<C> {
+    client: C,
+    config: MqttConfig,
+    /// Names of the fans, the main one first
+    fans: Vec<String>,
+    /// Last state published, by fan name
+    states: BTreeMap<String, State>,
+    units: TemperatureUnit,
+    tachometer: bool,
+}
+
+impl<C: MqttClient> MqttBridge<C> {
+    pub fn new(client: C, config: MqttConfig) -> Self {
+        MqttBridge {
+            client,
+            config,
+            fans: vec![MAIN_FAN.to_string()],
+            states: BTreeMap::new(),
+            units: TemperatureUnit::Celsius,
+            tachometer: false,
+        }
+    }
+
+    /// Announces the fans besides the main one too, and takes their commands
+    pub fn with_fans<I: IntoIterator<Item = String>>(mut self, names: I) -> Self {
+        self.fans.extend(names);
+        self
+    }
+
+    /// Unit of the published temperature
+    pub fn with_units(mut self, units: TemperatureUnit) -> Self {
+        self.units = units;
+        self
+    }
+
+    /// Announces the fan RPM and stall alarm too
+    pub fn with_tachometer(mut self) -> Self {
+        self.tachometer = true;
+        self
+    }
+
+    /// Publishes the discovery payloads, marks the device available and
+    /// subscribes to the command topics
+    pub fn announce(&mut self) -> Result<(), MqttError> {
+        for (topic, payload) in self.discovery_payloads() {
+            debug!("Publishing discovery payload to {}", topic);
+            self.client
+                .publish(&topic, serde_json::to_string(&payload)?.as_bytes(), true)?;
+        }
+        self.client.publish(
+            &self.config.availability_topic(),
+            PAYLOAD_ONLINE.as_bytes(),
+            true,
+        )?;
+        for fan in self.fans.iter() {
+            let topics = self.config.fan_topics(fan);
+            self.client.subscribe(&topics.command)?;
+            self.client.subscribe(&topics.percentage_command)?;
+            self.client.subscribe(&topics.preset_mode_command)?;
+        }
+        for (fan, state) in self.states.clone() {
+            self.publish(&fan, state)?;
+        }
+        Ok(())
+    }
+
+    /// Publishes the state of the named fan
+    pub fn publish_state(
+        &mut self,
+        fan: &str,
+        temperature: DegreesC,
+        fan_speed: FanSpeed,
+        overridden: bool,
+        tach: Option<TachReading>,
+    ) -> Result<(), MqttError> {
+        let state = State {
+            temperature,
+            fan_speed,
+            overridden,
+            tach,
+        };
+        self.states.insert(fan.to_string(), state);
+        self.publish(fan, state)
+    }
+
+    /// Handles the pending client events, returning the most recent command for each
+    /// fan, by name
+    pub fn poll(&mut self) -> Result<BTreeMap<String, FanCommand>, MqttError> {
+        let mut cmds = BTreeMap::new();
+        while let Some(event) = self.client.try_recv() {
+            match event {
+                MqttEvent::Connected => self.announce()?,
+                MqttEvent::Message { topic, payload } => {
+                    if let Some((fan, c)) = self.parse_command(&topic, &payload) {
+                        debug!("Received fan command {:?} for {}", c, fan);
+                        cmds.insert(fan, c);
+                    }
+                }
+            }
+        }
+        Ok(cmds)
+    }
+
+    /// Marks the device unavailable and disconnects from the broker
+    pub fn disconnect(&mut self) -> Result<(), MqttError> {
+        self.client.publish(
+            &self.config.availability_topic(),
+            PAYLOAD_OFFLINE.as_bytes(),
+            true,
+        )?;
+        self.client.disconnect()
+    }
+
+    fn publish(&mut self, fan: &str, state: State) -> Result<(), MqttError> {
+        let fan_speed = u8::from(state.fan_speed);
+        let mut payload = json!({
+            "temperature": state.temperature.in_unit(self.units),
+            "fan_speed": fan_speed,
+            "state": if fan_speed == 0 { PAYLOAD_OFF } else { PAYLOAD_ON },
+            "preset_mode": if state.overridden { None } else { Some(PRESET_AUTO) },
+        });
+        if let Some(t) = state.tach {
+            payload["rpm"] = json!(t.rpm);
+            payload["stalled"] = json!(if t.stalled { PAYLOAD_ON } else { PAYLOAD_OFF });
+        }
+        self.client.publish(
+            &self.config.fan_topics(fan).state,
+            serde_json::to_string(&payload)?.as_bytes(),
+            false,
+        )
+    }
+
+    /// The fan the topic belongs to, along with the command
+    fn parse_command(&self, topic: &str, payload: &[u8]) -> Option<(String, FanCommand)> {
+        let payload = String::from_utf8_lossy(payload);
+        let payload = payload.trim();
+        let (fan, topics) = self
+            .fans
+            .iter()
+            .map(|f| (f, self.config.fan_topics(f)))
+            .find(|(_, t)| {
+                topic == t.command
+                    || topic == t.percentage_command
+                    || topic == t.preset_mode_command
+            })?;
+        let cmd = if topic == topics.command {
+            match payload {
+                PAYLOAD_ON => Some(FanCommand::Auto),
+                PAYLOAD_OFF => Some(FanCommand::Override(FanSpeed::MIN)),
+                _ => None,
+            }
+        } else if topic == topics.percentage_command {
+            payload.parse::<FanSpeed>().ok().map(FanCommand::Override)
+        } else if payload == PRESET_AUTO {
+            Some(FanCommand::Auto)
+        } else {
+            None
+        };
+        if cmd.is_none() {
+            warn!("Ignoring invalid command '{}' on topic {}", payload, topic);
+        }
+        cmd.map(|c| (fan.clone(), c))
+    }
+
+    fn discovery_payloads(&self) -> Vec<(String, serde_json::Value)> {
+        let c = &self.config;
+        let prefix = &c.discovery_prefix;
+        let node_id = c.node_id();
+        let device = json!({
+            "identifiers": [c.client_id],
+            "name": format!("Argon ONE {}", c.client_id),
+            "manufacturer": "Argon40",
+            "model": "Argon ONE M.2",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        let mut payloads = Vec::new();
+        for fan in self.fans.iter() {
+            // The main fan's entities keep their original IDs and names
+            let (id, names) = if fan == MAIN_FAN {
+                let names = ["CPU temperature", "Fan speed", "Fan"].map(String::from);
+                (String::new(), names)
+            } else {
+                let names = [
+                    format!("Fan {} temperature", fan),
+                    format!("Fan {} speed", fan),
+                    format!("Fan {}", fan),
+                ];
+                (format!("fan_{}_", fan), names)
+            };
+            let topics = c.fan_topics(fan);
+            payloads.push((
+                format!("{}/sensor/{}/{}temperature/config", prefix, node_id, id),
+                json!({
+                    "name": names[0],
+                    "unique_id": format!("{}_{}temperature", node_id, id),
+                    "device_class": "temperature",
+                    "state_class": "measurement",
+                    "unit_of_measurement": format!("°{}", self.units.symbol()),
+                    "state_topic": topics.state,
+                    "value_template": "{{ value_json.temperature }}",
+                    "availability_topic": c.availability_topic(),
+                    "device": device,
+                }),
+            ));
+            payloads.push((
+                format!("{}/sensor/{}/{}fan_speed/config", prefix, node_id, id),
+                json!({
+                    "name": names[1],
+                    "unique_id": format!("{}_{}fan_speed", node_id, id),
+                    "state_class": "measurement",
+                    "unit_of_measurement": "%",
+                    "icon": "mdi:fan",
+                    "state_topic": topics.state,
+                    "value_template": "{{ value_json.fan_speed }}",
+                    "availability_topic": c.availability_topic(),
+                    "device": device,
+                }),
+            ));
+            payloads.push((
+                format!("{}/fan/{}/{}fan/config", prefix, node_id, id),
+                json!({
+                    "name": names[2],
+                    "unique_id": format!("{}_{}fan", node_id, id),
+                    "state_topic": topics.state,
+                    "state_value_template": "{{ value_json.state }}",
+                    "command_topic": topics.command,
+                    "percentage_state_topic": topics.state,
+                    "percentage_value_template": "{{ value_json.fan_speed }}",
+                    "percentage_command_topic": topics.percentage_command,
+                    "preset_mode_state_topic": topics.state,
+                    "preset_mode_value_template": "{{ value_json.preset_mode }}",
+                    "preset_mode_command_topic": topics.preset_mode_command,
+                    "preset_modes": [PRESET_AUTO],
+                    "availability_topic": c.availability_topic(),
+                    "device": device,
+                }),
+            ));
+        }
+        if self.tachometer {
+            payloads.push((
+                format!("{}/sensor/{}/rpm/config", prefix, node_id),
+                json!({
+                    "name": "Fan RPM",
+                    "unique_id": format!("{}_rpm", node_id),
+                    "state_class": "measurement",
+                    "unit_of_measurement": "RPM",
+                    "icon": "mdi:fan",
+                    "state_topic": c.state_topic(),
+                    "value_template": "{{ value_json.rpm }}",
+                    "availability_topic": c.availability_topic(),
+                    "device": device,
+                }),
+            ));
+            payloads.push((
+                format!("{}/binary_sensor/{}/stalled/config", prefix, node_id),
+                json!({
+                    "name": "Fan stalled",
+                    "unique_id": format!("{}_stalled", node_id),
+                    "device_class": "problem",
+                    "state_topic": c.state_topic(),
+                    "value_template": "{{ value_json.stalled }}",
+                    "availability_topic": c.availability_topic(),
+                    "device": device,
+                }),
+            ));
+        }
+        payloads
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::BytesMut;
+    use rumqttc::mqttbytes::v4::{self, ConnAck, ConnectReturnCode, PubAck, SubAck};
+    use rumqttc::{PingResp, Publish, SubscribeReasonCode};
+    use std::collections::BTreeMap;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[derive(Default)]
+    struct BrokerState {
+        subscriptions: Vec<(TcpStream, String)>,
+        retained: BTreeMap<String, Vec<u8>>,
+    }
+
+    /// Minimal QoS 0/1 MQTT 3.1.1 broker on loopback
+    struct TestBroker {
+        addr: SocketAddr,
+    }
+
+    impl TestBroker {
+        fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let state = Arc::new(Mutex::new(BrokerState::default()));
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = stream.unwrap();
+                    let state = state.clone();
+                    thread::spawn(move || Self::serve(stream, state));
+                }
+            });
+            TestBroker { addr }
+        }
+
+        fn config(&self, client_id: &str) -> MqttConfig {
+            MqttConfig {
+                host: self.addr.ip().to_string(),
+                port: self.addr.port(),
+                client_id: client_id.to_string(),
+                username: None,
+                password: None,
+                base_topic: None,
+                discovery_prefix: HA_DISCOVERY_PREFIX.to_string(),
+                override_timeout_seconds: None,
+            }
+        }
+
+        fn send<F>(stream: &mut TcpStream, f: F)
+        where
+            F: FnOnce(&mut BytesMut) -> Result<usize, rumqttc::Error>,
+        {
+            let mut buf = BytesMut::new();
+            f(&mut buf).unwrap();
+            let _ = stream.write_all(&buf);
+        }
+
+        fn serve(mut stream: TcpStream, state: Arc<Mutex<BrokerState>>) {
+            let mut buf = BytesMut::new();
+            let mut read_buf = [0_u8; 1024];
+            loop {
+                let packet = match v4::read(&mut buf, 1024 * 1024) {
+                    Ok(p) => p,
+                    Err(rumqttc::Error::InsufficientBytes(_)) => {
+                        match stream.read(&mut read_buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => buf.extend_from_slice(&read_buf[..n]),
+                        }
+                        continue;
+                    }
+                    Err(e) => panic!("Test broker read error {}", e),
+                };
+                let mut state = state.lock().unwrap();
+                match packet {
+                    Packet::Connect(_) => Self::send(&mut stream, |b| {
+                        ConnAck::new(ConnectReturnCode::Success, false).write(b)
+                    }),
+                    Packet::Subscribe(s) => {
+                        let mut codes = Vec::new();
+                        for f in s.filters.iter() {
+                            let sub = stream.try_clone().unwrap();
+                            state.subscriptions.push((sub, f.path.clone()));
+                            codes.push(SubscribeReasonCode::Success(f.qos));
+                        }
+                        Self::send(&mut stream, |b| SubAck::new(s.pkid, codes).write(b));
+                        for f in s.filters.iter() {
+                            for (topic, payload) in state.retained.iter() {
+                                if rumqttc::matches(topic, &f.path) {
+                                    let mut p =
+                                        Publish::new(topic, QoS::AtMostOnce, payload.clone());
+                                    p.retain = true;
+                                    Self::send(&mut stream, |b| p.write(b));
+                                }
+                            }
+                        }
+                    }
+                    Packet::Publish(p) => {
+                        if p.qos == QoS::AtLeastOnce {
+                            Self::send(&mut stream, |b| PubAck::new(p.pkid).write(b));
+                        }
+                        if p.retain {
+                            state.retained.insert(p.topic.clone(), p.payload.to_vec());
+                        }
+                        let fwd = Publish::new(&p.topic, QoS::AtMostOnce, p.payload.to_vec());
+                        for (sub, filter) in state.subscriptions.iter_mut() {
+                            if rumqttc::matches(&p.topic, filter) {
+                                Self::send(sub, |b| fwd.write(b));
+                            }
+                        }
+                    }
+                    Packet::PingReq => Self::send(&mut stream, |b| PingResp.write(b)),
+                    Packet::Disconnect => return,
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    fn wait_for<F, T>(mut f: F) -> T
+    where
+        F: FnMut() -> Option<T>,
+    {
+        let start = Instant::now();
+        loop {
+            if let Some(t) = f() {
+                return t;
+            }
+            assert!(start.elapsed() < TIMEOUT, "Timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Stands in for Home Assistant, subscribed to everything
+    struct Observer {
+        client: RumqttClient,
+        messages: BTreeMap<String, Vec<u8>>,
+    }
+
+    impl Observer {
+        fn new(config: &MqttConfig) -> Self {
+            let mut client = RumqttClient::new(config, Wakeup::new()).unwrap();
+            wait_for(|| match client.try_recv() {
+                Some(MqttEvent::Connected) => Some(()),
+                _ => None,
+            });
+            client.subscribe("#").unwrap();
+            Observer {
+                client,
+                messages: BTreeMap::new(),
+            }
+        }
+
+        fn take_raw(&mut self, topic: &str) -> Option<Vec<u8>> {
+            while let Some(event) = self.client.try_recv() {
+                if let MqttEvent::Message { topic, payload } = event {
+                    self.messages.insert(topic, payload);
+                }
+            }
+            self.messages.remove(topic)
+        }
+
+        fn take(&mut self, topic: &str) -> Option<serde_json::Value> {
+            self.take_raw(topic)
+                .map(|p| serde_json::from_slice(&p).unwrap())
+        }
+    }
+
+    #[test]
+    fn topics() {
+        let mut config = TestBroker {
+            addr: ([127, 0, 0, 1], MQTT_PORT).into(),
+        }
+        .config("my pi.local");
+        assert_eq!(config.node_id(), "my_pi_local");
+        assert_eq!(config.state_topic(), "argonone/my pi.local/state");
+        config.base_topic = Some("lab/rack1".to_string());
+        assert_eq!(config.command_topic(), "lab/rack1/fan/set");
+        assert_eq!(
+            config.fan_topics("hat").preset_mode_command,
+            "lab/rack1/fans/hat/fan/preset_mode/set"
+        );
+        assert_eq!(config.override_timeout(), None);
+    }
+
+    #[test]
+    fn discovery_state_and_commands() {
+        let broker = TestBroker::start();
+        let config = broker.config("pi-1");
+        let mut ha = Observer::new(&broker.config("home-assistant"));
+        let mut bridge = MqttBridge::new(
+            RumqttClient::new(&config, Wakeup::new()).unwrap(),
+            config.clone(),
+        )
+        .with_tachometer()
+        .with_fans(vec!["hat".to_string()]);
+
+        // The device announces itself once connected
+        let temp = wait_for(|| {
+            assert!(bridge.poll().unwrap().is_empty());
+            ha.take("homeassistant/sensor/pi-1/temperature/config")
+        });
+        assert_eq!(temp["device_class"], "temperature");
+        assert_eq!(temp["unit_of_measurement"], "°C");
+        assert_eq!(temp["state_topic"], "argonone/pi-1/state");
+        assert_eq!(temp["device"]["identifiers"][0], "pi-1");
+        let fan = wait_for(|| ha.take("homeassistant/fan/pi-1/fan/config"));
+        assert_eq!(
+            fan["percentage_command_topic"],
+            "argonone/pi-1/fan/percentage/set"
+        );
+        assert_eq!(fan["availability_topic"], "argonone/pi-1/availability");
+        assert!(wait_for(|| ha.take("homeassistant/sensor/pi-1/fan_speed/config")).is_object());
+        let stalled = wait_for(|| ha.take("homeassistant/binary_sensor/pi-1/stalled/config"));
+        assert_eq!(stalled["device_class"], "problem");
+        let hat = wait_for(|| ha.take("homeassistant/fan/pi-1/fan_hat_fan/config"));
+        assert_eq!(hat["name"], "Fan hat");
+        assert_eq!(hat["unique_id"], "pi-1_fan_hat_fan");
+        assert_eq!(hat["command_topic"], "argonone/pi-1/fans/hat/fan/set");
+        let hat_temp = wait_for(|| ha.take("homeassistant/sensor/pi-1/fan_hat_temperature/config"));
+        assert_eq!(hat_temp["state_topic"], "argonone/pi-1/fans/hat/state");
+
+        bridge
+            .publish_state(
+                MAIN_FAN,
+                DegreesC::from_tenths(485),
+                FanSpeed::new(30).unwrap(),
+                false,
+                Some(TachReading {
+                    rpm: 1100,
+                    stalled: false,
+                }),
+            )
+            .unwrap();
+        let state = wait_for(|| ha.take(&config.state_topic()));
+        assert_eq!(
+            state,
+            json!({
+                "temperature": 48.5,
+                "fan_speed": 30,
+                "state": "ON",
+                "preset_mode": "auto",
+                "rpm": 1100,
+                "stalled": "OFF",
+            })
+        );
+
+        ha.client
+            .publish(&config.percentage_command_topic(), b"75", false)
+            .unwrap();
+        let cmd = wait_for(|| bridge.poll().unwrap().remove(MAIN_FAN));
+        assert_eq!(cmd, FanCommand::Override(FanSpeed::new(75).unwrap()));
+
+        // The other fans have their own state and command topics
+        let hat = config.fan_topics("hat");
+        bridge
+            .publish_state("hat", DegreesC::new(40), FanSpeed::MAX, true, None)
+            .unwrap();
+        let state = wait_for(|| ha.take(&hat.state));
+        assert_eq!(state["fan_speed"], 100);
+        assert_eq!(state["preset_mode"], serde_json::Value::Null);
+        ha.client
+            .publish(&hat.percentage_command, b"20", false)
+            .unwrap();
+        let cmds = wait_for(|| Some(bridge.poll().unwrap()).filter(|c| !c.is_empty()));
+        assert_eq!(
+            cmds.into_iter().collect::<Vec<_>>(),
+            vec![(
+                "hat".to_string(),
+                FanCommand::Override(FanSpeed::new(20).unwrap())
+            )]
+        );
+
+        ha.client
+            .publish(&config.command_topic(), b"OFF", false)
+            .unwrap();
+        let cmd = wait_for(|| bridge.poll().unwrap().remove(MAIN_FAN));
+        assert_eq!(cmd, FanCommand::Override(FanSpeed::MIN));
+
+        ha.client
+            .publish(&config.preset_mode_command_topic(), b"auto", false)
+            .unwrap();
+        let cmd = wait_for(|| bridge.poll().unwrap().remove(MAIN_FAN));
+        assert_eq!(cmd, FanCommand::Auto);
+
+        // Invalid commands are ignored
+        ha.client
+            .publish(&config.percentage_command_topic(), b"101", false)
+            .unwrap();
+        ha.client
+            .publish(&config.command_topic(), b"ON", false)
+            .unwrap();
+        let cmd = wait_for(|| bridge.poll().unwrap().remove(MAIN_FAN));
+        assert_eq!(cmd, FanCommand::Auto);
+
+        bridge.disconnect().unwrap();
+        wait_for(|| {
+            ha.take_raw(&config.availability_topic())
+                .filter(|p| p == PAYLOAD_OFFLINE.as_bytes())
+        });
+    }
+}
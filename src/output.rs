@@ -0,0 +1,363 @@
+use crate::{
+    DaemonReport, DegreesC, FanSpeed, SensorFile, TachReading, TemperatureUnit, UnitTemperature,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, err_derive::Error)]
+pub enum ParseOutputFormatError {
+    #[error(display = "Invalid output format '{}', expected text or json", _0)]
+    Invalid(String),
+}
+
+/// Output of the one-shot commands. The JSON field names are stable, temperatures are
+/// in the "unit" field's unit and fan speeds are percentages.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl OutputFormat {
+    /// The text form, or JSON on a single line
+    pub fn render<T: Serialize + fmt::Display>(
+        self,
+        report: &T,
+    ) -> Result<String, serde_json::Error> {
+        match self {
+            OutputFormat::Text => Ok(report.to_string()),
+            OutputFormat::Json => serde_json::to_string(report),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = ParseOutputFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(ParseOutputFormatError::Invalid(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Text => f.write_str("text"),
+            OutputFormat::Json => f.write_str("json"),
+        }
+    }
+}
+
+/// The temperature read from the firmware, for --get-temp
+#[derive(Copy, Clone, PartialEq, Debug, Serialize)]
+pub struct TemperatureReport {
+    pub temperature: UnitTemperature,
+    pub unit: TemperatureUnit,
+}
+
+impl TemperatureReport {
+    pub fn new(temperature: DegreesC, unit: TemperatureUnit) -> Self {
+        TemperatureReport {
+            temperature: temperature.in_unit(unit),
+            unit,
+        }
+    }
+}
+
+impl fmt::Display for TemperatureReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Temperature: {}", self.temperature)
+    }
+}
+
+/// The fan speed the daemon last commanded, for the fan-speed subcommand
+#[derive(Copy, Clone, PartialEq, Debug, Serialize)]
+pub struct FanSpeedReport {
+    pub fan_speed: FanSpeed,
+    pub overridden: bool,
+    /// Read back by the tachometer, if any
+    pub rpm: Option<u32>,
+    pub stalled: bool,
+}
+
+impl FanSpeedReport {
+    pub fn new(fan_speed: FanSpeed, overridden: bool, tach: Option<TachReading>) -> Self {
+        FanSpeedReport {
+            fan_speed,
+            overridden,
+            rpm: tach.map(|t| t.rpm),
+            stalled: tach.is_some_and(|t| t.stalled),
+        }
+    }
+}
+
+impl fmt::Display for FanSpeedReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Fan speed: {}", self.fan_speed)?;
+        write_fan_notes(f, self.overridden, self.rpm, self.stalled)
+    }
+}
+
+/// " (override)", the RPM and the stall alarm, after a fan speed
+fn write_fan_notes(
+    f: &mut fmt::Formatter<'_>,
+    overridden: bool,
+    rpm: Option<u32>,
+    stalled: bool,
+) -> fmt::Result {
+    if overridden {
+        f.write_str(" (override)")?;
+    }
+    if let Some(rpm) = rpm {
+        write!(f, ", {} RPM", rpm)?;
+    }
+    if stalled {
+        f.write_str(", stalled")?;
+    }
+    Ok(())
+}
+
+/// The daemon's profile and last update, for the profile subcommand
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct StatusReport {
+    pub profile: Option<String>,
+    pub selected: bool,
+    pub temperature: Option<UnitTemperature>,
+    pub unit: TemperatureUnit,
+    pub fan_speed: Option<FanSpeed>,
+    pub overridden: bool,
+    pub rpm: Option<u32>,
+    pub stalled: bool,
+    /// Fans besides the main one, by name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fans: BTreeMap<String, FanStatus>,
+}
+
+/// The last update of a fan besides the main one, in a status report
+#[derive(Copy, Clone, PartialEq, Debug, Serialize)]
+pub struct FanStatus {
+    pub temperature: Option<UnitTemperature>,
+    pub fan_speed: Option<FanSpeed>,
+    pub overridden: bool,
+}
+
+impl StatusReport {
+    pub fn new(report: DaemonReport, unit: TemperatureUnit) -> Self {
+        StatusReport {
+            profile: report.profile,
+            selected: report.selected,
+            temperature: report.temperature.map(|t| t.in_unit(unit)),
+            unit,
+            fan_speed: report.fan_speed,
+            overridden: report.overridden,
+            rpm: report.tach.map(|t| t.rpm),
+            stalled: report.tach.is_some_and(|t| t.stalled),
+            fans: report
+                .fans
+                .into_iter()
+                .map(|(name, f)| {
+                    let status = FanStatus {
+                        temperature: f.temperature.map(|t| t.in_unit(unit)),
+                        fan_speed: f.fan_speed,
+                        overridden: f.overridden,
+                    };
+                    (name, status)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for StatusReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.profile {
+            None => f.write_str("no profiles")?,
+            Some(p) if self.selected => write!(f, "profile {} (selected)", p)?,
+            Some(p) => write!(f, "profile {} (scheduled)", p)?,
+        }
+        if let (Some(t), Some(s)) = (self.temperature, self.fan_speed) {
+            write!(f, "\nTemp {}, fan speed {}", t, s)?;
+            write_fan_notes(f, self.overridden, self.rpm, self.stalled)?;
+        }
+        for (name, fan) in self.fans.iter() {
+            if let (Some(t), Some(s)) = (fan.temperature, fan.fan_speed) {
+                write!(f, "\n{}: temp {}, fan speed {}", name, t, s)?;
+                write_fan_notes(f, fan.overridden, None, false)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The temperature files a fan's sensor can be, for the list-sensors subcommand
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct SensorsReport {
+    pub unit: TemperatureUnit,
+    pub sensors: Vec<SensorStatus>,
+}
+
+/// A temperature file and its reading, None if it couldn't be read
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct SensorStatus {
+    pub name: String,
+    pub path: PathBuf,
+    pub temperature: Option<UnitTemperature>,
+}
+
+impl SensorsReport {
+    pub fn new(sensors: Vec<(SensorFile, Option<DegreesC>)>, unit: TemperatureUnit) -> Self {
+        SensorsReport {
+            unit,
+            sensors: sensors
+                .into_iter()
+                .map(|(file, t)| SensorStatus {
+                    name: file.name,
+                    path: file.path,
+                    temperature: t.map(|t| t.in_unit(unit)),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for SensorsReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.sensors.is_empty() {
+            return f.write_str("No temperature files found");
+        }
+        for (i, s) in self.sensors.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let temperature = s.temperature.map_or("-".to_string(), |t| t.to_string());
+            write!(f, "{:<24} {:>8}  {}", s.name, temperature, s.path.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FanReport;
+
+    #[test]
+    fn render() {
+        let report = StatusReport::new(
+            DaemonReport {
+                profile: Some("quiet".to_string()),
+                selected: false,
+                temperature: Some(DegreesC::from_tenths(485)),
+                fan_speed: FanSpeed::new(30),
+                overridden: true,
+                tach: Some(TachReading {
+                    rpm: 0,
+                    stalled: true,
+                }),
+                fans: BTreeMap::new(),
+            },
+            TemperatureUnit::Celsius,
+        );
+        assert_eq!(
+            OutputFormat::Text.render(&report).unwrap(),
+            "profile quiet (scheduled)\nTemp 48.5 C, fan speed 30% (override), 0 RPM, stalled"
+        );
+        assert_eq!(
+            OutputFormat::Json.render(&report).unwrap(),
+            "{\"profile\":\"quiet\",\"selected\":false,\"temperature\":48.5,\"unit\":\"celsius\",\
+             \"fan_speed\":30,\"overridden\":true,\"rpm\":0,\"stalled\":true}"
+        );
+
+        let mut fans = BTreeMap::new();
+        fans.insert(
+            "hat".to_string(),
+            FanReport {
+                temperature: Some(DegreesC::new(40)),
+                fan_speed: FanSpeed::new(55),
+                overridden: false,
+            },
+        );
+        fans.insert("nvme".to_string(), FanReport::default());
+        let report = StatusReport::new(
+            DaemonReport {
+                temperature: Some(DegreesC::new(50)),
+                fan_speed: FanSpeed::new(60),
+                fans,
+                ..Default::default()
+            },
+            TemperatureUnit::Fahrenheit,
+        );
+        assert_eq!(
+            OutputFormat::Text.render(&report).unwrap(),
+            "no profiles\nTemp 122 F, fan speed 60%\nhat: temp 104 F, fan speed 55%"
+        );
+        assert!(OutputFormat::Json.render(&report).unwrap().ends_with(
+            "\"fans\":{\"hat\":{\"temperature\":104,\"fan_speed\":55,\"overridden\":false},\
+                        \"nvme\":{\"temperature\":null,\"fan_speed\":null,\"overridden\":false}}}"
+        ));
+
+        let report = TemperatureReport::new(DegreesC::new(50), TemperatureUnit::Fahrenheit);
+        assert_eq!(
+            OutputFormat::Text.render(&report).unwrap(),
+            "Temperature: 122 F"
+        );
+        assert_eq!(
+            OutputFormat::Json.render(&report).unwrap(),
+            "{\"temperature\":122,\"unit\":\"fahrenheit\"}"
+        );
+        let report = FanSpeedReport::new(
+            FanSpeed::new(42).unwrap(),
+            false,
+            Some(TachReading {
+                rpm: 1200,
+                stalled: false,
+            }),
+        );
+        assert_eq!(
+            OutputFormat::Text.render(&report).unwrap(),
+            "Fan speed: 42%, 1200 RPM"
+        );
+
+        let file = |name: &str, path: &str| SensorFile {
+            name: name.to_string(),
+            path: PathBuf::from(path),
+        };
+        let report = SensorsReport::new(
+            vec![
+                (
+                    file("cpu-thermal", "/sys/class/thermal/thermal_zone0/temp"),
+                    Some(DegreesC::from_tenths(480)),
+                ),
+                (
+                    file("nvme temp2", "/sys/class/hwmon/hwmon1/temp2_input"),
+                    None,
+                ),
+            ],
+            TemperatureUnit::Celsius,
+        );
+        assert_eq!(
+            OutputFormat::Text.render(&report).unwrap(),
+            "cpu-thermal                  48 C  /sys/class/thermal/thermal_zone0/temp\n\
+             nvme temp2                      -  /sys/class/hwmon/hwmon1/temp2_input"
+        );
+        assert_eq!(
+            OutputFormat::Json.render(&report).unwrap(),
+            "{\"unit\":\"celsius\",\"sensors\":[\
+             {\"name\":\"cpu-thermal\",\"path\":\"/sys/class/thermal/thermal_zone0/temp\",\
+             \"temperature\":48},\
+             {\"name\":\"nvme temp2\",\"path\":\"/sys/class/hwmon/hwmon1/temp2_input\",\
+             \"temperature\":null}]}"
+        );
+
+        assert_eq!("json".parse::<OutputFormat>(), Ok(OutputFormat::Json));
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+}
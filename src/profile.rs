@@ -0,0 +1,293 @@
+use crate::{DegreesC, FanSpeed, FanSpeedMap, FanSpeedMapError};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// Name of the profile made of the top level configuration fields
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Fan speed policy, the configuration file top level fields are the default profile
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct Profile {
+    /// Min temp, degrees C
+    pub temperature_min: DegreesC,
+    /// Max temp, degrees C
+    pub temperature_max: DegreesC,
+    /// Min fan speed percentage
+    pub fan_speed_min: FanSpeed,
+    /// Max fan speed percentage
+    pub fan_speed_max: FanSpeed,
+    /// Degrees C the temperature must drop by before the fan slows down
+    #[serde(default)]
+    pub hysteresis: DegreesC,
+    /// At or above this temperature the fan runs at 100%, regardless of the max fan speed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub safety_temperature: Option<DegreesC>,
+}
+
+impl Profile {
+    pub fn fan_speed_map(&self) -> Result<FanSpeedMap, FanSpeedMapError> {
+        FanSpeedMap::new(
+            self.temperature_min,
+            self.temperature_max,
+            self.fan_speed_min,
+            self.fan_speed_max,
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, err_derive::Error)]
+pub enum ParseTimeOfDayError {
+    #[error(display = "Invalid time of day '{}', expected HH:MM", _0)]
+    Invalid(String),
+}
+
+/// Local time of day, HH:MM
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u16);
+
+impl TimeOfDay {
+    pub fn new(hour: u8, minute: u8) -> Option<Self> {
+        if hour < 24 && minute < 60 {
+            Some(TimeOfDay(u16::from(hour) * 60 + u16::from(minute)))
+        } else {
+            None
+        }
+    }
+
+    fn minutes(t: NaiveTime) -> u16 {
+        (t.hour() * 60 + t.minute()) as u16
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = ParseTimeOfDayError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseTimeOfDayError::Invalid(s.to_string());
+        let mut parts = s.trim().splitn(2, ':');
+        let hour = parts.next().and_then(|h| h.parse().ok()).ok_or_else(err)?;
+        let minute = parts.next().and_then(|m| m.parse().ok()).ok_or_else(err)?;
+        TimeOfDay::new(hour, minute).ok_or_else(err)
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = ParseTimeOfDayError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> Self {
+        t.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, err_derive::Error)]
+pub enum ParseDayError {
+    #[error(display = "Invalid day of the week '{}'", _0)]
+    Invalid(String),
+}
+
+/// Day of the week, "mon" through "sun"
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Day(u8);
+
+impl From<Weekday> for Day {
+    fn from(d: Weekday) -> Self {
+        Day(d.num_days_from_monday() as u8)
+    }
+}
+
+impl FromStr for Day {
+    type Err = ParseDayError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim()
+            .parse::<Weekday>()
+            .map(Day::from)
+            .map_err(|_| ParseDayError::Invalid(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for Day {
+    type Error = ParseDayError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Day> for String {
+    fn from(d: Day) -> Self {
+        d.to_string()
+    }
+}
+
+impl fmt::Display for Day {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+        f.write_str(NAMES[usize::from(self.0 % 7)])
+    }
+}
+
+/// Uses a profile from start until end, local time. Windows ending before they
+/// start run past midnight and belong to the day they start on.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct ScheduleEntry {
+    pub profile: String,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    /// Days the window starts on, every day when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Day>,
+}
+
+impl ScheduleEntry {
+    pub fn is_active(&self, local: NaiveDateTime) -> bool {
+        let t = TimeOfDay(TimeOfDay::minutes(local.time()));
+        let today = Day::from(local.weekday());
+        let yesterday = Day::from(local.weekday().pred());
+        if self.start <= self.end {
+            self.start <= t && t < self.end && self.on(today)
+        } else if t >= self.start {
+            self.on(today)
+        } else {
+            t < self.end && self.on(yesterday)
+        }
+    }
+
+    fn on(&self, day: Day) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+}
+
+/// Named profiles and the schedule selecting between them
+#[derive(Clone, PartialEq, Debug)]
+pub struct ProfileSchedule {
+    profiles: BTreeMap<String, Profile>,
+    schedule: Vec<ScheduleEntry>,
+}
+
+impl ProfileSchedule {
+    /// `profiles` must contain the default profile and every scheduled profile
+    pub fn new(profiles: BTreeMap<String, Profile>, schedule: Vec<ScheduleEntry>) -> Self {
+        assert!(profiles.contains_key(DEFAULT_PROFILE), "No default profile");
+        assert!(
+            schedule.iter().all(|e| profiles.contains_key(&e.profile)),
+            "Unknown scheduled profile"
+        );
+        ProfileSchedule { profiles, schedule }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.get(name)
+    }
+
+    /// Profile names, in order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
+
+    /// The first schedule entry active at `local` wins, otherwise the default profile
+    pub fn active(&self, local: NaiveDateTime) -> (&str, &Profile) {
+        let name = self
+            .schedule
+            .iter()
+            .find(|e| e.is_active(local))
+            .map(|e| e.profile.as_str())
+            .unwrap_or(DEFAULT_PROFILE);
+        (name, &self.profiles[name])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2022-01-03 is a Monday
+        NaiveDate::from_ymd_opt(2022, 1, 2 + day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn entry(start: &str, end: &str, days: &[&str]) -> ScheduleEntry {
+        ScheduleEntry {
+            profile: "quiet".to_string(),
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            days: days.iter().map(|d| d.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn parse_time_and_day() {
+        assert_eq!("07:05".parse::<TimeOfDay>(), Ok(TimeOfDay(7 * 60 + 5)));
+        assert_eq!(TimeOfDay::new(23, 59).unwrap().to_string(), "23:59");
+        assert!("24:00".parse::<TimeOfDay>().is_err());
+        assert!("7".parse::<TimeOfDay>().is_err());
+        assert_eq!("Friday".parse::<Day>(), Ok(Day(4)));
+        assert_eq!("sun".parse::<Day>().unwrap().to_string(), "sun");
+        assert!("someday".parse::<Day>().is_err());
+    }
+
+    #[test]
+    fn schedule_windows() {
+        let e = entry("09:00", "17:00", &["mon", "tue"]);
+        assert!(e.is_active(at(1, 9, 0)));
+        assert!(e.is_active(at(2, 16, 59)));
+        assert!(!e.is_active(at(2, 17, 0)));
+        assert!(!e.is_active(at(3, 12, 0)));
+
+        // Past midnight, belongs to the start day
+        let e = entry("22:00", "07:00", &["fri"]);
+        assert!(!e.is_active(at(5, 21, 59)));
+        assert!(e.is_active(at(5, 22, 0)));
+        assert!(e.is_active(at(6, 6, 59)));
+        assert!(!e.is_active(at(6, 7, 0)));
+        assert!(!e.is_active(at(6, 23, 0)));
+
+        let e = entry("22:00", "07:00", &[]);
+        assert!(e.is_active(at(3, 2, 0)));
+        assert!(!e.is_active(at(3, 12, 0)));
+    }
+
+    #[test]
+    fn active_profile() {
+        let default = Profile {
+            temperature_min: DegreesC::new(40),
+            temperature_max: DegreesC::new(60),
+            fan_speed_min: FanSpeed::MIN,
+            fan_speed_max: FanSpeed::MAX,
+            hysteresis: DegreesC::new(0),
+            safety_temperature: None,
+        };
+        let quiet = Profile {
+            fan_speed_max: FanSpeed::new(40).unwrap(),
+            safety_temperature: Some(DegreesC::new(70)),
+            ..default.clone()
+        };
+        let mut profiles = BTreeMap::new();
+        profiles.insert(DEFAULT_PROFILE.to_string(), default.clone());
+        profiles.insert("quiet".to_string(), quiet.clone());
+        let s = ProfileSchedule::new(profiles, vec![entry("22:00", "07:00", &[])]);
+        assert_eq!(s.active(at(1, 12, 0)), (DEFAULT_PROFILE, &default));
+        assert_eq!(s.active(at(1, 23, 0)), ("quiet", &quiet));
+    }
+}
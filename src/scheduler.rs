@@ -1,51 +1,204 @@
-use log::warn;
+use crate::{DegreesC, UpdateIntervalSeconds};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
 use std::time::{Duration, Instant};
 
+/// What to do when the loop falls behind by more than one interval
+#[derive(
+    Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize, Default,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissedTicks {
+    /// Update once, then continue on the original schedule
+    #[default]
+    Skip,
+    /// Update once for every missed interval, back to back
+    CatchUp,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct AdaptiveIntervalConfig {
+    /// Shortest update interval, used while the temperature rises fast or is near the max
+    pub min_interval_seconds: UpdateIntervalSeconds,
+    /// Longest update interval, reached by doubling while the temperature is stable
+    pub max_interval_seconds: UpdateIntervalSeconds,
+    /// Rise rate, degrees C per minute, at or above which the min interval is used
+    #[serde(default = "AdaptiveIntervalConfig::default_rise_rate")]
+    pub rise_rate: DegreesC,
+    /// The min interval is used within this many degrees C of temperature_max
+    #[serde(default = "AdaptiveIntervalConfig::default_top_margin")]
+    pub top_margin: DegreesC,
+}
+
+impl AdaptiveIntervalConfig {
+    fn default_rise_rate() -> DegreesC {
+        DegreesC::new(2)
+    }
+
+    fn default_top_margin() -> DegreesC {
+        DegreesC::new(5)
+    }
+}
+
+impl Default for AdaptiveIntervalConfig {
+    fn default() -> Self {
+        AdaptiveIntervalConfig {
+            min_interval_seconds: UpdateIntervalSeconds(NonZeroU32::new(5).unwrap()),
+            max_interval_seconds: UpdateIntervalSeconds(NonZeroU32::new(120).unwrap()),
+            rise_rate: Self::default_rise_rate(),
+            top_margin: Self::default_top_margin(),
+        }
+    }
+}
+
+/// Adaptive update interval bounds and thresholds
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct AdaptiveInterval {
+    min: Duration,
+    max: Duration,
+    /// Degrees C per minute
+    rise_rate: f32,
+    top_margin: DegreesC,
+    hot_above: f32,
+}
+
+impl AdaptiveInterval {
+    pub fn new(config: &AdaptiveIntervalConfig, temperature_max: DegreesC) -> Self {
+        AdaptiveInterval {
+            min: config.min_interval_seconds.into(),
+            max: config.max_interval_seconds.into(),
+            rise_rate: config.rise_rate.as_f32(),
+            top_margin: config.top_margin,
+            hot_above: temperature_max.saturating_sub(config.top_margin).as_f32(),
+        }
+    }
+
+    /// Moves the min interval threshold along with the profile's temperature_max
+    pub fn set_temperature_max(&mut self, temperature_max: DegreesC) {
+        self.hot_above = temperature_max.saturating_sub(self.top_margin).as_f32();
+    }
+}
+
+/// Deadlines at fixed multiples of the interval from the start, so updates don't drift
 #[derive(Clone, Debug)]
 pub struct Scheduler {
     prev: Instant,
+    next: Instant,
     interval: Duration,
+    missed_ticks: MissedTicks,
+    adaptive: Option<AdaptiveInterval>,
+    last_reading: Option<(Instant, f32)>,
 }
 
 impl Scheduler {
     pub fn new(now: Instant, interval: Duration) -> Self {
         Scheduler {
             prev: now,
+            next: now + interval,
             interval,
+            missed_ticks: MissedTicks::default(),
+            adaptive: None,
+            last_reading: None,
+        }
+    }
+
+    /// Starts at the configured interval, clamped to the adaptive bounds
+    pub fn with_adaptive(mut self, adaptive: AdaptiveInterval) -> Self {
+        self.set_interval(self.interval.max(adaptive.min).min(adaptive.max));
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Updates the adaptive interval's threshold, if adaptive
+    pub fn set_temperature_max(&mut self, temperature_max: DegreesC) {
+        if let Some(a) = self.adaptive.as_mut() {
+            a.set_temperature_max(temperature_max);
         }
     }
 
-    /// True if interval was reached
+    pub fn with_missed_ticks(mut self, missed_ticks: MissedTicks) -> Self {
+        self.missed_ticks = missed_ticks;
+        self
+    }
+
+    /// When the next update is due
+    pub fn next_deadline(&self) -> Instant {
+        self.next
+    }
+
+    /// True if the next deadline was reached
     pub fn update(&mut self, now: Instant) -> bool {
-        match now.checked_duration_since(self.prev) {
-            None => {
-                warn!(
-                    "Scheduler time went backwards, prev={:?}, now={:?}",
-                    self.prev, now
-                );
-                self.prev = now;
-                false
+        if now < self.prev {
+            warn!(
+                "Scheduler time went backwards, prev={:?}, now={:?}",
+                self.prev, now
+            );
+            self.prev = now;
+            self.next = now + self.interval;
+            return false;
+        }
+        if now < self.next {
+            return false;
+        }
+
+        self.prev = now;
+        self.next += self.interval;
+        if self.missed_ticks == MissedTicks::Skip && self.next <= now {
+            let mut skipped = 0;
+            while self.next <= now {
+                self.next += self.interval;
+                skipped += 1;
             }
-            Some(time_since) => {
-                if time_since >= self.interval {
-                    self.prev = now;
-                    true
-                } else {
-                    false
-                }
+            debug!("Skipped {} missed updates", skipped);
+        }
+        true
+    }
+
+    /// Adapts the interval to a temperature reading taken at `now`, if adaptive:
+    /// the min interval while rising fast or near the max, otherwise doubling up to the max
+    pub fn observe(&mut self, now: Instant, temperature: f32) {
+        let adaptive = match self.adaptive {
+            Some(a) => a,
+            None => return,
+        };
+        let rising_fast = match self.last_reading {
+            Some((prev, prev_temp)) if now > prev => {
+                let minutes = (now - prev).as_secs_f32() / 60.0;
+                (temperature - prev_temp) / minutes >= adaptive.rise_rate
             }
+            _ => false,
+        };
+        self.last_reading = Some((now, temperature));
+
+        let interval = if rising_fast || temperature >= adaptive.hot_above {
+            adaptive.min
+        } else {
+            (self.interval * 2).min(adaptive.max)
+        };
+        if interval != self.interval {
+            debug!(
+                "Update interval {:?} -> {:?}, temperature {}",
+                self.interval, interval, temperature
+            );
+            self.set_interval(interval);
         }
     }
+
+    fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+        self.next = self.prev + interval;
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-
-    // TODO
-    // use something like https://crates.io/crates/sn_fake_clock
-    // check that windback works
-    // schedule can repeat
+    use crate::{Clock, ManualClock};
 
     #[test]
     fn tolerates_windback() {
@@ -53,9 +206,112 @@ mod test {
         let past = Instant::now();
         let first = Instant::now();
         let mut sched = Scheduler::new(first, dur);
-        assert_eq!(sched.update(Instant::now()), false);
+        assert!(!sched.update(Instant::now()));
         assert_eq!(sched.prev, first);
-        assert_eq!(sched.update(past), false);
+        assert!(!sched.update(past));
         assert_eq!(sched.prev, past);
     }
+
+    #[test]
+    fn repeats() {
+        let clock = ManualClock::new();
+        let dur = Duration::from_secs(30);
+        let mut sched = Scheduler::new(clock.now(), dur);
+        let mut updates = 0;
+        for _ in 0..3600 {
+            clock.sleep(Duration::from_secs(1));
+            if sched.update(clock.now()) {
+                updates += 1;
+            }
+        }
+        assert_eq!(updates, 120);
+        clock.advance(Duration::from_secs(29));
+        assert!(!sched.update(clock.now()));
+        clock.advance(Duration::from_secs(90));
+        assert!(sched.update(clock.now()));
+        assert!(!sched.update(clock.now()));
+    }
+
+    #[test]
+    fn does_not_drift() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let dur = Duration::from_secs(30);
+        let mut sched = Scheduler::new(start, dur);
+        // Waking up late doesn't push back the following deadlines
+        clock.advance(Duration::from_millis(30_900));
+        assert!(sched.update(clock.now()));
+        assert_eq!(sched.next_deadline(), start + dur * 2);
+        clock.advance(Duration::from_millis(29_100));
+        assert!(sched.update(clock.now()));
+        assert_eq!(sched.next_deadline(), start + dur * 3);
+    }
+
+    #[test]
+    fn adaptive_interval() {
+        let clock = ManualClock::new();
+        let config = AdaptiveIntervalConfig::default();
+        let mut sched = Scheduler::new(clock.now(), Duration::from_secs(30))
+            .with_adaptive(AdaptiveInterval::new(&config, DegreesC::new(65)));
+        let secs = Duration::from_secs;
+
+        // Lengthens while stable
+        let mut intervals = vec![];
+        for _ in 0..5 {
+            clock.sleep(sched.next_deadline() - clock.now());
+            assert!(sched.update(clock.now()));
+            sched.observe(clock.now(), 45.0);
+            intervals.push(sched.interval());
+        }
+        assert_eq!(
+            intervals,
+            vec![secs(60), secs(120), secs(120), secs(120), secs(120)]
+        );
+
+        // Rising 2 C/min drops to the min
+        clock.sleep(sched.next_deadline() - clock.now());
+        assert!(sched.update(clock.now()));
+        sched.observe(clock.now(), 49.0);
+        assert_eq!(sched.interval(), secs(5));
+        assert_eq!(sched.next_deadline(), clock.now() + secs(5));
+
+        // And stays there near the max
+        clock.sleep(secs(5));
+        assert!(sched.update(clock.now()));
+        sched.observe(clock.now(), 60.0);
+        clock.sleep(secs(5));
+        assert!(sched.update(clock.now()));
+        sched.observe(clock.now(), 60.0);
+        assert_eq!(sched.interval(), secs(5));
+
+        clock.sleep(secs(5));
+        assert!(sched.update(clock.now()));
+        sched.observe(clock.now(), 59.0);
+        assert_eq!(sched.interval(), secs(10));
+
+        // A lower temperature_max moves the threshold down
+        sched.set_temperature_max(DegreesC::new(60));
+        clock.sleep(secs(10));
+        assert!(sched.update(clock.now()));
+        sched.observe(clock.now(), 59.0);
+        assert_eq!(sched.interval(), secs(5));
+    }
+
+    #[test]
+    fn missed_ticks() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let dur = Duration::from_secs(30);
+
+        let mut sched = Scheduler::new(start, dur);
+        clock.advance(Duration::from_secs(100));
+        assert!(sched.update(clock.now()));
+        assert!(!sched.update(clock.now()));
+        assert_eq!(sched.next_deadline(), start + dur * 4);
+
+        let mut sched = Scheduler::new(start, dur).with_missed_ticks(MissedTicks::CatchUp);
+        let updates = (0..5).filter(|_| sched.update(clock.now())).count();
+        assert_eq!(updates, 3);
+        assert_eq!(sched.next_deadline(), start + dur * 4);
+    }
 }
@@ -0,0 +1,161 @@
+use crate::{LoadError, Mailbox, MailboxError, TemperatureSource};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, err_derive::Error)]
+pub enum SensorError {
+    #[error(display = "{}", _0)]
+    Mailbox(#[error(from)] MailboxError),
+
+    #[error(display = "{}", _0)]
+    File(#[error(from)] LoadError),
+}
+
+/// Reads a temperature file in millidegrees C, like the kernel's thermal zones
+/// (/sys/class/thermal/thermal_zone0/temp) and hwmon inputs
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct FileSensor(PathBuf);
+
+impl FileSensor {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        FileSensor(path.as_ref().to_path_buf())
+    }
+}
+
+impl TemperatureSource for FileSensor {
+    type Error = LoadError;
+
+    fn temperature(&mut self) -> Result<f32, Self::Error> {
+        let s = fs::read_to_string(&self.0).map_err(|e| LoadError::Io(self.0.clone(), e))?;
+        let millis: i32 = s
+            .trim()
+            .parse()
+            .map_err(|_| LoadError::Parse(self.0.clone()))?;
+        Ok(millis as f32 / 1000.0)
+    }
+}
+
+/// A temperature file under /sys, with the name the kernel gives it
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct SensorFile {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// The thermal zone and hwmon temperature files under `sys` (usually /sys), sorted by
+/// path. Any of them can be a fan's sensor.
+pub fn find_sensor_files<P: AsRef<Path>>(sys: P) -> Vec<SensorFile> {
+    let read_name = |p: PathBuf| fs::read_to_string(p).ok().map(|s| s.trim().to_string());
+    let mut found = Vec::new();
+    for zone in class_devices(&sys, "thermal", "thermal_zone") {
+        let path = zone.join("temp");
+        if path.is_file() {
+            let name = read_name(zone.join("type")).unwrap_or_default();
+            found.push(SensorFile { name, path });
+        }
+    }
+    for hwmon in class_devices(&sys, "hwmon", "hwmon") {
+        let chip = read_name(hwmon.join("name")).unwrap_or_default();
+        let inputs = fs::read_dir(&hwmon).into_iter().flatten().flatten();
+        for input in inputs.map(|e| e.file_name().to_string_lossy().into_owned()) {
+            if let Some(temp) = input
+                .strip_prefix("temp")
+                .and_then(|s| s.strip_suffix("_input"))
+            {
+                let label = read_name(hwmon.join(format!("temp{}_label", temp)))
+                    .unwrap_or_else(|| format!("temp{}", temp));
+                found.push(SensorFile {
+                    name: format!("{} {}", chip, label),
+                    path: hwmon.join(&input),
+                });
+            }
+        }
+    }
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    found
+}
+
+/// The entries of /sys/class/<class> starting with `prefix`
+fn class_devices<P: AsRef<Path>>(sys: P, class: &str, prefix: &str) -> Vec<PathBuf> {
+    let dir = sys.as_ref().join("class").join(class);
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|e| e.file_name().to_string_lossy().starts_with(prefix))
+        .map(|e| e.path())
+        .collect()
+}
+
+/// The CPU temperature, or a temperature file, a fan's sensor binding
+pub enum Sensor {
+    Cpu(Mailbox),
+    File(FileSensor),
+}
+
+impl Sensor {
+    /// The file at `path`, or the CPU temperature through `vcio_dev` when None
+    pub fn open<P: AsRef<Path>>(path: Option<&Path>, vcio_dev: P) -> Result<Self, SensorError> {
+        Ok(match path {
+            Some(p) => Sensor::File(FileSensor::new(p)),
+            None => Sensor::Cpu(Mailbox::new(vcio_dev)?),
+        })
+    }
+}
+
+impl TemperatureSource for Sensor {
+    type Error = SensorError;
+
+    fn temperature(&mut self) -> Result<f32, Self::Error> {
+        Ok(match self {
+            Sensor::Cpu(mb) => mb.temperature()?,
+            Sensor::File(f) => f.temperature()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn file_sensor() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("temp");
+        let mut sensor = FileSensor::new(&path);
+        assert!(matches!(sensor.temperature(), Err(LoadError::Io(..))));
+        fs::write(&path, "48512\n").unwrap();
+        assert_eq!(sensor.temperature().unwrap(), 48.512);
+        fs::write(&path, "-2500\n").unwrap();
+        assert_eq!(sensor.temperature().unwrap(), -2.5);
+        fs::write(&path, "n/a\n").unwrap();
+        assert!(matches!(sensor.temperature(), Err(LoadError::Parse(_))));
+    }
+
+    #[test]
+    fn sensor_files() {
+        let sys = tempfile::tempdir().unwrap();
+        let write = |path: &str, contents: &str| {
+            let path = sys.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+        write("class/thermal/thermal_zone0/type", "cpu-thermal\n");
+        write("class/thermal/thermal_zone0/temp", "48000\n");
+        write("class/thermal/cooling_device0/type", "gpio-fan\n");
+        write("class/hwmon/hwmon1/name", "nvme\n");
+        write("class/hwmon/hwmon1/temp1_input", "38850\n");
+        write("class/hwmon/hwmon1/temp1_label", "Composite\n");
+        write("class/hwmon/hwmon1/temp2_input", "41850\n");
+        write("class/hwmon/hwmon1/temp2_max", "81850\n");
+
+        let found = find_sensor_files(sys.path());
+        let names: Vec<&str> = found.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["nvme Composite", "nvme temp2", "cpu-thermal"]);
+        assert_eq!(
+            found[2].path,
+            sys.path().join("class/thermal/thermal_zone0/temp")
+        );
+        assert!(find_sensor_files(sys.path().join("missing")).is_empty());
+    }
+}
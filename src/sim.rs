@@ -0,0 +1,415 @@
+use crate::{
+    Clock, Controller, Fan, FanSpeed, FanSpeedMapError, ManualClock, Profile, Scheduler,
+    TemperatureSource,
+};
+use std::convert::Infallible;
+use std::num::ParseFloatError;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Lumped thermal model of a Pi in an Argon ONE case,
+/// C * dT/dt = P(load) - (G_passive + G_fan * fan) * (T - T_ambient)
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ThermalModel {
+    /// Heat capacity of the SoC, heatsink and case, J/C
+    pub heat_capacity: f64,
+    /// Power dissipated at idle, W
+    pub idle_power: f64,
+    /// Power dissipated at full CPU load, W
+    pub max_power: f64,
+    /// Passive (case) thermal conductance to ambient, W/C
+    pub passive_conductance: f64,
+    /// Additional thermal conductance with the fan at 100%, W/C
+    pub fan_conductance: f64,
+    /// Ambient temperature, C
+    pub ambient: f64,
+}
+
+impl Default for ThermalModel {
+    fn default() -> Self {
+        ThermalModel {
+            heat_capacity: 60.0,
+            idle_power: 2.5,
+            max_power: 7.0,
+            passive_conductance: 0.15,
+            fan_conductance: 0.2,
+            ambient: 25.0,
+        }
+    }
+}
+
+impl ThermalModel {
+    pub fn power(&self, load: f64) -> f64 {
+        self.idle_power + (self.max_power - self.idle_power) * load.clamp(0.0, 1.0)
+    }
+
+    pub fn conductance(&self, fan_speed: FanSpeed) -> f64 {
+        self.passive_conductance + self.fan_conductance * f64::from(u8::from(fan_speed)) / 100.0
+    }
+
+    /// Temperature the model settles at for a constant load and fan speed
+    pub fn steady_state(&self, load: f64, fan_speed: FanSpeed) -> f64 {
+        self.ambient + self.power(load) / self.conductance(fan_speed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, err_derive::Error)]
+pub enum ParseLoadProfileError {
+    #[error(
+        display = "Invalid load profile entry '{}', expected <seconds>:<load>",
+        _0
+    )]
+    Entry(String),
+
+    #[error(display = "Invalid load profile time, {}", _0)]
+    Time(#[error(from)] std::num::ParseIntError),
+
+    #[error(display = "Invalid load profile load, {}", _0)]
+    Load(#[error(from)] ParseFloatError),
+
+    #[error(display = "Load profile loads must be in 0.0..=1.0")]
+    LoadRange,
+}
+
+/// CPU load (0.0..=1.0) over time, a step function of (start seconds, load) pairs
+#[derive(Clone, PartialEq, Debug)]
+pub struct LoadProfile(Vec<(u32, f64)>);
+
+impl Default for LoadProfile {
+    /// Idle for 10 minutes, full load for 20 minutes, then idle
+    fn default() -> Self {
+        LoadProfile(vec![(0, 0.05), (600, 1.0), (1800, 0.05)])
+    }
+}
+
+impl LoadProfile {
+    pub fn load_at(&self, time: Duration) -> f64 {
+        let secs = time.as_secs();
+        self.0
+            .iter()
+            .take_while(|(start, _)| u64::from(*start) <= secs)
+            .last()
+            .map(|(_, load)| *load)
+            .unwrap_or(0.0)
+    }
+}
+
+impl FromStr for LoadProfile {
+    type Err = ParseLoadProfileError;
+
+    /// Comma separated <seconds>:<load> entries, e.g. "0:0.1,600:1.0,1800:0.1"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(2, ':');
+            let (t, l) = match (parts.next(), parts.next()) {
+                (Some(t), Some(l)) => (t, l),
+                _ => return Err(ParseLoadProfileError::Entry(entry.to_string())),
+            };
+            let t = t.trim().parse::<u32>()?;
+            let l = l.trim().parse::<f64>()?;
+            if !(0.0..=1.0).contains(&l) {
+                return Err(ParseLoadProfileError::LoadRange);
+            }
+            entries.push((t, l));
+        }
+        entries.sort_by_key(|(t, _)| *t);
+        Ok(LoadProfile(entries))
+    }
+}
+
+#[derive(Debug)]
+struct SimState {
+    temperature: f64,
+    load: f64,
+    fan_speed: FanSpeed,
+    fan_writes: usize,
+    speed_changes: usize,
+}
+
+/// Thermal model state, shared by its temperature source and fan
+#[derive(Clone, Debug)]
+pub struct ThermalSim {
+    model: ThermalModel,
+    state: Arc<Mutex<SimState>>,
+}
+
+impl ThermalSim {
+    /// Starts at the steady state idle temperature with the fan off
+    pub fn new(model: ThermalModel) -> Self {
+        let state = SimState {
+            temperature: model.steady_state(0.0, FanSpeed::MIN),
+            load: 0.0,
+            fan_speed: FanSpeed::MIN,
+            fan_writes: 0,
+            speed_changes: 0,
+        };
+        ThermalSim {
+            model,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn sensor(&self) -> SimSensor {
+        SimSensor(self.clone())
+    }
+
+    pub fn fan(&self) -> SimFan {
+        SimFan(self.clone())
+    }
+
+    pub fn set_load(&self, load: f64) {
+        self.state.lock().unwrap().load = load;
+    }
+
+    pub fn set_temperature(&self, temperature: f64) {
+        self.state.lock().unwrap().temperature = temperature;
+    }
+
+    pub fn temperature(&self) -> f64 {
+        self.state.lock().unwrap().temperature
+    }
+
+    pub fn fan_speed(&self) -> FanSpeed {
+        self.state.lock().unwrap().fan_speed
+    }
+
+    /// Advances the model by dt (forward Euler)
+    pub fn step(&self, dt: Duration) {
+        let mut s = self.state.lock().unwrap();
+        let m = &self.model;
+        let heat_in = m.power(s.load);
+        let heat_out = m.conductance(s.fan_speed) * (s.temperature - m.ambient);
+        s.temperature += (heat_in - heat_out) * dt.as_secs_f64() / m.heat_capacity;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SimSensor(ThermalSim);
+
+impl TemperatureSource for SimSensor {
+    type Error = Infallible;
+
+    fn temperature(&mut self) -> Result<f32, Self::Error> {
+        Ok(self.0.temperature() as f32)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SimFan(ThermalSim);
+
+impl Fan for SimFan {
+    type Error = Infallible;
+
+    fn set_speed(&mut self, speed: FanSpeed) -> Result<(), Self::Error> {
+        let mut s = (self.0).state.lock().unwrap();
+        if s.fan_speed != speed {
+            s.speed_changes += 1;
+        }
+        s.fan_speed = speed;
+        s.fan_writes += 1;
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SimSample {
+    /// Simulated seconds since the start
+    pub time: f64,
+    pub load: f64,
+    pub temperature: f64,
+    pub fan_speed: u8,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SimReport {
+    pub trace: Vec<SimSample>,
+    pub max_temperature: f64,
+    /// Average fan speed over the trace, percentage
+    pub mean_fan_speed: f64,
+    pub fan_writes: usize,
+    /// Fan writes changing the speed
+    pub speed_changes: usize,
+}
+
+impl SimReport {
+    pub fn trace_csv(&self) -> String {
+        let mut out = String::from("time,load,temperature,fan_speed\n");
+        for s in self.trace.iter() {
+            out.push_str(&format!(
+                "{:.0},{:.2},{:.2},{}\n",
+                s.time, s.load, s.temperature, s.fan_speed
+            ));
+        }
+        out
+    }
+}
+
+/// Runs the control loop with the profile against the thermal model in
+/// accelerated time, sampling the trace every `dt`
+pub fn simulate(
+    model: ThermalModel,
+    load_profile: &LoadProfile,
+    profile: &Profile,
+    update_interval: Duration,
+    duration: Duration,
+    dt: Duration,
+) -> Result<SimReport, FanSpeedMapError> {
+    let sim = ThermalSim::new(model);
+    let mut controller = Controller::new(sim.sensor(), sim.fan(), profile.fan_speed_map()?);
+    controller.set_profile(profile)?;
+    let clock = ManualClock::new();
+    let mut sched = Scheduler::new(clock.now(), update_interval);
+
+    let mut trace = Vec::new();
+    let mut elapsed = Duration::from_secs(0);
+    let mut fan_speed_sum = 0.0;
+    let mut max_temperature = sim.temperature();
+    while elapsed <= duration {
+        let load = load_profile.load_at(elapsed);
+        sim.set_load(load);
+        if sched.update(clock.now()) {
+            controller.tick(clock.utc_now());
+        }
+        let temperature = sim.temperature();
+        let fan_speed = sim.fan_speed();
+        max_temperature = max_temperature.max(temperature);
+        fan_speed_sum += f64::from(u8::from(fan_speed));
+        trace.push(SimSample {
+            time: elapsed.as_secs_f64(),
+            load,
+            temperature,
+            fan_speed: fan_speed.into(),
+        });
+
+        sim.step(dt);
+        clock.advance(dt);
+        elapsed += dt;
+    }
+
+    let state = sim.state.lock().unwrap();
+    Ok(SimReport {
+        mean_fan_speed: fan_speed_sum / trace.len() as f64,
+        trace,
+        max_temperature,
+        fan_writes: state.fan_writes,
+        speed_changes: state.speed_changes,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DegreesC;
+
+    fn profile() -> Profile {
+        Profile {
+            temperature_min: DegreesC::new(45),
+            temperature_max: DegreesC::new(65),
+            fan_speed_min: FanSpeed::MIN,
+            fan_speed_max: FanSpeed::MAX,
+            hysteresis: DegreesC::new(0),
+            safety_temperature: None,
+        }
+    }
+
+    #[test]
+    fn load_profile_from_str() {
+        let p = LoadProfile::from_str("600:1.0, 0:0.1,1800:0").unwrap();
+        assert_eq!(p, LoadProfile(vec![(0, 0.1), (600, 1.0), (1800, 0.0)]));
+        assert_eq!(p.load_at(Duration::from_secs(0)), 0.1);
+        assert_eq!(p.load_at(Duration::from_secs(599)), 0.1);
+        assert_eq!(p.load_at(Duration::from_secs(600)), 1.0);
+        assert_eq!(p.load_at(Duration::from_secs(5000)), 0.0);
+        assert_eq!(
+            LoadProfile::from_str("10"),
+            Err(ParseLoadProfileError::Entry("10".to_string()))
+        );
+        assert_eq!(
+            LoadProfile::from_str("0:1.5"),
+            Err(ParseLoadProfileError::LoadRange)
+        );
+    }
+
+    #[test]
+    fn converges_to_steady_state() {
+        let model = ThermalModel::default();
+        let sim = ThermalSim::new(model);
+        let mut fan = sim.fan();
+        fan.set_speed(FanSpeed::new(50).unwrap()).unwrap();
+        sim.set_load(1.0);
+        for _ in 0..(4 * 3600) {
+            sim.step(Duration::from_secs(1));
+        }
+        let expected = model.steady_state(1.0, FanSpeed::new(50).unwrap());
+        assert!((sim.temperature() - expected).abs() < 0.01);
+        assert!((expected - 53.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn fan_follows_load() {
+        let report = simulate(
+            ThermalModel::default(),
+            &LoadProfile::default(),
+            &profile(),
+            Duration::from_secs(30),
+            Duration::from_secs(3600),
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        assert_eq!(report.trace.len(), 3601);
+        // 30s update interval over 1 hour
+        assert_eq!(report.fan_writes, 120);
+
+        let at = |secs: usize| report.trace[secs];
+        // Idle settles below the curve
+        assert!(at(599).temperature < 45.0);
+        assert_eq!(at(599).fan_speed, 0);
+        // Under load the fan spins up and holds the temperature well below
+        // the fan-less steady state
+        assert!(at(1799).fan_speed > 30);
+        let passive_max = ThermalModel::default().steady_state(1.0, FanSpeed::MIN);
+        assert!(report.max_temperature < passive_max - 10.0);
+        assert!(report.max_temperature > 50.0);
+        // And slows down again once idle
+        assert_eq!(at(3600).fan_speed, 0);
+        assert!(report.mean_fan_speed > 0.0 && report.mean_fan_speed < 100.0);
+
+        let csv = report.trace_csv();
+        assert_eq!(csv.lines().count(), 3602);
+        assert!(csv.starts_with("time,load,temperature,fan_speed\n0,0.05,"));
+    }
+
+    #[test]
+    fn hysteresis_holds_fan_speed() {
+        // Load alternating every minute
+        let load = LoadProfile(
+            (0..60)
+                .map(|m| (m * 60, 0.6 + 0.2 * (m % 2) as f64))
+                .collect(),
+        );
+        let run = |hysteresis| {
+            let profile = Profile {
+                hysteresis,
+                ..profile()
+            };
+            simulate(
+                ThermalModel::default(),
+                &load,
+                &profile,
+                Duration::from_secs(30),
+                Duration::from_secs(3600),
+                Duration::from_secs(1),
+            )
+            .unwrap()
+        };
+        let without = run(DegreesC::new(0));
+        let with = run(DegreesC::new(2));
+        // The speed is written at every update either way, but only follows
+        // the load down without hysteresis
+        assert_eq!(without.fan_writes, with.fan_writes);
+        assert!(without.speed_changes > 100);
+        assert!(with.speed_changes < 20);
+    }
+}
@@ -0,0 +1,90 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+pub const STATE_SYS_PATH: &str = "/var/lib/argon-fan-ctl/state.toml";
+
+#[derive(Debug, err_derive::Error)]
+pub enum StateError {
+    #[error(display = "Failed to access state file {:?}, {}", _0, _1)]
+    Io(PathBuf, io::Error),
+
+    #[error(display = "State file {:?} is invalid, {}", _0, _1)]
+    Invalid(PathBuf, toml::de::Error),
+
+    #[error(display = "Failed to serialize the state, {}", _0)]
+    Serialize(#[error(from)] toml::ser::Error),
+}
+
+/// Runtime choices that persist across restarts
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    /// Profile selected at runtime, the schedule applies when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+}
+
+/// State file path, written atomically
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct StateFile(PathBuf);
+
+impl StateFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        StateFile(path.as_ref().to_path_buf())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// A missing file is the default state
+    pub fn load(&self) -> Result<State, StateError> {
+        match fs::read_to_string(&self.0) {
+            Ok(content) => {
+                toml::from_str(&content).map_err(|e| StateError::Invalid(self.0.clone(), e))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(StateError::Io(self.0.clone(), e)),
+        }
+    }
+
+    pub fn save(&self, state: &State) -> Result<(), StateError> {
+        let err = |e| StateError::Io(self.0.clone(), e);
+        if let Some(dir) = self.0.parent() {
+            fs::create_dir_all(dir).map_err(err)?;
+        }
+        let mut tmp = self.0.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, toml::to_string(state)?).map_err(err)?;
+        fs::rename(&tmp, &self.0).map_err(err)?;
+        debug!("Saved state to {}", self.0.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = StateFile::new(dir.path().join("lib/state.toml"));
+        assert_eq!(file.load().unwrap(), State::default());
+        let state = State {
+            profile: Some("silent".to_string()),
+        };
+        file.save(&state).unwrap();
+        assert_eq!(
+            fs::read_to_string(file.path()).unwrap(),
+            "profile = \"silent\"\n"
+        );
+        assert_eq!(file.load().unwrap(), state);
+        file.save(&State::default()).unwrap();
+        assert_eq!(file.load().unwrap(), State::default());
+
+        fs::write(file.path(), "profile = 1").unwrap();
+        assert!(matches!(file.load(), Err(StateError::Invalid(_, _))));
+    }
+}
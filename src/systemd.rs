@@ -0,0 +1,220 @@
+use log::{debug, warn};
+use std::ffi::OsString;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::{env, io, process};
+
+pub const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+pub const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+pub const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+
+#[derive(Debug, err_derive::Error)]
+pub enum NotifyError {
+    #[error(display = "Failed to send systemd notification, {}", _0)]
+    Io(#[error(from)] io::Error),
+
+    #[error(display = "Invalid {} value {:?}", _0, _1)]
+    InvalidEnv(&'static str, OsString),
+}
+
+/// Implements the sd_notify protocol for Type=notify services,
+/// all operations are no-ops when not started by systemd
+#[derive(Debug)]
+pub struct SystemdNotifier {
+    socket: Option<(UnixDatagram, PathBuf)>,
+    watchdog_interval: Option<Duration>,
+    last_ping: Option<Instant>,
+}
+
+impl SystemdNotifier {
+    /// Uses the NOTIFY_SOCKET, WATCHDOG_USEC and WATCHDOG_PID environment variables
+    pub fn from_env() -> Result<Self, NotifyError> {
+        let socket_path = env::var_os(NOTIFY_SOCKET_ENV).map(PathBuf::from);
+        let watchdog_pid = match env::var_os(WATCHDOG_PID_ENV) {
+            Some(pid) => Some(
+                pid.to_str()
+                    .and_then(|s| s.parse::<u32>().ok())
+                    .ok_or(NotifyError::InvalidEnv(WATCHDOG_PID_ENV, pid))?,
+            ),
+            None => None,
+        };
+        let watchdog_usec = match env::var_os(WATCHDOG_USEC_ENV) {
+            Some(usec) => Some(
+                usec.to_str()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .ok_or(NotifyError::InvalidEnv(WATCHDOG_USEC_ENV, usec))?,
+            ),
+            None => None,
+        };
+        let watchdog_usec = match watchdog_pid {
+            Some(pid) if pid != process::id() => {
+                debug!("Watchdog is meant for PID {}, ignoring", pid);
+                None
+            }
+            _ => watchdog_usec,
+        };
+        Self::new(socket_path, watchdog_usec)
+    }
+
+    pub fn new(
+        socket_path: Option<PathBuf>,
+        watchdog_usec: Option<u64>,
+    ) -> Result<Self, NotifyError> {
+        let socket = match socket_path {
+            Some(path) if path.to_string_lossy().starts_with('@') => {
+                warn!(
+                    "Abstract notification socket {} is not supported",
+                    path.display()
+                );
+                None
+            }
+            Some(path) => Some((UnixDatagram::unbound()?, path)),
+            None => None,
+        };
+        let watchdog_interval = match (&socket, watchdog_usec) {
+            (Some(_), Some(usec)) if usec != 0 => {
+                // Ping at half the timeout, as recommended by sd_watchdog_enabled(3)
+                let interval = Duration::from_micros(usec) / 2;
+                debug!("Watchdog enabled, ping interval {:?}", interval);
+                Some(interval)
+            }
+            _ => None,
+        };
+        Ok(SystemdNotifier {
+            socket,
+            watchdog_interval,
+            last_ping: None,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog_interval
+    }
+
+    /// Service startup is finished
+    pub fn ready(&mut self) -> Result<(), NotifyError> {
+        self.send("READY=1")
+    }
+
+    /// Service is beginning its shutdown
+    pub fn stopping(&mut self) -> Result<(), NotifyError> {
+        self.send("STOPPING=1")
+    }
+
+    /// Single line status string, shown by systemctl status
+    pub fn status(&mut self, status: &str) -> Result<(), NotifyError> {
+        self.send(&format!("STATUS={}", status.replace('\n', " ")))
+    }
+
+    /// When the next watchdog ping is due, if enabled
+    pub fn next_watchdog(&self, now: Instant) -> Option<Instant> {
+        let interval = self.watchdog_interval?;
+        Some(
+            self.last_ping
+                .and_then(|prev| prev.checked_add(interval))
+                .unwrap_or(now),
+        )
+    }
+
+    /// Pings the watchdog if enabled and the ping interval was reached,
+    /// returns true if a ping was sent
+    pub fn watchdog(&mut self, now: Instant) -> Result<bool, NotifyError> {
+        let interval = match self.watchdog_interval {
+            None => return Ok(false),
+            Some(i) => i,
+        };
+        let due = match self.last_ping {
+            None => true,
+            Some(prev) => match now.checked_duration_since(prev) {
+                // Time went backwards, ping to be safe
+                None => true,
+                Some(time_since) => time_since >= interval,
+            },
+        };
+        if due {
+            self.send("WATCHDOG=1")?;
+            self.last_ping = Some(now);
+        }
+        Ok(due)
+    }
+
+    fn send(&self, state: &str) -> Result<(), NotifyError> {
+        if let Some((socket, path)) = &self.socket {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn recv(socket: &UnixDatagram) -> Option<String> {
+        let mut buf = [0_u8; 256];
+        match socket.recv(&mut buf) {
+            Ok(n) => Some(String::from_utf8_lossy(&buf[..n]).to_string()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn disabled_without_socket() {
+        let mut n = SystemdNotifier::new(None, Some(1_000_000)).unwrap();
+        assert!(!n.is_enabled());
+        assert_eq!(n.watchdog_interval(), None);
+        assert!(n.ready().is_ok());
+        assert!(!n.watchdog(Instant::now()).unwrap());
+        assert_eq!(n.next_watchdog(Instant::now()), None);
+    }
+
+    #[test]
+    fn notifications() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_nonblocking(true).unwrap();
+
+        let mut n = SystemdNotifier::new(Some(path), Some(2_000_000)).unwrap();
+        assert!(n.is_enabled());
+        assert_eq!(n.watchdog_interval(), Some(Duration::from_secs(1)));
+
+        n.ready().unwrap();
+        assert_eq!(recv(&socket).as_deref(), Some("READY=1"));
+
+        n.status("Temp 48 C,\nfan speed 30%").unwrap();
+        assert_eq!(
+            recv(&socket).as_deref(),
+            Some("STATUS=Temp 48 C, fan speed 30%")
+        );
+
+        let now = Instant::now();
+        assert_eq!(n.next_watchdog(now), Some(now));
+        assert!(n.watchdog(now).unwrap());
+        assert_eq!(recv(&socket).as_deref(), Some("WATCHDOG=1"));
+        assert_eq!(n.next_watchdog(now), Some(now + Duration::from_secs(1)));
+        assert!(!n.watchdog(now + Duration::from_millis(500)).unwrap());
+        assert_eq!(recv(&socket), None);
+        assert!(n.watchdog(now + Duration::from_secs(1)).unwrap());
+        assert_eq!(recv(&socket).as_deref(), Some("WATCHDOG=1"));
+
+        n.stopping().unwrap();
+        assert_eq!(recv(&socket).as_deref(), Some("STOPPING=1"));
+    }
+
+    #[test]
+    fn watchdog_disabled_when_zero() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let _socket = UnixDatagram::bind(&path).unwrap();
+        let n = SystemdNotifier::new(Some(path), Some(0)).unwrap();
+        assert!(n.is_enabled());
+        assert_eq!(n.watchdog_interval(), None);
+    }
+}
@@ -0,0 +1,355 @@
+use crate::{Clock, FanSpeed, I2cAddress, I2cBus};
+use log::{error, info};
+use rppal::gpio::{self, Gpio, InputPin, Trigger};
+use rppal::i2c::{self, I2c};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, err_derive::Error)]
+pub enum TachError {
+    #[error(display = "Failed to read the tachometer GPIO pin, {}", _0)]
+    Gpio(#[error(from)] gpio::Error),
+
+    #[error(display = "Failed to read the tachometer register, {}", _0)]
+    I2c(#[error(from)] i2c::Error),
+
+    #[error(display = "No tachometer gpio_pin or register configured")]
+    NoSource,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct TachometerConfig {
+    /// BCM GPIO pin of the fan's tach output, pulses are counted on falling edges
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpio_pin: Option<u8>,
+    /// Fan controller register holding the speed in RPM, read as an SMBus word,
+    /// for controllers reporting it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub register: Option<u8>,
+    /// Tach pulses per revolution, 2 for most PC fans
+    #[serde(default = "TachometerConfig::default_pulses_per_revolution")]
+    pub pulses_per_revolution: u8,
+    /// The stall alarm fires when the fan speed is at least this but the fan doesn't turn
+    #[serde(default = "TachometerConfig::default_stall_fan_speed")]
+    pub stall_fan_speed: FanSpeed,
+    /// Seconds the fan must not turn for before the stall alarm fires
+    #[serde(default = "TachometerConfig::default_stall_seconds")]
+    pub stall_seconds: u32,
+}
+
+impl TachometerConfig {
+    fn default_pulses_per_revolution() -> u8 {
+        2
+    }
+
+    fn default_stall_fan_speed() -> FanSpeed {
+        FanSpeed(30)
+    }
+
+    fn default_stall_seconds() -> u32 {
+        10
+    }
+}
+
+impl Default for TachometerConfig {
+    fn default() -> Self {
+        TachometerConfig {
+            gpio_pin: None,
+            register: None,
+            pulses_per_revolution: Self::default_pulses_per_revolution(),
+            stall_fan_speed: Self::default_stall_fan_speed(),
+            stall_seconds: Self::default_stall_seconds(),
+        }
+    }
+}
+
+/// Reads back how fast the fan actually turns
+pub trait FanFeedback {
+    type Error: Error + 'static;
+
+    /// Fan speed in RPM, averaged since the previous call for pulse counters
+    fn rpm(&mut self) -> Result<u32, Self::Error>;
+}
+
+/// Counts the falling edges of a fan tach output on a GPIO pin
+pub struct GpioTach<K> {
+    _pin: InputPin,
+    counter: PulseCounter<K>,
+}
+
+impl<K: Clock> GpioTach<K> {
+    pub fn new(clock: K, pin: u8, pulses_per_revolution: u8) -> Result<Self, gpio::Error> {
+        let mut pin = Gpio::new()?.get(pin)?.into_input_pullup();
+        let counter = PulseCounter::new(clock, pulses_per_revolution);
+        let p = counter.pulses.clone();
+        pin.set_async_interrupt(Trigger::FallingEdge, move |_| {
+            p.fetch_add(1, Ordering::Relaxed);
+        })?;
+        Ok(GpioTach { _pin: pin, counter })
+    }
+}
+
+impl<K: Clock> FanFeedback for GpioTach<K> {
+    type Error = TachError;
+
+    fn rpm(&mut self) -> Result<u32, Self::Error> {
+        Ok(self.counter.rpm())
+    }
+}
+
+/// Tach pulses counted elsewhere, turned into RPM over the time between reads
+struct PulseCounter<K> {
+    clock: K,
+    pulses: Arc<AtomicU64>,
+    pulses_per_revolution: u8,
+    last: (Instant, u64),
+}
+
+impl<K: Clock> PulseCounter<K> {
+    fn new(clock: K, pulses_per_revolution: u8) -> Self {
+        let now = clock.now();
+        PulseCounter {
+            clock,
+            pulses: Arc::new(AtomicU64::new(0)),
+            pulses_per_revolution: pulses_per_revolution.max(1),
+            last: (now, 0),
+        }
+    }
+
+    fn rpm(&mut self) -> u32 {
+        let now = self.clock.now();
+        let count = self.pulses.load(Ordering::Relaxed);
+        let (since, prev) = std::mem::replace(&mut self.last, (now, count));
+        pulses_to_rpm(count - prev, now - since, self.pulses_per_revolution)
+    }
+}
+
+fn pulses_to_rpm(pulses: u64, elapsed: Duration, pulses_per_revolution: u8) -> u32 {
+    if elapsed.is_zero() {
+        return 0;
+    }
+    let revolutions = pulses as f64 / f64::from(pulses_per_revolution.max(1));
+    (revolutions * 60.0 / elapsed.as_secs_f64()).round() as u32
+}
+
+/// Reads the speed in RPM from a fan controller register
+#[derive(Debug)]
+pub struct RegisterTach {
+    i2c: I2c,
+    register: u8,
+}
+
+impl RegisterTach {
+    pub fn new(bus: I2cBus, addr: I2cAddress, register: u8) -> Result<Self, i2c::Error> {
+        let mut i2c = I2c::with_bus(bus.into())?;
+        i2c.set_slave_address(addr.into())?;
+        Ok(RegisterTach { i2c, register })
+    }
+}
+
+impl FanFeedback for RegisterTach {
+    type Error = TachError;
+
+    fn rpm(&mut self) -> Result<u32, Self::Error> {
+        Ok(u32::from(self.i2c.smbus_read_word(self.register)?))
+    }
+}
+
+/// The GPIO pin tach when configured, otherwise the register of the fan controller at
+/// `bus` and `addr`
+pub fn open_feedback<K: Clock + 'static>(
+    clock: K,
+    config: &TachometerConfig,
+    bus: I2cBus,
+    addr: I2cAddress,
+) -> Result<Box<dyn FanFeedback<Error = TachError>>, TachError> {
+    Ok(match (config.gpio_pin, config.register) {
+        (Some(pin), _) => Box::new(GpioTach::new(clock, pin, config.pulses_per_revolution)?),
+        (None, Some(register)) => Box::new(RegisterTach::new(bus, addr, register)?),
+        (None, None) => return Err(TachError::NoSource),
+    })
+}
+
+/// The fan speed read back at an update
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct TachReading {
+    pub rpm: u32,
+    /// True while the stall alarm is raised
+    pub stalled: bool,
+}
+
+/// Raises the alarm when the fan doesn't turn for a while, although commanded to
+#[derive(Clone, Debug)]
+pub struct StallDetector {
+    fan_speed: FanSpeed,
+    duration: Duration,
+    stopped_since: Option<Instant>,
+    stalled: bool,
+}
+
+impl StallDetector {
+    pub fn new(fan_speed: FanSpeed, duration: Duration) -> Self {
+        StallDetector {
+            fan_speed,
+            duration,
+            stopped_since: None,
+            stalled: false,
+        }
+    }
+
+    /// Returns true while stalled, `commanded` is the fan speed the RPM was measured at
+    pub fn update(&mut self, now: Instant, commanded: FanSpeed, rpm: u32) -> bool {
+        if commanded < self.fan_speed || rpm > 0 {
+            if self.stalled {
+                info!("Fan turning again at {} RPM", rpm);
+            }
+            self.stopped_since = None;
+            self.stalled = false;
+            return false;
+        }
+        let since = *self.stopped_since.get_or_insert(now);
+        if !self.stalled && now.saturating_duration_since(since) >= self.duration {
+            error!(
+                "Fan stalled, not turning for {} s at {}",
+                self.duration.as_secs(),
+                commanded
+            );
+            self.stalled = true;
+        }
+        self.stalled
+    }
+}
+
+/// Reads the fan speed back at each update and watches for stalls
+pub struct Tachometer {
+    feedback: Box<dyn FanFeedback<Error = TachError>>,
+    stall: StallDetector,
+    /// Fan speed commanded at the previous read, over which the RPM was measured
+    commanded: Option<FanSpeed>,
+}
+
+impl Tachometer {
+    /// Reads the tach configured, see `open_feedback`
+    pub fn new<K: Clock + 'static>(
+        clock: K,
+        config: &TachometerConfig,
+        bus: I2cBus,
+        addr: I2cAddress,
+    ) -> Result<Self, TachError> {
+        Ok(Self::with_feedback(
+            open_feedback(clock, config, bus, addr)?,
+            config,
+        ))
+    }
+
+    pub fn with_feedback(
+        feedback: Box<dyn FanFeedback<Error = TachError>>,
+        config: &TachometerConfig,
+    ) -> Self {
+        Tachometer {
+            feedback,
+            stall: StallDetector::new(
+                config.stall_fan_speed,
+                Duration::from_secs(config.stall_seconds.into()),
+            ),
+            commanded: None,
+        }
+    }
+
+    /// Reads the RPM, `commanded` is the fan speed set at this update
+    pub fn read(&mut self, now: Instant, commanded: FanSpeed) -> Result<TachReading, TachError> {
+        let rpm = self.feedback.rpm()?;
+        let stalled = match self.commanded.replace(commanded) {
+            Some(prev) => self.stall.update(now, prev, rpm),
+            None => false,
+        };
+        Ok(TachReading { rpm, stalled })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::ManualClock;
+    use std::collections::VecDeque;
+
+    /// Returns the given RPMs in turn, the last one forever
+    pub(crate) struct FakeTach(pub VecDeque<u32>);
+
+    impl FanFeedback for FakeTach {
+        type Error = TachError;
+
+        fn rpm(&mut self) -> Result<u32, Self::Error> {
+            Ok(if self.0.len() > 1 {
+                self.0.pop_front().unwrap()
+            } else {
+                self.0.front().copied().unwrap_or(0)
+            })
+        }
+    }
+
+    #[test]
+    fn rpm_from_pulses() {
+        assert_eq!(pulses_to_rpm(100, Duration::from_secs(2), 2), 1500);
+        assert_eq!(pulses_to_rpm(7, Duration::from_millis(500), 1), 840);
+        assert_eq!(pulses_to_rpm(0, Duration::from_secs(30), 2), 0);
+        assert_eq!(pulses_to_rpm(10, Duration::from_secs(0), 2), 0);
+
+        // Averaged over the time since the previous read
+        let clock = ManualClock::new();
+        let mut counter = PulseCounter::new(clock.clone(), 2);
+        counter.pulses.fetch_add(100, Ordering::Relaxed);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(counter.rpm(), 1500);
+        counter.pulses.fetch_add(30, Ordering::Relaxed);
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(counter.rpm(), 300);
+        assert_eq!(counter.rpm(), 0);
+    }
+
+    #[test]
+    fn stall_alarm() {
+        let start = Instant::now();
+        let at = |s| start + Duration::from_secs(s);
+        let speed = |s| FanSpeed::new(s).unwrap();
+        let mut d = StallDetector::new(speed(30), Duration::from_secs(10));
+
+        // Stopped below the threshold is fine
+        assert!(!d.update(at(0), speed(20), 0));
+        assert!(!d.update(at(20), speed(20), 0));
+
+        assert!(!d.update(at(30), speed(50), 0));
+        assert!(!d.update(at(39), speed(50), 0));
+        assert!(d.update(at(40), speed(50), 0));
+        assert!(d.update(at(70), speed(100), 0));
+
+        // Cleared once turning, and timed again from the start
+        assert!(!d.update(at(80), speed(100), 900));
+        assert!(!d.update(at(90), speed(100), 0));
+        assert!(!d.update(at(99), speed(100), 0));
+        assert!(d.update(at(100), speed(100), 0));
+    }
+
+    #[test]
+    fn tachometer() {
+        let config = TachometerConfig {
+            stall_seconds: 0,
+            ..Default::default()
+        };
+        let mut tach = Tachometer::with_feedback(
+            Box::new(FakeTach(vec![0, 0, 1200, 0].into_iter().collect())),
+            &config,
+        );
+        let now = Instant::now();
+        let mut read = |s| tach.read(now, FanSpeed::new(s).unwrap()).unwrap();
+        let reading = |rpm, stalled| TachReading { rpm, stalled };
+        // Nothing was commanded while the first RPM was measured
+        assert_eq!(read(50), reading(0, false));
+        assert_eq!(read(0), reading(0, true));
+        assert_eq!(read(50), reading(1200, false));
+        assert_eq!(read(50), reading(0, true));
+    }
+}
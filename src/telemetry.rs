@@ -0,0 +1,339 @@
+use crate::{DegreesC, Tick};
+use chrono::SecondsFormat;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, err_derive::Error)]
+pub enum TelemetryError {
+    #[error(display = "Failed to write telemetry file {:?}, {}", _0, _1)]
+    Io(PathBuf, io::Error),
+
+    #[error(display = "Failed to serialize telemetry record, {}", _0)]
+    Json(#[error(from)] serde_json::Error),
+}
+
+#[derive(
+    Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize, Default,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum TelemetryFormat {
+    /// Comma separated values, with a header line
+    #[default]
+    Csv,
+    /// One JSON object per line
+    JsonLines,
+}
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct TelemetryConfig {
+    /// Telemetry file path, rotated files get a .1, .2, ... suffix
+    pub path: PathBuf,
+    /// Record format
+    #[serde(default)]
+    pub format: TelemetryFormat,
+    /// Rotate the file before it exceeds this many bytes
+    #[serde(default = "TelemetryConfig::default_max_size_bytes")]
+    pub max_size_bytes: u64,
+    /// Number of rotated files to keep
+    #[serde(default = "TelemetryConfig::default_retention")]
+    pub retention: u32,
+}
+
+impl TelemetryConfig {
+    fn default_max_size_bytes() -> u64 {
+        1024 * 1024
+    }
+
+    fn default_retention() -> u32 {
+        5
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Record {
+    timestamp: String,
+    fan: String,
+    raw_temperature: Option<f32>,
+    temperature: Option<DegreesC>,
+    fan_speed: Option<u8>,
+    overridden: bool,
+    written: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rpm: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stalled: Option<bool>,
+    error: Option<String>,
+}
+
+impl Record {
+    const CSV_HEADER: &'static str =
+        "timestamp,fan,raw_temperature,temperature,fan_speed,overridden,written,rpm,stalled,error";
+
+    fn new(fan: &str, tick: &Tick) -> Self {
+        Record {
+            timestamp: tick.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+            fan: fan.to_string(),
+            raw_temperature: tick.raw_temperature,
+            temperature: tick.temperature,
+            fan_speed: tick.fan_speed.map(u8::from),
+            overridden: tick.overridden,
+            written: tick.written,
+            rpm: tick.tach.map(|t| t.rpm),
+            stalled: tick.tach.map(|t| t.stalled),
+            error: tick.error.as_ref().map(|e| e.to_string()),
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        fn opt<T: ToString>(v: Option<T>) -> String {
+            v.map(|v| v.to_string()).unwrap_or_default()
+        }
+        let error = match &self.error {
+            Some(e) => format!("\"{}\"", e.replace('"', "\"\"")),
+            None => String::new(),
+        };
+        format!(
+            "{},{},{},{},{},{},{},{},{},{}",
+            self.timestamp,
+            self.fan,
+            opt(self.raw_temperature),
+            opt(self.temperature.map(DegreesC::as_f64)),
+            opt(self.fan_speed),
+            self.overridden,
+            self.written,
+            opt(self.rpm),
+            opt(self.stalled),
+            error
+        )
+    }
+}
+
+/// Appends a record per control loop tick to a file, with size based rotation
+#[derive(Debug)]
+pub struct TelemetrySink {
+    config: TelemetryConfig,
+    file: File,
+    size: u64,
+}
+
+impl TelemetrySink {
+    pub fn new(config: TelemetryConfig) -> Result<Self, TelemetryError> {
+        let (file, size) = Self::open(&config)?;
+        info!(
+            "Writing telemetry to {} ({:?})",
+            config.path.display(),
+            config.format
+        );
+        Ok(TelemetrySink { config, file, size })
+    }
+
+    /// Records a tick of the fan named `fan`
+    pub fn record(&mut self, fan: &str, tick: &Tick) -> Result<(), TelemetryError> {
+        let record = Record::new(fan, tick);
+        let mut line = match self.config.format {
+            TelemetryFormat::Csv => record.to_csv(),
+            TelemetryFormat::JsonLines => serde_json::to_string(&record)?,
+        };
+        line.push('\n');
+
+        let header_size = self.header().map(|h| h.len() + 1).unwrap_or(0) as u64;
+        if self.size > header_size && self.size + line.len() as u64 > self.config.max_size_bytes {
+            self.rotate()?;
+        }
+        self.write(line.as_bytes())
+    }
+
+    fn header(&self) -> Option<&'static str> {
+        match self.config.format {
+            TelemetryFormat::Csv => Some(Record::CSV_HEADER),
+            TelemetryFormat::JsonLines => None,
+        }
+    }
+
+    fn open(config: &TelemetryConfig) -> Result<(File, u64), TelemetryError> {
+        let path = &config.path;
+        let err = |e| TelemetryError::Io(path.clone(), e);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(err)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(err)?;
+        let mut size = file.metadata().map_err(err)?.len();
+        if size == 0 && config.format == TelemetryFormat::Csv {
+            writeln!(file, "{}", Record::CSV_HEADER).map_err(err)?;
+            size = Record::CSV_HEADER.len() as u64 + 1;
+        }
+        Ok((file, size))
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), TelemetryError> {
+        self.file
+            .write_all(buf)
+            .map_err(|e| TelemetryError::Io(self.config.path.clone(), e))?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    /// path.N-1 -> path.N, ..., path -> path.1
+    fn rotate(&mut self) -> Result<(), TelemetryError> {
+        let path = self.config.path.clone();
+        let retention = self.config.retention;
+        debug!("Rotating telemetry file {}", path.display());
+        if retention == 0 {
+            remove_if_exists(&path)?;
+        } else {
+            remove_if_exists(&rotated_path(&path, retention))?;
+            for n in (1..retention).rev() {
+                let from = rotated_path(&path, n);
+                if from.exists() {
+                    let to = rotated_path(&path, n + 1);
+                    fs::rename(&from, &to).map_err(|e| TelemetryError::Io(from, e))?;
+                }
+            }
+            let to = rotated_path(&path, 1);
+            fs::rename(&path, &to).map_err(|e| TelemetryError::Io(path.clone(), e))?;
+        }
+        let (file, size) = Self::open(&self.config)?;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+}
+
+pub fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(format!(".{}", n));
+    PathBuf::from(p)
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), TelemetryError> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(TelemetryError::Io(path.to_path_buf(), e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::test::FakeError;
+    use crate::{ControlError, DegreesC, FanSpeed, TachReading, MAIN_FAN};
+    use chrono::prelude::*;
+
+    fn tick(raw: f32) -> Tick {
+        Tick {
+            timestamp: Utc.from_utc_datetime(
+                &NaiveDate::from_ymd_opt(2022, 4, 1)
+                    .unwrap()
+                    .and_hms_opt(12, 0, 0)
+                    .unwrap(),
+            ),
+            raw_temperature: Some(raw),
+            temperature: Some(DegreesC::from_f32(raw)),
+            fan_speed: Some(FanSpeed::new(42).unwrap()),
+            overridden: false,
+            boost: FanSpeed::MIN,
+            written: true,
+            tach: None,
+            error: None,
+        }
+    }
+
+    fn config(dir: &Path, format: TelemetryFormat) -> TelemetryConfig {
+        TelemetryConfig {
+            path: dir.join("telemetry.log"),
+            format,
+            max_size_bytes: 210,
+            retention: 2,
+        }
+    }
+
+    #[test]
+    fn csv_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config(dir.path(), TelemetryFormat::Csv);
+        config.max_size_bytes = 4096;
+        let mut sink = TelemetrySink::new(config.clone()).unwrap();
+        sink.record(MAIN_FAN, &tick(48.5)).unwrap();
+        let mut t = tick(48.5);
+        t.written = false;
+        t.error = Some(ControlError::Fan(Box::new(FakeError("bus \"busy\""))));
+        sink.record("hat", &t).unwrap();
+        drop(sink);
+
+        // Reopening appends without another header
+        let mut sink = TelemetrySink::new(config.clone()).unwrap();
+        let mut t = tick(50.0);
+        t.tach = Some(TachReading {
+            rpm: 1250,
+            stalled: false,
+        });
+        sink.record(MAIN_FAN, &t).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&config.path).unwrap(),
+            "timestamp,fan,raw_temperature,temperature,fan_speed,overridden,written,rpm,stalled,error\n\
+             2022-04-01T12:00:00.000Z,case,48.5,48.5,42,false,true,,,\n\
+             2022-04-01T12:00:00.000Z,hat,48.5,48.5,42,false,false,,,\"Failed to set the fan speed, bus \"\"busy\"\"\"\n\
+             2022-04-01T12:00:00.000Z,case,50,50,42,false,true,1250,false,\n"
+        );
+    }
+
+    #[test]
+    fn json_lines_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config(dir.path(), TelemetryFormat::JsonLines);
+        config.max_size_bytes = 4096;
+        let mut sink = TelemetrySink::new(config.clone()).unwrap();
+        let mut t = tick(48.5);
+        t.overridden = true;
+        sink.record(MAIN_FAN, &t).unwrap();
+        t.tach = Some(TachReading {
+            rpm: 0,
+            stalled: true,
+        });
+        sink.record(MAIN_FAN, &t).unwrap();
+        assert_eq!(
+            fs::read_to_string(&config.path).unwrap(),
+            "{\"timestamp\":\"2022-04-01T12:00:00.000Z\",\"fan\":\"case\",\"raw_temperature\":48.5,\
+             \"temperature\":48.5,\"fan_speed\":42,\"overridden\":true,\"written\":true,\
+             \"error\":null}\n\
+             {\"timestamp\":\"2022-04-01T12:00:00.000Z\",\"fan\":\"case\",\"raw_temperature\":48.5,\
+             \"temperature\":48.5,\"fan_speed\":42,\"overridden\":true,\"written\":true,\
+             \"rpm\":0,\"stalled\":true,\"error\":null}\n"
+        );
+    }
+
+    #[test]
+    fn rotation_and_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(dir.path(), TelemetryFormat::Csv);
+        let mut sink = TelemetrySink::new(config.clone()).unwrap();
+        // Header is 89 bytes, records are 57 bytes, 2 records per file
+        for _ in 0..9 {
+            sink.record(MAIN_FAN, &tick(48.5)).unwrap();
+        }
+        let lines = |n| {
+            let p = if n == 0 {
+                config.path.clone()
+            } else {
+                rotated_path(&config.path, n)
+            };
+            let content = fs::read_to_string(p).unwrap();
+            assert!(content.len() as u64 <= config.max_size_bytes);
+            assert!(content.starts_with(Record::CSV_HEADER));
+            content.lines().count() - 1
+        };
+        assert_eq!(lines(0), 1);
+        assert_eq!(lines(1), 2);
+        assert_eq!(lines(2), 2);
+        assert!(!rotated_path(&config.path, 3).exists());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::LoadError;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Firmware throttling flags, as read by `vcgencmd get_throttled`
+pub const THROTTLED_SYS_PATH: &str = "/sys/devices/platform/soc/soc:firmware/get_throttled";
+
+/// The firmware's throttling bit field, the low bits are the current state and the
+/// high bits what has happened since boot
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct Throttled(pub u32);
+
+impl Throttled {
+    const FLAGS: [(u32, &'static str); 4] = [
+        (1 << 0, "under-voltage"),
+        (1 << 1, "frequency capped"),
+        (1 << 2, "throttled"),
+        (1 << 3, "soft temperature limit"),
+    ];
+    const OCCURRED_SHIFT: u32 = 16;
+
+    /// Reads the sysfs file, hex with or without a 0x or "throttled=" prefix
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path).map_err(|e| LoadError::Io(path.to_path_buf(), e))?;
+        Self::parse(&s).ok_or_else(|| LoadError::Parse(path.to_path_buf()))
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let s = s.strip_prefix("throttled=").unwrap_or(s);
+        let s = s.strip_prefix("0x").unwrap_or(s);
+        u32::from_str_radix(s, 16).ok().map(Throttled)
+    }
+
+    /// Flags set now
+    pub fn now(self) -> impl Iterator<Item = &'static str> {
+        Self::names(self.0)
+    }
+
+    /// Flags set at some point since boot
+    pub fn since_boot(self) -> impl Iterator<Item = &'static str> {
+        Self::names(self.0 >> Self::OCCURRED_SHIFT)
+    }
+
+    fn names(bits: u32) -> impl Iterator<Item = &'static str> {
+        Self::FLAGS
+            .iter()
+            .filter(move |(bit, _)| bits & bit != 0)
+            .map(|(_, name)| *name)
+    }
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let now: Vec<&str> = self.now().collect();
+        let since_boot: Vec<&str> = self.since_boot().collect();
+        match (now.is_empty(), since_boot.is_empty()) {
+            (true, true) => f.write_str("none"),
+            (false, true) => f.write_str(&now.join(", ")),
+            (true, false) => write!(f, "none now, {} since boot", since_boot.join(", ")),
+            (false, false) => write!(
+                f,
+                "{}, {} since boot",
+                now.join(", "),
+                since_boot.join(", ")
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_and_show() {
+        assert_eq!(Throttled::parse("0\n"), Some(Throttled(0)));
+        assert_eq!(
+            Throttled::parse("throttled=0x50005"),
+            Some(Throttled(0x50005))
+        );
+        assert_eq!(Throttled::parse("e0008"), Some(Throttled(0xe0008)));
+        assert_eq!(Throttled::parse("nope"), None);
+
+        assert_eq!(Throttled(0).to_string(), "none");
+        assert_eq!(Throttled(0x5).to_string(), "under-voltage, throttled");
+        assert_eq!(
+            Throttled(0x50000).to_string(),
+            "none now, under-voltage, throttled since boot"
+        );
+        assert_eq!(
+            Throttled(0x80008).to_string(),
+            "soft temperature limit, soft temperature limit since boot"
+        );
+    }
+
+    #[test]
+    fn read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("get_throttled");
+        fs::write(&path, "20002\n").unwrap();
+        assert_eq!(Throttled::read(&path).unwrap(), Throttled(0x20002));
+        assert!(Throttled::read(dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use crate::{
+    Clock, Controller, Fan, FanSpeed, FanSpeedMapError, ManualClock, Profile, Scheduler,
+    TemperatureSource, Tick,
+};
+use chrono::prelude::*;
+use log::info;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, err_derive::Error)]
+pub enum TraceError {
+    #[error(display = "Failed to access trace file {:?}, {}", _0, _1)]
+    Io(PathBuf, io::Error),
+
+    #[error(display = "Invalid trace file {:?}, line {}", _0, _1)]
+    Parse(PathBuf, usize),
+
+    #[error(display = "Trace file {:?} has no samples", _0)]
+    Empty(PathBuf),
+}
+
+/// A recorded temperature reading
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct TraceSample {
+    pub timestamp: DateTime<Utc>,
+    pub millidegrees: i32,
+}
+
+impl TraceSample {
+    /// Trace files are CSV, with this header
+    pub const HEADER: &'static str = "timestamp_ms,millidegrees";
+
+    pub fn temperature(&self) -> f32 {
+        self.millidegrees as f32 / 1000.0
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split(',').map(str::trim);
+        let ts = fields.next()?.parse::<i64>().ok()?;
+        let millidegrees = fields.next()?.parse::<i32>().ok()?;
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(TraceSample {
+            timestamp: Utc.timestamp_millis_opt(ts).single()?,
+            millidegrees,
+        })
+    }
+}
+
+/// Appends the temperature of each control loop tick to a trace file
+#[derive(Debug)]
+pub struct TraceRecorder {
+    path: PathBuf,
+    file: File,
+}
+
+impl TraceRecorder {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, TraceError> {
+        let path = path.as_ref().to_path_buf();
+        let err = |e| TraceError::Io(path.clone(), e);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(err)?;
+        if file.metadata().map_err(err)?.len() == 0 {
+            writeln!(file, "{}", TraceSample::HEADER).map_err(err)?;
+        }
+        info!("Recording temperature trace to {}", path.display());
+        Ok(TraceRecorder { path, file })
+    }
+
+    pub fn record(&mut self, tick: &Tick) -> Result<(), TraceError> {
+        let raw = match tick.raw_temperature {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        writeln!(
+            self.file,
+            "{},{}",
+            tick.timestamp.timestamp_millis(),
+            (raw * 1000.0).round() as i32
+        )
+        .map_err(|e| TraceError::Io(self.path.clone(), e))
+    }
+}
+
+/// Reads a trace file, samples are sorted by timestamp
+pub fn read_trace<P: AsRef<Path>>(path: P) -> Result<Vec<TraceSample>, TraceError> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path).map_err(|e| TraceError::Io(path.to_path_buf(), e))?;
+    let mut samples = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line == TraceSample::HEADER {
+            continue;
+        }
+        let sample =
+            TraceSample::parse(line).ok_or_else(|| TraceError::Parse(path.to_path_buf(), n + 1))?;
+        samples.push(sample);
+    }
+    if samples.is_empty() {
+        return Err(TraceError::Empty(path.to_path_buf()));
+    }
+    samples.sort();
+    Ok(samples)
+}
+
+#[derive(Debug, Default)]
+struct ReplayState {
+    temperature: f32,
+    fan_speed: Option<FanSpeed>,
+    writes: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+struct ReplaySensor(Arc<Mutex<ReplayState>>);
+
+impl TemperatureSource for ReplaySensor {
+    type Error = Infallible;
+
+    fn temperature(&mut self) -> Result<f32, Self::Error> {
+        Ok(self.0.lock().unwrap().temperature)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct ReplayFan(Arc<Mutex<ReplayState>>);
+
+impl Fan for ReplayFan {
+    type Error = Infallible;
+
+    fn set_speed(&mut self, speed: FanSpeed) -> Result<(), Self::Error> {
+        let mut s = self.0.lock().unwrap();
+        s.fan_speed = Some(speed);
+        s.writes += 1;
+        Ok(())
+    }
+}
+
+/// Fan speed chosen at a replayed control loop tick
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ReplayTick {
+    pub timestamp: DateTime<Utc>,
+    pub temperature: f32,
+    pub fan_speed: FanSpeed,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ReplayReport {
+    pub ticks: Vec<ReplayTick>,
+    pub time_at_speed: BTreeMap<FanSpeed, Duration>,
+    pub writes: usize,
+    pub max_temperature: f32,
+}
+
+impl ReplayReport {
+    pub fn ticks_csv(&self) -> String {
+        let mut out = String::from("timestamp,temperature,fan_speed\n");
+        for t in self.ticks.iter() {
+            out.push_str(&format!(
+                "{},{:.3},{}\n",
+                t.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+                t.temperature,
+                u8::from(t.fan_speed)
+            ));
+        }
+        out
+    }
+
+    pub fn summary(&self) -> String {
+        let mut out = format!(
+            "Max temperature {:.1} C, {} fan writes\nTime at speed:\n",
+            self.max_temperature, self.writes
+        );
+        for (speed, dur) in self.time_at_speed.iter() {
+            let secs = dur.as_secs();
+            out.push_str(&format!(
+                "{:>6}  {:>3}h {:02}m {:02}s\n",
+                speed.to_string(),
+                secs / 3600,
+                (secs / 60) % 60,
+                secs % 60
+            ));
+        }
+        out
+    }
+}
+
+/// Runs the control loop with the profile over a recorded trace, in one second
+/// steps. The temperature at any time is that of the latest sample.
+pub fn replay(
+    samples: &[TraceSample],
+    profile: &Profile,
+    update_interval: Duration,
+) -> Result<ReplayReport, FanSpeedMapError> {
+    let state = Arc::new(Mutex::new(ReplayState::default()));
+    let mut controller = Controller::new(
+        ReplaySensor(state.clone()),
+        ReplayFan(state.clone()),
+        profile.fan_speed_map()?,
+    );
+    controller.set_profile(profile)?;
+    let step = Duration::from_secs(1);
+    let clock = ManualClock::new();
+    let mut sched = Scheduler::new(clock.now(), update_interval);
+
+    let mut ticks = Vec::new();
+    let mut time_at_speed = BTreeMap::new();
+    let (first, end) = match (samples.first(), samples.last()) {
+        (Some(first), Some(last)) => (first.timestamp, last.timestamp),
+        _ => {
+            return Ok(ReplayReport {
+                ticks,
+                time_at_speed,
+                writes: 0,
+                max_temperature: 0.0,
+            })
+        }
+    };
+    // Like the daemon, start at the default speed until the first tick
+    let _ = controller.set_speed(FanSpeed::default());
+    let mut max_temperature = f32::MIN;
+    let mut next = samples.iter().peekable();
+    let mut elapsed = Duration::from_secs(0);
+    loop {
+        let now = first + chrono::Duration::from_std(elapsed).unwrap();
+        if now > end {
+            break;
+        }
+        while let Some(s) = next.peek() {
+            if s.timestamp > now {
+                break;
+            }
+            let t = s.temperature();
+            state.lock().unwrap().temperature = t;
+            max_temperature = max_temperature.max(t);
+            next.next();
+        }
+
+        if sched.update(clock.now()) {
+            let tick = controller.tick(clock.utc_now());
+            if let (Some(t), Some(s)) = (tick.raw_temperature, tick.fan_speed) {
+                ticks.push(ReplayTick {
+                    timestamp: now,
+                    temperature: t,
+                    fan_speed: s,
+                });
+            }
+        }
+        if now < end {
+            if let Some(s) = state.lock().unwrap().fan_speed {
+                *time_at_speed.entry(s).or_insert_with(Duration::default) += step;
+            }
+        }
+        clock.advance(step);
+        elapsed += step;
+    }
+
+    let writes = state.lock().unwrap().writes;
+    Ok(ReplayReport {
+        ticks,
+        time_at_speed,
+        writes,
+        max_temperature,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::test::profile;
+    use crate::DegreesC;
+
+    fn sample(secs: i64, millidegrees: i32) -> TraceSample {
+        TraceSample {
+            timestamp: Utc.timestamp_opt(1_650_000_000 + secs, 0).unwrap(),
+            millidegrees,
+        }
+    }
+
+    #[test]
+    fn record_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.csv");
+        let mut tick = Tick {
+            timestamp: sample(0, 0).timestamp,
+            raw_temperature: Some(48.5),
+            temperature: Some(DegreesC::new(48)),
+            fan_speed: None,
+            overridden: false,
+            boost: FanSpeed::MIN,
+            written: false,
+            tach: None,
+            error: None,
+        };
+        let mut rec = TraceRecorder::new(&path).unwrap();
+        rec.record(&tick).unwrap();
+        tick.raw_temperature = None;
+        rec.record(&tick).unwrap();
+        drop(rec);
+        let mut rec = TraceRecorder::new(&path).unwrap();
+        tick.timestamp = sample(30, 0).timestamp;
+        tick.raw_temperature = Some(51.234);
+        rec.record(&tick).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "timestamp_ms,millidegrees\n1650000000000,48500\n1650000030000,51234\n"
+        );
+        assert_eq!(
+            read_trace(&path).unwrap(),
+            vec![sample(0, 48500), sample(30, 51234)]
+        );
+
+        fs::write(&path, "timestamp_ms,millidegrees\n1650000000000,48.5\n").unwrap();
+        assert!(matches!(read_trace(&path), Err(TraceError::Parse(_, 2))));
+        fs::write(&path, "timestamp_ms,millidegrees\n").unwrap();
+        assert!(matches!(read_trace(&path), Err(TraceError::Empty(_))));
+    }
+
+    #[test]
+    fn replay_stats() {
+        // 30..70 C maps to 0..100%
+        let samples = vec![
+            sample(0, 40_000),
+            sample(60, 50_000),
+            sample(120, 80_000),
+            sample(180, 40_000),
+            sample(240, 40_000),
+        ];
+        let report = replay(&samples, &profile(), Duration::from_secs(60)).unwrap();
+        let speeds: Vec<u8> = report.ticks.iter().map(|t| t.fan_speed.into()).collect();
+        assert_eq!(speeds, vec![50, 100, 25, 25]);
+        // Including the default speed at startup
+        assert_eq!(report.writes, 5);
+        assert_eq!(report.max_temperature, 80.0);
+        let at = |s| report.time_at_speed[&FanSpeed::new(s).unwrap()];
+        assert_eq!(at(25), Duration::from_secs(120));
+        assert_eq!(at(50), Duration::from_secs(60));
+        assert_eq!(at(100), Duration::from_secs(60));
+
+        // A slower update interval writes less often
+        let report = replay(&samples, &profile(), Duration::from_secs(120)).unwrap();
+        let speeds: Vec<u8> = report.ticks.iter().map(|t| t.fan_speed.into()).collect();
+        assert_eq!(speeds, vec![100, 25]);
+        assert_eq!(report.writes, 3);
+        let at = |s| report.time_at_speed[&FanSpeed::new(s).unwrap()];
+        assert_eq!(at(25), Duration::from_secs(120));
+        assert_eq!(at(100), Duration::from_secs(120));
+    }
+}
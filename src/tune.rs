@@ -0,0 +1,665 @@
+use crate::layers::temperatures_in_unit;
+use crate::{
+    Clock, ControlError, DegreesC, Fan, FanSpeed, Profile, TemperatureSource, TemperatureUnit,
+    TraceError,
+};
+use log::{info, warn};
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, err_derive::Error)]
+pub enum TuneError {
+    #[error(display = "No fan speed was held long enough to measure its temperature")]
+    NoSteadyState,
+
+    #[error(display = "Failed to serialize the configuration snippet, {}", _0)]
+    Serialize(#[error(from)] toml::ser::Error),
+}
+
+/// How the tune subcommand's experiment runs
+#[derive(Clone, PartialEq, Debug)]
+pub struct TuneSettings {
+    /// Held in turn, from the fastest is the safest under load
+    pub fan_speeds: Vec<FanSpeed>,
+    pub sample_interval: Duration,
+    /// A fan speed is steady once the mean temperature over a window is within
+    /// `tolerance` degrees C of the window before
+    pub window: Duration,
+    pub tolerance: f64,
+    /// Moves on to the next fan speed after this, steady or not
+    pub max_step_time: Duration,
+    /// The lower fan speeds are skipped once the temperature reaches this
+    pub limit: DegreesC,
+}
+
+impl Default for TuneSettings {
+    fn default() -> Self {
+        TuneSettings {
+            fan_speeds: [100, 75, 50, 25, 0]
+                .iter()
+                .map(|s| FanSpeed::new_unchecked(*s))
+                .collect(),
+            sample_interval: Duration::from_secs(5),
+            window: Duration::from_secs(60),
+            tolerance: 0.2,
+            max_step_time: Duration::from_secs(30 * 60),
+            limit: DegreesC::new(80),
+        }
+    }
+}
+
+/// A temperature read while holding a fan speed
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct TuneSample {
+    /// Seconds since the start of the experiment
+    pub time: f64,
+    pub fan_speed: FanSpeed,
+    pub temperature: f64,
+}
+
+impl TuneSample {
+    /// Samples files are CSV, with this header
+    pub const HEADER: &'static str = "time,fan_speed,temperature";
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split(',').map(str::trim);
+        let time = fields.next()?.parse().ok()?;
+        let fan_speed = fields.next()?.parse().ok()?;
+        let temperature = fields.next()?.parse().ok()?;
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(TuneSample {
+            time,
+            fan_speed,
+            temperature,
+        })
+    }
+}
+
+pub fn tune_samples_csv(samples: &[TuneSample]) -> String {
+    let mut out = format!("{}\n", TuneSample::HEADER);
+    for s in samples.iter() {
+        writeln!(
+            out,
+            "{:.0},{},{:.3}",
+            s.time,
+            u8::from(s.fan_speed),
+            s.temperature
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Reads a samples file written by the tune subcommand
+pub fn read_tune_samples<P: AsRef<Path>>(path: P) -> Result<Vec<TuneSample>, TraceError> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path).map_err(|e| TraceError::Io(path.to_path_buf(), e))?;
+    let mut samples = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line == TuneSample::HEADER {
+            continue;
+        }
+        let sample =
+            TuneSample::parse(line).ok_or_else(|| TraceError::Parse(path.to_path_buf(), n + 1))?;
+        samples.push(sample);
+    }
+    if samples.is_empty() {
+        return Err(TraceError::Empty(path.to_path_buf()));
+    }
+    Ok(samples)
+}
+
+/// Keeps every CPU busy until dropped
+#[derive(Debug)]
+pub struct BusyLoad {
+    running: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl BusyLoad {
+    /// One busy thread per CPU
+    pub fn start() -> Self {
+        let cpus = thread::available_parallelism().map_or(1, usize::from);
+        let running = Arc::new(AtomicBool::new(true));
+        let threads = (0..cpus)
+            .map(|_| {
+                let running = running.clone();
+                thread::spawn(move || {
+                    let mut x = 0u64;
+                    while running.load(Ordering::Relaxed) {
+                        x = std::hint::black_box(
+                            x.wrapping_mul(6364136223846793005).wrapping_add(1),
+                        );
+                    }
+                })
+            })
+            .collect();
+        info!("Loading {} CPUs", cpus);
+        BusyLoad { running, threads }
+    }
+}
+
+impl Drop for BusyLoad {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+/// Holds each fan speed until the temperature is steady, sampling it every interval.
+/// The fan is left at 100%.
+pub fn run_tune<T: TemperatureSource, F: Fan, K: Clock>(
+    sensor: &mut T,
+    fan: &mut F,
+    settings: &TuneSettings,
+    clock: &K,
+) -> Result<Vec<TuneSample>, ControlError> {
+    let result = hold_fan_speeds(sensor, fan, settings, clock);
+    if let Err(e) = fan.set_speed(FanSpeed::MAX) {
+        warn!("Failed to set the fan speed back to 100%, {}", e);
+    }
+    result
+}
+
+fn hold_fan_speeds<T: TemperatureSource, F: Fan, K: Clock>(
+    sensor: &mut T,
+    fan: &mut F,
+    settings: &TuneSettings,
+    clock: &K,
+) -> Result<Vec<TuneSample>, ControlError> {
+    let began = clock.now();
+    let mut samples: Vec<TuneSample> = Vec::new();
+    for fan_speed in settings.fan_speeds.iter().copied() {
+        fan.set_speed(fan_speed)
+            .map_err(|e| ControlError::Fan(Box::new(e)))?;
+        info!("Holding the fan at {}", fan_speed);
+        let first = samples.len();
+        let start = clock.now();
+        loop {
+            clock.sleep(settings.sample_interval);
+            let time = (clock.now() - began).as_secs_f64();
+            let temperature = sensor
+                .temperature()
+                .map_err(|e| ControlError::Temperature(Box::new(e)))?;
+            samples.push(TuneSample {
+                time,
+                fan_speed,
+                temperature: temperature.into(),
+            });
+            if DegreesC::from_f32(temperature) >= settings.limit {
+                warn!(
+                    "Reached {} at {}, skipping the lower fan speeds",
+                    settings.limit, fan_speed
+                );
+                return Ok(samples);
+            }
+            let step = &samples[first..];
+            if let Some(t) = steady_temperature(step, settings.window, settings.tolerance) {
+                info!("Steady at {:.1} C with the fan at {}", t, fan_speed);
+                break;
+            }
+            if clock.now() - start >= settings.max_step_time {
+                warn!(
+                    "Not steady after {:?} at {}",
+                    settings.max_step_time, fan_speed
+                );
+                break;
+            }
+        }
+    }
+    Ok(samples)
+}
+
+/// Mean temperatures of the last windows of `samples`, the latest last
+fn window_means(samples: &[TuneSample], window: f64, count: usize) -> Vec<f64> {
+    let (first, last) = match (samples.first(), samples.last()) {
+        (Some(f), Some(l)) => (f.time, l.time),
+        _ => return Vec::new(),
+    };
+    let spacing = if samples.len() > 1 {
+        (last - first) / (samples.len() - 1) as f64
+    } else {
+        window
+    };
+    let mut means = Vec::new();
+    for i in (0..count).rev() {
+        let to = last - window * i as f64;
+        let from = to - window;
+        // Only whole windows
+        if from + 1e-9 < first - spacing {
+            continue;
+        }
+        let temps: Vec<f64> = samples
+            .iter()
+            .filter(|s| s.time > from && s.time <= to)
+            .map(|s| s.temperature)
+            .collect();
+        if !temps.is_empty() {
+            means.push(temps.iter().sum::<f64>() / temps.len() as f64);
+        }
+    }
+    means
+}
+
+/// The temperature `samples` of one fan speed settle at, None until the last window's
+/// mean is within `tolerance` of the one before. Extrapolated from the last three
+/// windows when they approach it like a cooling or heating curve.
+pub fn steady_temperature(samples: &[TuneSample], window: Duration, tolerance: f64) -> Option<f64> {
+    let means = window_means(samples, window.as_secs_f64(), 3);
+    let (m0, m1, m2) = match means.as_slice() {
+        [m0, m1, m2] => (Some(*m0), *m1, *m2),
+        [m1, m2] => (None, *m1, *m2),
+        _ => return None,
+    };
+    if (m2 - m1).abs() > tolerance {
+        return None;
+    }
+    match m0 {
+        Some(m0) if (m1 - m0) * (m2 - m1) > 0.0 && (m2 - m1).abs() < (m1 - m0).abs() => {
+            let ratio = (m2 - m1) / (m1 - m0);
+            Some(m2 + (m2 - m1) * ratio / (1.0 - ratio))
+        }
+        _ => Some(m2),
+    }
+}
+
+/// The temperature a fan speed held under load settled at
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SteadyState {
+    pub fan_speed: FanSpeed,
+    /// Degrees C, the last window's mean when not steady
+    pub temperature: f64,
+    pub steady: bool,
+}
+
+/// One steady state per fan speed held, in the order held
+pub fn steady_states(samples: &[TuneSample], window: Duration, tolerance: f64) -> Vec<SteadyState> {
+    let mut states = Vec::new();
+    let mut rest = samples;
+    while let Some(first) = rest.first() {
+        let len = rest
+            .iter()
+            .position(|s| s.fan_speed != first.fan_speed)
+            .unwrap_or(rest.len());
+        let (step, next) = rest.split_at(len);
+        rest = next;
+        let state = match steady_temperature(step, window, tolerance) {
+            Some(temperature) => SteadyState {
+                fan_speed: first.fan_speed,
+                temperature,
+                steady: true,
+            },
+            None => SteadyState {
+                fan_speed: first.fan_speed,
+                temperature: *window_means(step, window.as_secs_f64(), 1)
+                    .last()
+                    .unwrap_or(&step[step.len() - 1].temperature),
+                steady: false,
+            },
+        };
+        states.push(state);
+    }
+    states
+}
+
+/// The lowest fan speed holding `target` under the load, interpolated between the
+/// steady states, None when even the fastest held doesn't
+fn fan_speed_holding(states: &[SteadyState], target: f64) -> Option<FanSpeed> {
+    let mut points: Vec<(f64, f64)> = states
+        .iter()
+        .map(|s| (f64::from(u8::from(s.fan_speed)), s.temperature))
+        .collect();
+    points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let speed = |s: f64| FanSpeed::new_unchecked(s.ceil().min(100.0) as u8);
+    let (s0, t0) = *points.first()?;
+    if t0 <= target {
+        return Some(speed(s0));
+    }
+    points.windows(2).find_map(|w| {
+        let ((s0, t0), (s1, t1)) = (w[0], w[1]);
+        if t1 <= target {
+            Some(speed(s0 + (s1 - s0) * (t0 - target) / (t0 - t1)))
+        } else {
+            None
+        }
+    })
+}
+
+/// The proposed curve, from the steady states under load
+#[derive(Clone, PartialEq, Debug)]
+pub struct TuneReport {
+    pub states: Vec<SteadyState>,
+    pub target: DegreesC,
+    /// Lowest fan speed holding the target under load, None if even the fastest held
+    /// doesn't
+    pub holding: Option<FanSpeed>,
+    /// The configuration's default profile with the proposed curve
+    pub profile: Profile,
+    pub unit: TemperatureUnit,
+}
+
+impl TuneReport {
+    /// A curve reaching the fan speed holding `target` at `target`, ramping up from
+    /// `band` below it at the current fan_speed_min
+    pub fn new(
+        states: Vec<SteadyState>,
+        target: DegreesC,
+        band: DegreesC,
+        current: &Profile,
+        unit: TemperatureUnit,
+    ) -> Result<Self, TuneError> {
+        if states.is_empty() {
+            return Err(TuneError::NoSteadyState);
+        }
+        let holding = fan_speed_holding(&states, target.as_f64());
+        let fan_speed_min = current.fan_speed_min.min(FanSpeed::new_unchecked(99));
+        let (temperature_min, temperature_max) = match holding {
+            Some(s) if s <= fan_speed_min => (target, target.saturating_add(band)),
+            Some(s) => {
+                let s_min = f64::from(u8::from(fan_speed_min));
+                let rise = band.as_f64() * (100.0 - s_min) / (f64::from(u8::from(s)) - s_min);
+                let t_min = target.saturating_sub(band);
+                (t_min, DegreesC::from_f64(t_min.as_f64() + rise))
+            }
+            None => (target.saturating_sub(band), target),
+        };
+        let profile = Profile {
+            temperature_min,
+            temperature_max,
+            fan_speed_min,
+            fan_speed_max: FanSpeed::MAX,
+            ..current.clone()
+        };
+        Ok(TuneReport {
+            states,
+            target,
+            holding,
+            profile,
+            unit,
+        })
+    }
+
+    /// The proposed curve as top level configuration fields, temperatures in `unit`,
+    /// the configuration's
+    pub fn snippet(&self, unit: TemperatureUnit) -> Result<String, TuneError> {
+        let mut value = toml::Value::try_from(&self.profile)?;
+        temperatures_in_unit(&mut value, unit);
+        let mut out = format!("# Written by argon-fan-ctl tune, {}\n", self.summary());
+        out.push_str(&toml::to_string_pretty(&value)?);
+        Ok(out)
+    }
+
+    fn summary(&self) -> String {
+        let target = self.target.in_unit(self.unit);
+        match self.holding {
+            Some(s) => format!("{} holds {} under full load", s, target),
+            None => format!("even the fastest fan speed can't hold {}", target),
+        }
+    }
+}
+
+impl fmt::Display for TuneReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Steady state under full load")?;
+        writeln!(f, "{:>9}  {:>11}", "Fan speed", "Temperature")?;
+        for s in self.states.iter() {
+            let t = DegreesC::from_f64(s.temperature).in_unit(self.unit);
+            write!(f, "{:>9}  {:>11}", s.fan_speed.to_string(), t.to_string())?;
+            if !s.steady {
+                f.write_str("  not steady, still heating or cooling")?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "{}", self.summary())?;
+        let p = &self.profile;
+        write!(
+            f,
+            "Proposed curve {} at {} to {} at {}",
+            p.fan_speed_min,
+            p.temperature_min.in_unit(self.unit),
+            p.fan_speed_max,
+            p.temperature_max.in_unit(self.unit)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ManualClock, SimSensor, ThermalModel, ThermalSim};
+    use std::time::Instant;
+
+    /// Advances the simulation by the clock time passed since the previous reading
+    struct ClockedSensor {
+        sensor: SimSensor,
+        sim: ThermalSim,
+        clock: ManualClock,
+        last: Instant,
+    }
+
+    impl TemperatureSource for ClockedSensor {
+        type Error = std::convert::Infallible;
+
+        fn temperature(&mut self) -> Result<f32, Self::Error> {
+            let now = self.clock.now();
+            self.sim.step(now - self.last);
+            self.last = now;
+            self.sensor.temperature()
+        }
+    }
+
+    fn speed(s: u8) -> FanSpeed {
+        FanSpeed::new(s).unwrap()
+    }
+
+    fn profile(fan_speed_min: u8) -> Profile {
+        Profile {
+            temperature_min: DegreesC::new(33),
+            temperature_max: DegreesC::new(65),
+            fan_speed_min: speed(fan_speed_min),
+            fan_speed_max: FanSpeed::MAX,
+            hysteresis: DegreesC::new(2),
+            safety_temperature: None,
+        }
+    }
+
+    fn simulated(settings: &TuneSettings) -> (ThermalModel, Vec<TuneSample>) {
+        let model = ThermalModel::default();
+        let sim = ThermalSim::new(model);
+        sim.set_load(1.0);
+        let clock = ManualClock::new();
+        let mut sensor = ClockedSensor {
+            sensor: sim.sensor(),
+            sim: sim.clone(),
+            clock: clock.clone(),
+            last: clock.now(),
+        };
+        let samples = run_tune(&mut sensor, &mut sim.fan(), settings, &clock).unwrap();
+        assert_eq!(sim.fan_speed(), FanSpeed::MAX);
+        (model, samples)
+    }
+
+    #[test]
+    fn steady_states_of_the_simulator() {
+        let settings = TuneSettings::default();
+        let (model, samples) = simulated(&settings);
+        let states = steady_states(&samples, settings.window, settings.tolerance);
+        let speeds: Vec<u8> = states.iter().map(|s| s.fan_speed.into()).collect();
+        assert_eq!(speeds, vec![100, 75, 50, 25, 0]);
+        for s in states.iter() {
+            let expected = model.steady_state(1.0, s.fan_speed);
+            assert!(s.steady);
+            assert!(
+                (s.temperature - expected).abs() < 0.5,
+                "{:?}, expected {}",
+                s,
+                expected
+            );
+        }
+
+        // The model at 53 C with the fan at 50%
+        let report = TuneReport::new(
+            states,
+            DegreesC::new(53),
+            DegreesC::new(10),
+            &profile(20),
+            TemperatureUnit::Celsius,
+        )
+        .unwrap();
+        let holding = u8::from(report.holding.unwrap());
+        assert!((48..=53).contains(&holding), "{}", holding);
+        assert_eq!(report.profile.temperature_min, DegreesC::new(43));
+        assert_eq!(report.profile.fan_speed_min, speed(20));
+        assert_eq!(report.profile.hysteresis, DegreesC::new(2));
+        let map = report.profile.fan_speed_map().unwrap();
+        let at_target = i32::from(u8::from(map.get(DegreesC::new(53))));
+        assert!((at_target - i32::from(holding)).abs() <= 1);
+    }
+
+    #[test]
+    fn limit_and_step_time() {
+        let settings = TuneSettings {
+            limit: DegreesC::new(50),
+            max_step_time: Duration::from_secs(120),
+            ..Default::default()
+        };
+        let (_, samples) = simulated(&settings);
+        let states = steady_states(&samples, settings.window, settings.tolerance);
+        // Not steady within 2 minutes, and 0% wasn't tried once 50 C was reached
+        assert!(states.iter().all(|s| !s.steady));
+        assert_eq!(states.last().unwrap().fan_speed, speed(25));
+        assert!(DegreesC::from_f64(samples.last().unwrap().temperature) >= DegreesC::new(50));
+    }
+
+    #[test]
+    fn extrapolated() {
+        // Window means 50, 55, 57.5 approach 60
+        let sample = |time, temperature| TuneSample {
+            time,
+            fan_speed: speed(50),
+            temperature,
+        };
+        let samples = [sample(10.0, 50.0), sample(20.0, 55.0), sample(30.0, 57.5)];
+        let window = Duration::from_secs(10);
+        assert_eq!(steady_temperature(&samples, window, 1.0), None);
+        assert_eq!(steady_temperature(&samples, window, 3.0), Some(60.0));
+        assert_eq!(steady_temperature(&samples[1..], window, 3.0), Some(57.5));
+        assert_eq!(steady_temperature(&samples[2..], window, 3.0), None);
+    }
+
+    #[test]
+    fn proposals() {
+        let state = |s, temperature| SteadyState {
+            fan_speed: speed(s),
+            temperature,
+            steady: true,
+        };
+        let states = vec![state(100, 45.0), state(50, 55.0), state(0, 70.0)];
+        let report = |target, fan_speed_min| {
+            TuneReport::new(
+                states.clone(),
+                DegreesC::new(target),
+                DegreesC::new(10),
+                &profile(fan_speed_min),
+                TemperatureUnit::Celsius,
+            )
+            .unwrap()
+        };
+
+        let r = report(60, 0);
+        // A third of the way from 0% at 70 C to 50% at 55 C
+        assert_eq!(r.holding, Some(speed(34)));
+        assert_eq!(r.profile.temperature_min, DegreesC::new(50));
+        assert_eq!(r.profile.temperature_max, DegreesC::from_tenths(794));
+        assert_eq!(
+            r.to_string(),
+            "Steady state under full load\n\
+             Fan speed  Temperature\n\
+             \x20    100%         45 C\n\
+             \x20     50%         55 C\n\
+             \x20      0%         70 C\n\
+             34% holds 60 C under full load\n\
+             Proposed curve 0% at 50 C to 100% at 79.4 C"
+        );
+
+        // Within fan_speed_min, the fan ramps up from the target
+        let r = report(75, 10);
+        assert_eq!(r.holding, Some(speed(0)));
+        assert_eq!(r.profile.temperature_min, DegreesC::new(75));
+        assert_eq!(r.profile.temperature_max, DegreesC::new(85));
+
+        // Out of reach, full speed at the target
+        let r = report(40, 10);
+        assert_eq!(r.holding, None);
+        assert_eq!(r.profile.temperature_min, DegreesC::new(30));
+        assert_eq!(r.profile.temperature_max, DegreesC::new(40));
+        assert!(r
+            .to_string()
+            .contains("even the fastest fan speed can't hold 40 C"));
+
+        assert_eq!(
+            TuneReport::new(
+                Vec::new(),
+                DegreesC::new(60),
+                DegreesC::new(10),
+                &profile(0),
+                TemperatureUnit::Celsius
+            ),
+            Err(TuneError::NoSteadyState)
+        );
+    }
+
+    #[test]
+    fn snippet_and_samples_file() {
+        let state = SteadyState {
+            fan_speed: speed(50),
+            temperature: 55.0,
+            steady: true,
+        };
+        let r = TuneReport::new(
+            vec![state],
+            DegreesC::new(60),
+            DegreesC::new(10),
+            &profile(0),
+            TemperatureUnit::Fahrenheit,
+        )
+        .unwrap();
+        let snippet = r.snippet(TemperatureUnit::Fahrenheit).unwrap();
+        assert!(snippet
+            .starts_with("# Written by argon-fan-ctl tune, 50% holds 140 F under full load\n"));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, format!("units = \"fahrenheit\"\n{}", snippet)).unwrap();
+        let config = crate::Config::load(&path).unwrap();
+        assert_eq!(config.default_profile(), r.profile);
+
+        let settings = TuneSettings {
+            fan_speeds: vec![speed(100), speed(0)],
+            ..Default::default()
+        };
+        let (_, samples) = simulated(&settings);
+        let path = dir.path().join("samples.csv");
+        fs::write(&path, tune_samples_csv(&samples)).unwrap();
+        let read = read_tune_samples(&path).unwrap();
+        assert_eq!(read.len(), samples.len());
+        assert_eq!(
+            steady_states(&read, settings.window, settings.tolerance).len(),
+            2
+        );
+        fs::write(&path, "time,fan_speed,temperature\n5,150,40\n").unwrap();
+        assert!(matches!(
+            read_tune_samples(&path),
+            Err(TraceError::Parse(_, 2))
+        ));
+    }
+}
@@ -0,0 +1,244 @@
+use crate::{DegreesC, ParseDegreesCError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, err_derive::Error)]
+pub enum ParseTemperatureUnitError {
+    #[error(
+        display = "Invalid temperature unit '{}', expected celsius or fahrenheit",
+        _0
+    )]
+    Invalid(String),
+}
+
+/// Unit temperatures are shown in, and read in when written without a C or F suffix.
+/// The control loop always works in Celsius.
+#[derive(
+    Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize, Default,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum TemperatureUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "C",
+            TemperatureUnit::Fahrenheit => "F",
+        }
+    }
+
+    /// A temperature in this unit
+    pub fn from_celsius(self, t: DegreesC) -> f64 {
+        match self {
+            TemperatureUnit::Celsius => t.as_f64(),
+            TemperatureUnit::Fahrenheit => t.as_f64() * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    /// A temperature in this unit, in Celsius
+    pub fn to_celsius(self, t: f64) -> DegreesC {
+        match self {
+            TemperatureUnit::Celsius => DegreesC::from_f64(t),
+            TemperatureUnit::Fahrenheit => DegreesC::from_f64((t - 32.0) * 5.0 / 9.0),
+        }
+    }
+
+    /// A temperature difference, e.g. a hysteresis, in this unit
+    pub fn difference_from_celsius(self, t: DegreesC) -> f64 {
+        match self {
+            TemperatureUnit::Celsius => t.as_f64(),
+            TemperatureUnit::Fahrenheit => t.as_f64() * 9.0 / 5.0,
+        }
+    }
+
+    /// A temperature difference in this unit, e.g. a hysteresis, in Celsius
+    pub fn difference_to_celsius(self, t: f64) -> DegreesC {
+        match self {
+            TemperatureUnit::Celsius => DegreesC::from_f64(t),
+            TemperatureUnit::Fahrenheit => DegreesC::from_f64(t * 5.0 / 9.0),
+        }
+    }
+}
+
+impl FromStr for TemperatureUnit {
+    type Err = ParseTemperatureUnitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "c" | "celsius" => Ok(TemperatureUnit::Celsius),
+            "f" | "fahrenheit" => Ok(TemperatureUnit::Fahrenheit),
+            _ => Err(ParseTemperatureUnitError::Invalid(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for TemperatureUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemperatureUnit::Celsius => f.write_str("celsius"),
+            TemperatureUnit::Fahrenheit => f.write_str("fahrenheit"),
+        }
+    }
+}
+
+/// A temperature as written, e.g. "65", "65.5C" or "150F", the unit is left to the
+/// reader when there's no suffix
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Temperature {
+    pub value: f64,
+    pub unit: Option<TemperatureUnit>,
+}
+
+impl Temperature {
+    /// In Celsius, reading a temperature without a suffix in `unit`
+    pub fn to_celsius(self, unit: TemperatureUnit) -> DegreesC {
+        self.unit.unwrap_or(unit).to_celsius(self.value)
+    }
+
+    /// Like `to_celsius`, for a temperature difference
+    pub fn difference_to_celsius(self, unit: TemperatureUnit) -> DegreesC {
+        self.unit.unwrap_or(unit).difference_to_celsius(self.value)
+    }
+}
+
+impl FromStr for Temperature {
+    type Err = ParseDegreesCError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let (value, unit) = match trimmed.char_indices().last() {
+            Some((i, c)) if c.is_ascii_alphabetic() => (
+                &trimmed[..i],
+                Some(
+                    c.to_string()
+                        .parse::<TemperatureUnit>()
+                        .map_err(|_| ParseDegreesCError::Invalid(s.to_string()))?,
+                ),
+            ),
+            _ => (trimmed, None),
+        };
+        let value: f64 = value.trim().parse()?;
+        let celsius = match unit {
+            Some(TemperatureUnit::Fahrenheit) => (value - 32.0) * 5.0 / 9.0,
+            _ => value,
+        };
+        if !value.is_finite()
+            || celsius < DegreesC::MIN.as_f64()
+            || celsius > DegreesC::MAX.as_f64()
+        {
+            return Err(ParseDegreesCError::Invalid(s.to_string()));
+        }
+        Ok(Temperature { value, unit })
+    }
+}
+
+/// A temperature shown in a unit, to a tenth of a degree, like `DegreesC` is
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct UnitTemperature {
+    pub value: f64,
+    pub unit: TemperatureUnit,
+}
+
+impl UnitTemperature {
+    fn tenths(&self) -> i64 {
+        (self.value * 10.0).round() as i64
+    }
+}
+
+impl fmt::Display for UnitTemperature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tenths = self.tenths();
+        if tenths % 10 == 0 {
+            write!(f, "{} {}", tenths / 10, self.unit.symbol())
+        } else {
+            write!(f, "{:.1} {}", tenths as f64 / 10.0, self.unit.symbol())
+        }
+    }
+}
+
+/// Whole degrees as integers, like `DegreesC`
+impl Serialize for UnitTemperature {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let tenths = self.tenths();
+        if tenths % 10 == 0 {
+            serializer.serialize_i64(tenths / 10)
+        } else {
+            serializer.serialize_f64(tenths as f64 / 10.0)
+        }
+    }
+}
+
+impl DegreesC {
+    pub fn in_unit(self, unit: TemperatureUnit) -> UnitTemperature {
+        UnitTemperature {
+            value: unit.from_celsius(self),
+            unit,
+        }
+    }
+
+    /// Parses a temperature with an optional C or F suffix, in `unit` without one
+    pub fn parse_in(s: &str, unit: TemperatureUnit) -> Result<Self, ParseDegreesCError> {
+        Ok(s.parse::<Temperature>()?.to_celsius(unit))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::gen_degrees_c;
+    use proptest::prelude::*;
+
+    #[test]
+    fn parse_and_show() {
+        use TemperatureUnit::*;
+        assert_eq!(
+            DegreesC::parse_in("150F", Celsius),
+            Ok(DegreesC::from_tenths(656))
+        );
+        assert_eq!(
+            DegreesC::parse_in("150", Fahrenheit),
+            Ok(DegreesC::from_tenths(656))
+        );
+        assert_eq!(
+            DegreesC::parse_in(" 65.5 c", Fahrenheit),
+            Ok(DegreesC::from_tenths(655))
+        );
+        assert_eq!(DegreesC::parse_in("65", Celsius), Ok(DegreesC::new(65)));
+        assert!(DegreesC::parse_in("65K", Celsius).is_err());
+        assert!(DegreesC::parse_in("F", Celsius).is_err());
+        assert_eq!("F".parse::<TemperatureUnit>(), Ok(Fahrenheit));
+        assert!("kelvin".parse::<TemperatureUnit>().is_err());
+
+        assert_eq!(DegreesC::new(50).in_unit(Fahrenheit).to_string(), "122 F");
+        assert_eq!(
+            DegreesC::from_tenths(649).in_unit(Fahrenheit).to_string(),
+            "148.8 F"
+        );
+        assert_eq!(
+            DegreesC::from_tenths(649).in_unit(Celsius).to_string(),
+            "64.9 C"
+        );
+        assert_eq!(
+            serde_json::to_string(&DegreesC::new(50).in_unit(Fahrenheit)).unwrap(),
+            "122"
+        );
+        assert_eq!(Fahrenheit.difference_to_celsius(9.0), DegreesC::new(5));
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(t in gen_degrees_c()) {
+            for unit in [TemperatureUnit::Celsius, TemperatureUnit::Fahrenheit].iter() {
+                let shown = t.in_unit(*unit).to_string();
+                let back = DegreesC::parse_in(&shown.replace(' ', ""), TemperatureUnit::Celsius).unwrap();
+                // Fahrenheit is shown to a tenth, a bit finer than a tenth of a degree C
+                prop_assert!((back.tenths() - t.tenths()).abs() <= 1);
+            }
+        }
+    }
+}